@@ -0,0 +1,431 @@
+//! Event counts grouped by time bucket and dimension
+//!
+//! Buckets follow the calendar of the requested time zone: a day starts at
+//! local midnight, so the days DST begins or ends last 23 or 25 hours, and
+//! the repeated hour when clocks go back gets a bucket of its own. Counts
+//! come from `daily_counts` or `hourly_counts` whenever every bucket boundary
+//! falls on a whole UTC day or hour, and from the events table otherwise
+//! (minute buckets, or zones offset by a fraction of an hour).
+
+use chrono::{
+    DateTime, Duration, FixedOffset, LocalResult, NaiveDateTime, NaiveTime, TimeZone, Timelike, Utc,
+};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use crate::db::{Database, EventFilter, Interval};
+use crate::error::Result;
+
+/// Most groups one request may list before the rest become `other`
+pub const MAX_GROUPS: usize = 100;
+
+/// Source granularities to read counts at, coarsest first (milliseconds)
+const STEPS: [i64; 6] = [86_400_000, 3_600_000, 900_000, 60_000, 1_000, 1];
+
+/// Event attribute counts can be grouped by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dimension {
+    Category,
+    Type,
+    Severity,
+    SourceType,
+    SourceId,
+}
+
+impl Dimension {
+    /// Column holding this dimension in `events` and the rollup tables
+    pub fn column(self) -> &'static str {
+        match self {
+            Dimension::Category => "category",
+            Dimension::Type => "type",
+            Dimension::Severity => "severity",
+            Dimension::SourceType => "source_type",
+            Dimension::SourceId => "source_id",
+        }
+    }
+}
+
+impl FromStr for Dimension {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "category" => Ok(Dimension::Category),
+            "type" => Ok(Dimension::Type),
+            "severity" => Ok(Dimension::Severity),
+            "source_type" => Ok(Dimension::SourceType),
+            "source_id" => Ok(Dimension::SourceId),
+            other => Err(format!("unknown dimension '{}'", other)),
+        }
+    }
+}
+
+/// Page view attribute with a top-N list, kept in the `page_views` table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebField {
+    Path,
+    ReferrerHost,
+    UtmSource,
+    UtmMedium,
+    UtmCampaign,
+    DeviceType,
+    Browser,
+}
+
+impl WebField {
+    /// Column holding this field in `page_views`
+    pub fn column(self) -> &'static str {
+        match self {
+            WebField::Path => "path",
+            WebField::ReferrerHost => "referrer_host",
+            WebField::UtmSource => "utm_source",
+            WebField::UtmMedium => "utm_medium",
+            WebField::UtmCampaign => "utm_campaign",
+            WebField::DeviceType => "device_type",
+            WebField::Browser => "browser",
+        }
+    }
+}
+
+/// What to count
+#[derive(Debug, Clone)]
+pub struct CountQuery {
+    pub interval: Interval,
+    pub tz: Tz,
+    /// Widened to the start of its bucket
+    pub since: DateTime<Utc>,
+    /// Widened to the end of its bucket
+    pub until: DateTime<Utc>,
+    pub group_by: Vec<Dimension>,
+    /// Groups listed by total count; the rest are summed into `other`
+    pub limit: usize,
+    pub filter: EventFilter,
+}
+
+/// Counts per bucket, one series per group
+#[derive(Debug, Serialize)]
+pub struct Counts {
+    pub interval: Interval,
+    pub tz: String,
+    pub group_by: Vec<Dimension>,
+    /// Start of each bucket, in the requested time zone
+    pub buckets: Vec<DateTime<FixedOffset>>,
+    /// Largest groups first
+    pub groups: Vec<Group>,
+    /// Everything past the first `limit` groups, if anything
+    pub other: Option<Other>,
+    pub total: i64,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Group {
+    pub key: BTreeMap<Dimension, String>,
+    pub total: i64,
+    /// One count per bucket
+    pub counts: Vec<i64>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Other {
+    /// Groups summed up here
+    pub groups: usize,
+    pub total: i64,
+    pub counts: Vec<i64>,
+}
+
+/// Count events per bucket and group
+pub fn count(db: &Database, query: &CountQuery) -> Result<Counts> {
+    let bounds = boundaries(query.interval, query.tz, query.since, query.until);
+    let bounds: Vec<i64> = bounds.iter().map(|b| b.timestamp_millis()).collect();
+    let buckets = bounds.len() - 1;
+
+    let rows = db.grouped_counts(
+        source_step(&bounds),
+        bounds[0],
+        bounds[buckets],
+        &query.group_by,
+        &query.filter,
+    )?;
+    let mut series: HashMap<Vec<String>, Vec<i64>> = HashMap::new();
+    for row in rows {
+        let bucket = bounds.partition_point(|b| *b <= row.start) - 1;
+        series.entry(row.key).or_insert_with(|| vec![0; buckets])[bucket] += row.count;
+    }
+
+    let mut groups: Vec<Group> = series
+        .into_iter()
+        .map(|(key, counts)| Group {
+            key: query.group_by.iter().copied().zip(key).collect(),
+            total: counts.iter().sum(),
+            counts,
+        })
+        .collect();
+    groups.sort_by(|a, b| b.total.cmp(&a.total).then_with(|| a.key.cmp(&b.key)));
+
+    let rest = groups.split_off(query.limit.min(groups.len()));
+    let other = (!rest.is_empty()).then(|| Other {
+        groups: rest.len(),
+        total: rest.iter().map(|g| g.total).sum(),
+        counts: (0..buckets)
+            .map(|i| rest.iter().map(|g| g.counts[i]).sum())
+            .collect(),
+    });
+
+    Ok(Counts {
+        interval: query.interval,
+        tz: query.tz.name().to_string(),
+        group_by: query.group_by.clone(),
+        buckets: bounds[..buckets]
+            .iter()
+            .map(|ms| {
+                let start = DateTime::from_timestamp_millis(*ms).unwrap_or_default();
+                start.with_timezone(&query.tz).fixed_offset()
+            })
+            .collect(),
+        total: groups.iter().map(|g| g.total).sum::<i64>() + other.as_ref().map_or(0, |o| o.total),
+        groups,
+        other,
+    })
+}
+
+/// Start of every bucket overlapping `[since, until)`, then the end of the last
+///
+/// An empty range still gets the bucket `since` falls in.
+pub fn boundaries(
+    interval: Interval,
+    tz: Tz,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> Vec<DateTime<Utc>> {
+    // Local days are 23 to 25 hours long: a day and an hour past the start
+    // of one always lands in the next
+    let stride = match interval {
+        Interval::Day => Duration::hours(25),
+        _ => Duration::milliseconds(interval.millis()),
+    };
+
+    let mut bounds = vec![bucket_start(interval, tz, since)];
+    loop {
+        let last = bounds[bounds.len() - 1];
+        let next = bucket_start(interval, tz, last + stride).max(last + Duration::minutes(1));
+        bounds.push(next);
+        if next >= until {
+            return bounds;
+        }
+    }
+}
+
+/// Start of the bucket holding `t`
+fn bucket_start(interval: Interval, tz: Tz, t: DateTime<Utc>) -> DateTime<Utc> {
+    let local = t.with_timezone(&tz).naive_local();
+    let start = truncate(interval, local);
+    match tz.from_local_datetime(&start) {
+        LocalResult::Single(s) => s.with_timezone(&Utc),
+        // Clocks went back: the local time exists twice, once per bucket
+        LocalResult::Ambiguous(earlier, later) => {
+            let later = later.with_timezone(&Utc);
+            if later <= t {
+                later
+            } else {
+                earlier.with_timezone(&Utc)
+            }
+        }
+        // Clocks went forward past the start: the bucket begins at the jump
+        LocalResult::None => (1..)
+            .find_map(|m| {
+                tz.from_local_datetime(&(start + Duration::minutes(m)))
+                    .earliest()
+            })
+            .map(|s| s.with_timezone(&Utc))
+            .unwrap_or(t),
+    }
+}
+
+fn truncate(interval: Interval, local: NaiveDateTime) -> NaiveDateTime {
+    let minute = local.with_second(0).and_then(|l| l.with_nanosecond(0));
+    match interval {
+        Interval::Minute => minute,
+        Interval::Hour => minute.and_then(|l| l.with_minute(0)),
+        Interval::Day => Some(local.date().and_time(NaiveTime::MIN)),
+    }
+    .unwrap_or(local)
+}
+
+/// Coarsest granularity every bucket boundary is a multiple of
+fn source_step(bounds: &[i64]) -> i64 {
+    STEPS
+        .into_iter()
+        .find(|step| bounds.iter().all(|b| b.rem_euclid(*step) == 0))
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::test_support::test_event;
+    use crate::event::Event;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn labels(interval: Interval, tz: Tz, since: &str, until: &str) -> Vec<String> {
+        boundaries(interval, tz, utc(since), utc(until))
+            .iter()
+            .map(|b| b.with_timezone(&tz).fixed_offset().to_rfc3339())
+            .collect()
+    }
+
+    #[test]
+    fn test_buckets_follow_local_calendar() {
+        // Clocks in Berlin go back at 01:00 UTC on 25 October 2026
+        let berlin = chrono_tz::Europe::Berlin;
+        assert_eq!(
+            labels(
+                Interval::Day,
+                berlin,
+                "2026-10-24T12:00:00Z",
+                "2026-10-25T23:30:00Z"
+            ),
+            [
+                "2026-10-24T00:00:00+02:00",
+                "2026-10-25T00:00:00+02:00",
+                "2026-10-26T00:00:00+01:00",
+                "2026-10-27T00:00:00+01:00",
+            ]
+        );
+        assert_eq!(
+            labels(
+                Interval::Hour,
+                berlin,
+                "2026-10-24T23:30:00Z",
+                "2026-10-25T02:00:00Z"
+            ),
+            [
+                "2026-10-25T01:00:00+02:00",
+                "2026-10-25T02:00:00+02:00",
+                "2026-10-25T02:00:00+01:00",
+                "2026-10-25T03:00:00+01:00",
+            ]
+        );
+        // Clocks go forward at 01:00 UTC on 29 March: there is no 02:00 hour
+        assert_eq!(
+            labels(
+                Interval::Hour,
+                berlin,
+                "2026-03-29T00:00:00Z",
+                "2026-03-29T02:00:00Z"
+            ),
+            [
+                "2026-03-29T01:00:00+01:00",
+                "2026-03-29T03:00:00+02:00",
+                "2026-03-29T04:00:00+02:00",
+            ]
+        );
+
+        let bounds = |interval, tz, since, until| -> Vec<i64> {
+            boundaries(interval, tz, utc(since), utc(until))
+                .iter()
+                .map(|b| b.timestamp_millis())
+                .collect()
+        };
+        let day = ("2026-10-24T12:00:00Z", "2026-10-26T12:00:00Z");
+        assert_eq!(
+            source_step(&bounds(Interval::Day, Tz::UTC, day.0, day.1)),
+            86_400_000
+        );
+        assert_eq!(
+            source_step(&bounds(Interval::Day, berlin, day.0, day.1)),
+            3_600_000
+        );
+        let kathmandu = chrono_tz::Asia::Kathmandu;
+        assert_eq!(
+            source_step(&bounds(Interval::Hour, kathmandu, day.0, day.1)),
+            900_000
+        );
+    }
+
+    #[test]
+    fn test_counts_match_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db")).unwrap();
+        db.migrate().unwrap();
+
+        // Every 7 minutes for three days around the Berlin DST change
+        let start = utc("2026-10-23T20:00:00Z");
+        let events: Vec<Event> = (0..900)
+            .map(|i| {
+                test_event(["page_view", "click", "scroll"][i as usize % 3])
+                    .source("browser", &format!("tab-{}", i % 5))
+                    .observed_at(start + Duration::minutes(7 * i))
+                    .build()
+            })
+            .collect();
+        db.insert_events(&events).unwrap();
+
+        let (since, until) = (utc("2026-10-24T00:00:00Z"), utc("2026-10-26T00:00:00Z"));
+        for (interval, tz) in [
+            (Interval::Day, Tz::UTC),
+            (Interval::Day, chrono_tz::Europe::Berlin),
+            (Interval::Hour, chrono_tz::Asia::Kolkata),
+            (Interval::Minute, chrono_tz::America::New_York),
+        ] {
+            let query = CountQuery {
+                interval,
+                tz,
+                since,
+                until,
+                group_by: vec![Dimension::Type],
+                limit: MAX_GROUPS,
+                filter: EventFilter::default(),
+            };
+            let counts = count(&db, &query).unwrap();
+
+            let bounds = boundaries(interval, tz, since, until);
+            assert_eq!(counts.buckets.len(), bounds.len() - 1);
+            for group in &counts.groups {
+                let expected: Vec<i64> = bounds
+                    .windows(2)
+                    .map(|w| {
+                        events
+                            .iter()
+                            .filter(|e| e.event.event_type == group.key[&Dimension::Type])
+                            .filter(|e| e.observed_at >= w[0] && e.observed_at < w[1])
+                            .count() as i64
+                    })
+                    .collect();
+                assert_eq!(group.counts, expected, "{:?} in {}", interval, tz);
+            }
+            let in_range = events
+                .iter()
+                .filter(|e| e.observed_at >= bounds[0] && e.observed_at < bounds[bounds.len() - 1])
+                .count();
+            assert_eq!(counts.total, in_range as i64);
+        }
+
+        // Past the limit, groups are summed into `other`
+        let query = CountQuery {
+            interval: Interval::Day,
+            tz: Tz::UTC,
+            since,
+            until,
+            group_by: vec![Dimension::SourceId, Dimension::Type],
+            limit: 4,
+            filter: EventFilter::default(),
+        };
+        let counts = count(&db, &query).unwrap();
+        assert_eq!(counts.groups.len(), 4);
+        assert!(counts.groups.windows(2).all(|g| g[0].total >= g[1].total));
+        let other = counts.other.unwrap();
+        assert_eq!(other.groups, 11);
+        assert_eq!(
+            counts.groups.iter().map(|g| g.total).sum::<i64>() + other.total,
+            counts.total
+        );
+        assert_eq!(counts.total, 411);
+    }
+}
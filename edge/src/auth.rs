@@ -0,0 +1,393 @@
+//! API key authentication for EdgeKite
+//!
+//! Keys are random bearer tokens stored as SHA-256 hashes in the `api_keys`
+//! table. Each key carries one scope; routes are grouped by the scope they
+//! require and guarded by the `require` middleware.
+
+use axum::{
+    extract::{Query, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, SubsecRound, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+
+use crate::db::Database;
+use crate::error::{Error, Result};
+use crate::server::error_response;
+
+/// What a key is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Submit events (browsers, devices)
+    Ingest,
+    /// Query events, stats and streams (dashboards)
+    Read,
+    /// Forward synced events to this agent in hub mode (downstream agents)
+    Relay,
+    /// Everything, including management endpoints
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Ingest => "ingest",
+            Scope::Read => "read",
+            Scope::Relay => "relay",
+            Scope::Admin => "admin",
+        }
+    }
+
+    /// Whether a key with this scope may access a route requiring `required`
+    pub fn allows(self, required: Scope) -> bool {
+        self == Scope::Admin || self == required
+    }
+}
+
+impl FromStr for Scope {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ingest" => Ok(Scope::Ingest),
+            "read" => Ok(Scope::Read),
+            "relay" => Ok(Scope::Relay),
+            "admin" => Ok(Scope::Admin),
+            other => Err(Error::Auth(format!("unknown scope '{}'", other))),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A stored API key (the secret itself is never stored)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiKey {
+    /// Short identifier, used to revoke the key
+    pub id: String,
+    /// Human-readable label
+    pub name: String,
+    pub scope: Scope,
+    /// Public keys are embedded in web pages and may be restricted by origin
+    pub public: bool,
+    /// Allowed `Origin` values for public keys (None = any)
+    pub allowed_origins: Option<Vec<String>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Create and store a new key, returning the record and the secret token
+///
+/// The token is only available here; it cannot be recovered later.
+pub fn create_key(
+    db: &Database,
+    name: &str,
+    scope: Scope,
+    public: bool,
+    allowed_origins: Vec<String>,
+) -> Result<(ApiKey, String)> {
+    if public && scope != Scope::Ingest {
+        return Err(Error::Auth(
+            "public keys must have the ingest scope".to_string(),
+        ));
+    }
+    if !public && !allowed_origins.is_empty() {
+        return Err(Error::Auth(
+            "origin restrictions only apply to public keys".to_string(),
+        ));
+    }
+
+    let token = format!("ek_{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let key = ApiKey {
+        id: Uuid::new_v4().simple().to_string()[..12].to_string(),
+        name: name.to_string(),
+        scope,
+        public,
+        allowed_origins: (!allowed_origins.is_empty()).then_some(allowed_origins),
+        created_at: Utc::now().trunc_subsecs(3),
+    };
+    db.insert_api_key(&key, &hash_key(&token))?;
+
+    Ok((key, token))
+}
+
+/// Hex-encoded SHA-256 of a token
+///
+/// Tokens carry 244 random bits, so a fast unsalted hash is sufficient.
+pub fn hash_key(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Why a request was not authorized
+#[derive(Debug, PartialEq, Eq)]
+pub enum Denied {
+    /// No token supplied
+    Missing,
+    /// Unknown or revoked token
+    Invalid,
+    /// Valid token that may not access this route
+    Forbidden(String),
+}
+
+/// Resolve a token and check it against the required scope and request origin
+pub fn authorize(
+    db: &Database,
+    token: Option<&str>,
+    origin: Option<&str>,
+    required: Scope,
+) -> std::result::Result<ApiKey, Denied> {
+    let token = token.ok_or(Denied::Missing)?;
+    let key = db
+        .find_api_key(&hash_key(token))
+        .map_err(|_| Denied::Invalid)?
+        .ok_or(Denied::Invalid)?;
+
+    if !key.scope.allows(required) {
+        return Err(Denied::Forbidden(format!(
+            "key scope '{}' does not allow {} access",
+            key.scope, required
+        )));
+    }
+    if let Some(allowed) = &key.allowed_origins {
+        if !origin.is_some_and(|o| allowed.iter().any(|a| a == o)) {
+            return Err(Denied::Forbidden(
+                "origin not allowed for this key".to_string(),
+            ));
+        }
+    }
+
+    Ok(key)
+}
+
+/// Middleware state: the scope a group of routes requires
+#[derive(Clone)]
+pub struct Guard {
+    pub db: Database,
+    pub required: Scope,
+    /// When false, requests pass through unauthenticated
+    pub enforce: bool,
+    /// Also accept the token as `?key=`, for clients that cannot set headers
+    pub query_key: bool,
+}
+
+#[derive(Deserialize)]
+struct KeyParam {
+    key: Option<String>,
+}
+
+/// Authenticate a request against the guard's required scope
+///
+/// The token is read from `Authorization: Bearer <token>`, or from the `key`
+/// query parameter on routes whose guard allows it (`sendBeacon` ingest and
+/// the `EventSource` stream, which cannot set headers). Query strings end up
+/// in logs and browser history, so other routes refuse a key sent that way.
+/// The matched `ApiKey` is added to request extensions.
+pub async fn require(State(guard): State<Guard>, mut req: Request, next: Next) -> Response {
+    if !guard.enforce {
+        return next.run(req).await;
+    }
+
+    let header_token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|t| t.trim().to_string());
+    let query_token = Query::<KeyParam>::try_from_uri(req.uri())
+        .ok()
+        .and_then(|q| q.0.key);
+    let token = match (header_token, query_token) {
+        (Some(token), _) => Some(token),
+        (None, Some(_)) if !guard.query_key => {
+            return unauthorized("send the API key in the Authorization header")
+        }
+        (None, query_token) => query_token,
+    };
+    let origin = req
+        .headers()
+        .get(header::ORIGIN)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    let required = guard.required;
+    let verdict = guard
+        .db
+        .blocking(move |db| authorize(db, token.as_deref(), origin.as_deref(), required))
+        .await;
+    match verdict {
+        Ok(key) => {
+            req.extensions_mut().insert(key);
+            next.run(req).await
+        }
+        Err(Denied::Missing) => unauthorized("missing API key"),
+        Err(Denied::Invalid) => unauthorized("invalid API key"),
+        Err(Denied::Forbidden(reason)) => error_response(StatusCode::FORBIDDEN, &reason),
+    }
+}
+
+fn unauthorized(message: &str) -> Response {
+    let mut response = error_response(StatusCode::UNAUTHORIZED, message);
+    response.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        header::HeaderValue::from_static("Bearer"),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn test_db() -> (tempfile::TempDir, Database) {
+        let dir = tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db")).unwrap();
+        db.migrate().unwrap();
+        (dir, db)
+    }
+
+    #[test]
+    fn test_scope_allows() {
+        assert!(Scope::Admin.allows(Scope::Ingest));
+        assert!(Scope::Admin.allows(Scope::Read));
+        assert!(Scope::Read.allows(Scope::Read));
+        assert!(!Scope::Read.allows(Scope::Ingest));
+        assert!(!Scope::Ingest.allows(Scope::Read));
+        assert!(!Scope::Ingest.allows(Scope::Admin));
+        assert!(Scope::Admin.allows(Scope::Relay));
+        assert!(!Scope::Ingest.allows(Scope::Relay));
+        assert_eq!("relay".parse::<Scope>().unwrap(), Scope::Relay);
+    }
+
+    #[test]
+    fn test_create_authorize_revoke() {
+        let (_dir, db) = test_db();
+        let (key, token) = create_key(&db, "dashboard", Scope::Read, false, vec![]).unwrap();
+        assert!(token.starts_with("ek_"));
+
+        let found = authorize(&db, Some(&token), None, Scope::Read).unwrap();
+        assert_eq!(found, key);
+        assert!(matches!(
+            authorize(&db, Some(&token), None, Scope::Ingest),
+            Err(Denied::Forbidden(_))
+        ));
+        assert_eq!(
+            authorize(&db, Some("ek_wrong"), None, Scope::Read),
+            Err(Denied::Invalid)
+        );
+        assert_eq!(
+            authorize(&db, None, None, Scope::Read),
+            Err(Denied::Missing)
+        );
+
+        assert!(db.revoke_api_key(&key.id).unwrap());
+        assert_eq!(
+            authorize(&db, Some(&token), None, Scope::Read),
+            Err(Denied::Invalid)
+        );
+        assert!(!db.revoke_api_key(&key.id).unwrap());
+    }
+
+    #[test]
+    fn test_public_key_origin_restriction() {
+        let (_dir, db) = test_db();
+        assert!(create_key(&db, "site", Scope::Read, true, vec![]).is_err());
+
+        let origins = vec!["https://example.com".to_string()];
+        let (_, token) = create_key(&db, "site", Scope::Ingest, true, origins).unwrap();
+
+        assert!(authorize(
+            &db,
+            Some(&token),
+            Some("https://example.com"),
+            Scope::Ingest
+        )
+        .is_ok());
+        assert!(matches!(
+            authorize(&db, Some(&token), Some("https://evil.test"), Scope::Ingest),
+            Err(Denied::Forbidden(_))
+        ));
+        assert!(matches!(
+            authorize(&db, Some(&token), None, Scope::Ingest),
+            Err(Denied::Forbidden(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_query_key_only_where_allowed() {
+        use axum::{body::Body, middleware, routing::get, Router};
+        use tower::Service;
+
+        let (_dir, db) = test_db();
+        let (_, token) = create_key(&db, "ops", Scope::Admin, false, vec![]).unwrap();
+        let guard = |required, query_key| {
+            middleware::from_fn_with_state(
+                Guard {
+                    db: db.clone(),
+                    required,
+                    enforce: true,
+                    query_key,
+                },
+                require,
+            )
+        };
+        let app = Router::new()
+            .route("/stream", get(|| async {}))
+            .route_layer(guard(Scope::Read, true))
+            .merge(
+                Router::new()
+                    .route("/admin", get(|| async {}))
+                    .route_layer(guard(Scope::Admin, false)),
+            )
+            .merge(
+                Router::new()
+                    .route("/relay", get(|| async {}))
+                    .route_layer(guard(Scope::Relay, false)),
+            );
+        let status = |uri: String, bearer: bool| {
+            let mut app = app.clone();
+            let mut request = Request::get(uri);
+            if bearer {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            let request = request.body(Body::empty()).unwrap();
+            async move { app.call(request).await.unwrap().status() }
+        };
+
+        assert_eq!(
+            status(format!("/stream?key={}", token), false).await,
+            StatusCode::OK
+        );
+        for path in ["/admin", "/relay"] {
+            assert_eq!(
+                status(format!("{}?key={}", path, token), false).await,
+                StatusCode::UNAUTHORIZED
+            );
+            assert_eq!(status(path.to_string(), true).await, StatusCode::OK);
+        }
+    }
+
+    #[test]
+    fn test_keys_are_stored_hashed() {
+        let (_dir, db) = test_db();
+        let (key, token) = create_key(&db, "camera", Scope::Ingest, false, vec![]).unwrap();
+
+        let listed = db.list_api_keys().unwrap();
+        assert_eq!(listed, vec![(key, None)]);
+        assert_eq!(hash_key(&token).len(), 64);
+        assert_ne!(hash_key(&token), token);
+    }
+}
@@ -0,0 +1,887 @@
+//! Sneakernet bundles for air-gapped sites
+//!
+//! `edge-kite bundle export` writes a destination's queued events (and
+//! optionally the media they reference) to a single file that a technician
+//! carries to a connected agent or hub. `bundle import` stores the events
+//! there and writes a receipt; `bundle receipt` applies it back on the
+//! exporting agent, where it acts like a sync acknowledgement.
+//!
+//! A bundle is a tar archive of:
+//!
+//! - `manifest.json`: bundle id, destination, event count and the SHA-256 of
+//!   every other entry
+//! - `manifest.sig`: optional HMAC-SHA256 of the manifest with a shared key
+//! - `events.jsonl.zst`: one event envelope per line, zstd-compressed
+//! - `media/<sha256>`: referenced `file://` attachments
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::db::{Cursor, Database, InsertOutcome};
+use crate::error::{Error, Result};
+use crate::event::Event;
+use crate::validate::Validator;
+
+const BUNDLE_FORMAT: &str = "edgekite-bundle";
+const RECEIPT_FORMAT: &str = "edgekite-receipt";
+const FORMAT_VERSION: u32 = 1;
+
+const MANIFEST_PATH: &str = "manifest.json";
+const SIGNATURE_PATH: &str = "manifest.sig";
+const EVENTS_PATH: &str = "events.jsonl.zst";
+const MEDIA_PREFIX: &str = "media/";
+
+/// Events read or stored per database call
+const CHUNK: usize = 1000;
+
+/// zstd level for the events file: bundles are written once, so compress harder
+const ZSTD_LEVEL: i32 = 9;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    format: String,
+    version: u32,
+    bundle_id: String,
+    created_at: DateTime<Utc>,
+    /// Queue on the exporting agent the events came from
+    destination: String,
+    event_count: usize,
+    events: Entry,
+    #[serde(default)]
+    media: Vec<Entry>,
+}
+
+/// A file in the archive
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    path: String,
+    sha256: String,
+    size: u64,
+    /// Attachment URI the file was read from (media only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uri: Option<String>,
+}
+
+/// What `export` should include
+#[derive(Debug, Default)]
+pub struct ExportOptions {
+    /// Also export events already in an earlier bundle (lost or damaged stick)
+    pub include_exported: bool,
+    /// Include `file://` attachments stored under this directory; others
+    /// are skipped, so a forged URI cannot pull arbitrary files into a bundle
+    pub media: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct ExportSummary {
+    pub bundle_id: String,
+    pub events: usize,
+    pub media_files: usize,
+}
+
+/// Write `destination`'s queued events to a bundle at `out`
+///
+/// Exported events are marked with the bundle id but stay queued until a
+/// receipt arrives. Returns None (and writes nothing) when there is nothing
+/// to export.
+pub fn export(
+    db: &Database,
+    destination: &str,
+    out: &Path,
+    options: &ExportOptions,
+    key: Option<&[u8]>,
+) -> Result<Option<ExportSummary>> {
+    let mut encoder = zstd::Encoder::new(Vec::new(), ZSTD_LEVEL)?;
+    let mut event_ids = Vec::new();
+    let mut attachments = Vec::new();
+    let mut after = None;
+    loop {
+        let page =
+            db.exportable_events(destination, options.include_exported, after.as_ref(), CHUNK)?;
+        let Some(last) = page.last() else { break };
+        after = Some(Cursor {
+            observed_at: last.observed_at.timestamp_millis(),
+            event_id: last.event_id.clone(),
+        });
+        for event in &page {
+            serde_json::to_writer(&mut encoder, event)?;
+            encoder.write_all(b"\n")?;
+            event_ids.push(event.event_id.clone());
+            if options.media.is_some() {
+                attachments.extend(event.attachments.iter().flatten().map(|a| a.uri.clone()));
+            }
+        }
+    }
+    if event_ids.is_empty() {
+        return Ok(None);
+    }
+    let events = encoder.finish()?;
+
+    // Hash media up front: the manifest goes first in the archive
+    let mut media = Vec::new();
+    let mut sources: HashMap<String, PathBuf> = HashMap::new();
+    let mut seen = HashSet::new();
+    let media_root = match options.media.as_deref().map(fs::canonicalize) {
+        Some(Ok(root)) => Some(root),
+        Some(Err(e)) => {
+            if !attachments.is_empty() {
+                warn!(
+                    "Skipping all attachments: media directory unavailable: {}",
+                    e
+                );
+            }
+            None
+        }
+        None => None,
+    };
+    for uri in attachments {
+        let Some(root) = &media_root else { break };
+        let Some(path) = uri.strip_prefix("file://").map(PathBuf::from) else {
+            continue;
+        };
+        if !seen.insert(uri.clone()) {
+            continue;
+        }
+        // Resolve `..` and symlinks before checking where the file lives
+        let path = match fs::canonicalize(&path) {
+            Ok(path) if path.starts_with(root) => path,
+            Ok(_) => {
+                warn!("Skipping attachment {}: outside {}", uri, root.display());
+                continue;
+            }
+            Err(e) => {
+                warn!("Skipping attachment {}: {}", uri, e);
+                continue;
+            }
+        };
+        match hash_file(&path) {
+            Ok((sha256, size)) => {
+                sources.insert(sha256.clone(), path);
+                media.push(Entry {
+                    path: format!("{}{}", MEDIA_PREFIX, sha256),
+                    sha256,
+                    size,
+                    uri: Some(uri),
+                });
+            }
+            Err(e) => warn!("Skipping attachment {}: {}", uri, e),
+        }
+    }
+
+    let bundle_id = Uuid::new_v4().to_string();
+    let manifest = Manifest {
+        format: BUNDLE_FORMAT.to_string(),
+        version: FORMAT_VERSION,
+        bundle_id: bundle_id.clone(),
+        created_at: Utc::now(),
+        destination: destination.to_string(),
+        event_count: event_ids.len(),
+        events: Entry {
+            path: EVENTS_PATH.to_string(),
+            sha256: sha256_hex(&events),
+            size: events.len() as u64,
+            uri: None,
+        },
+        media,
+    };
+    let manifest_json = serde_json::to_vec_pretty(&manifest)?;
+
+    // Write next to the target and rename, so a full stick never leaves a
+    // truncated bundle behind under the final name
+    let mut partial = out.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    {
+        let mut archive = tar::Builder::new(File::create(&partial)?);
+        append(&mut archive, MANIFEST_PATH, &manifest_json)?;
+        if let Some(key) = key {
+            append(
+                &mut archive,
+                SIGNATURE_PATH,
+                sign(key, &manifest_json).as_bytes(),
+            )?;
+        }
+        append(&mut archive, EVENTS_PATH, &events)?;
+        for entry in &manifest.media {
+            // Identical files referenced under several URIs are stored once
+            if let Some(source) = sources.remove(&entry.sha256) {
+                let mut header = header(entry.size);
+                archive.append_data(&mut header, &entry.path, File::open(source)?)?;
+            }
+        }
+        archive.into_inner()?.sync_all()?;
+    }
+    fs::rename(&partial, out)?;
+
+    db.mark_exported(destination, &bundle_id, &event_ids)?;
+    Ok(Some(ExportSummary {
+        bundle_id,
+        events: event_ids.len(),
+        media_files: manifest.media.len(),
+    }))
+}
+
+/// Result of importing a bundle, carried back to the exporting agent
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Receipt {
+    pub format: String,
+    pub version: u32,
+    pub bundle_id: String,
+    /// Queue on the exporting agent the bundle came from
+    pub destination: String,
+    pub imported_at: DateTime<Utc>,
+    /// Stored or already present; these leave the queue
+    pub accepted: Vec<String>,
+    /// Count towards dead letters; the rest go into the next bundle
+    pub rejected: Vec<RejectedEvent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RejectedEvent {
+    pub event_id: String,
+    pub reason: String,
+}
+
+impl Receipt {
+    /// Write the receipt as JSON, signed when a key is given
+    pub fn save(&self, path: &Path, key: Option<&[u8]>) -> Result<()> {
+        let mut receipt = self.clone();
+        receipt.signature = None;
+        if let Some(key) = key {
+            receipt.signature = Some(sign(key, &serde_json::to_vec(&receipt)?));
+        }
+        fs::write(path, serde_json::to_vec_pretty(&receipt)?)?;
+        Ok(())
+    }
+
+    /// Read a receipt; with a key, unsigned or tampered receipts are refused
+    pub fn load(path: &Path, key: Option<&[u8]>) -> Result<Self> {
+        let mut receipt: Receipt = serde_json::from_slice(&fs::read(path)?)?;
+        if receipt.format != RECEIPT_FORMAT || receipt.version > FORMAT_VERSION {
+            return Err(invalid(format!(
+                "{} is not a version {} receipt",
+                path.display(),
+                FORMAT_VERSION
+            )));
+        }
+        let signature = receipt.signature.take();
+        if let Some(key) = key {
+            let signed = serde_json::to_vec(&receipt)?;
+            if !signature.is_some_and(|s| verify(key, &signed, &s)) {
+                return Err(invalid(
+                    "receipt signature does not match the key".to_string(),
+                ));
+            }
+        }
+        Ok(receipt)
+    }
+}
+
+#[derive(Debug)]
+pub struct ImportSummary {
+    pub receipt: Receipt,
+    /// Newly stored events (the rest of `accepted` were already present)
+    pub stored: usize,
+    pub media_files: usize,
+}
+
+/// Store a bundle's events, as if they had been relayed by the exporting agent
+///
+/// Every checksum is verified before anything is stored. With a key, the
+/// bundle must carry a matching signature; without one, a signed bundle is
+/// refused unless `allow_unverified` is set. Events are checked by the ingest
+/// `validator`, and those it refuses are listed in the receipt as rejected.
+/// Media is saved in `media_dir` under its SHA-256 and attachment URIs are
+/// rewritten to point there.
+pub fn import(
+    db: &Database,
+    validator: &Validator,
+    bundle: &Path,
+    media_dir: &Path,
+    key: Option<&[u8]>,
+    allow_unverified: bool,
+) -> Result<ImportSummary> {
+    let mut archive = tar::Archive::new(File::open(bundle)?);
+    let mut manifest_json = None;
+    let mut signature = None;
+    let mut events = None;
+    // Media is staged under a temporary name until it is known to be wanted
+    let mut staged: HashMap<String, PathBuf> = HashMap::new();
+    fs::create_dir_all(media_dir)?;
+
+    let result = (|| -> Result<()> {
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().into_owned();
+            match path.as_str() {
+                MANIFEST_PATH => manifest_json = Some(read_all(&mut entry)?),
+                SIGNATURE_PATH => {
+                    signature = Some(String::from_utf8_lossy(&read_all(&mut entry)?).into_owned())
+                }
+                EVENTS_PATH => events = Some(read_all(&mut entry)?),
+                media if media.starts_with(MEDIA_PREFIX) => {
+                    let staging = media_dir.join(format!(".import-{}", Uuid::new_v4().simple()));
+                    let sha256 = copy_hashed(&mut entry, &staging)?;
+                    staged.insert(sha256, staging);
+                }
+                other => warn!("Ignoring unexpected bundle entry {}", other),
+            }
+        }
+        Ok(())
+    })();
+    let discard = |staged: &HashMap<String, PathBuf>| {
+        for path in staged.values() {
+            let _ = fs::remove_file(path);
+        }
+    };
+    if let Err(e) = result {
+        discard(&staged);
+        return Err(e);
+    }
+
+    let verified = verify_bundle(
+        manifest_json.as_deref(),
+        signature.as_deref(),
+        events.as_deref(),
+        &staged,
+        key,
+        allow_unverified,
+    );
+    let manifest = match verified {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            discard(&staged);
+            return Err(e);
+        }
+    };
+
+    // Keep verified media under its hash; the attachments are rewritten to it
+    let mut moved = HashMap::new();
+    for entry in &manifest.media {
+        let target = media_dir.join(&entry.sha256);
+        if let Some(staging) = staged.remove(&entry.sha256) {
+            fs::rename(staging, &target)?;
+        }
+        if let Some(uri) = &entry.uri {
+            let target = fs::canonicalize(&target).unwrap_or(target);
+            moved.insert(uri.clone(), format!("file://{}", target.display()));
+        }
+    }
+    discard(&staged);
+
+    let mut receipt = Receipt {
+        format: RECEIPT_FORMAT.to_string(),
+        version: FORMAT_VERSION,
+        bundle_id: manifest.bundle_id.clone(),
+        destination: manifest.destination.clone(),
+        imported_at: Utc::now(),
+        accepted: Vec::new(),
+        rejected: Vec::new(),
+        signature: None,
+    };
+    let mut stored = 0;
+    let decoder = io::BufReader::new(zstd::Decoder::new(events.as_deref().unwrap_or_default())?);
+    let mut batch = Vec::with_capacity(CHUNK);
+    for line in decoder.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let raw: serde_json::Value = match serde_json::from_str(&line) {
+            Ok(raw) => raw,
+            Err(e) => {
+                warn!("Skipping unreadable bundle line: {}", e);
+                continue;
+            }
+        };
+        let claimed_id = raw
+            .get("event_id")
+            .and_then(|id| id.as_str())
+            .map(str::to_string);
+        let mut event = match serde_json::from_value::<Event>(raw) {
+            Ok(event) => event.into_relayed(),
+            Err(e) => {
+                if let Some(event_id) = claimed_id {
+                    receipt.rejected.push(RejectedEvent {
+                        event_id,
+                        reason: format!("malformed event: {}", e),
+                    });
+                }
+                continue;
+            }
+        };
+        for attachment in event.attachments.iter_mut().flatten() {
+            if let Some(local) = moved.get(&attachment.uri) {
+                attachment.uri = local.clone();
+            }
+        }
+        if let Err(reason) = validator.check(&event) {
+            receipt.rejected.push(RejectedEvent {
+                event_id: event.event_id,
+                reason,
+            });
+            continue;
+        }
+        batch.push(event);
+        if batch.len() == CHUNK {
+            stored += store(db, &mut batch, &mut receipt)?;
+        }
+    }
+    stored += store(db, &mut batch, &mut receipt)?;
+
+    Ok(ImportSummary {
+        receipt,
+        stored,
+        media_files: manifest.media.len(),
+    })
+}
+
+/// Insert a chunk of imported events, recording the outcomes in the receipt
+fn store(db: &Database, batch: &mut Vec<Event>, receipt: &mut Receipt) -> Result<usize> {
+    let outcomes = db.insert_events(batch)?;
+    let mut stored = 0;
+    for (event, outcome) in batch.drain(..).zip(outcomes) {
+        match outcome {
+            InsertOutcome::Inserted(_) => {
+                stored += 1;
+                receipt.accepted.push(event.event_id);
+            }
+            InsertOutcome::Duplicate => receipt.accepted.push(event.event_id),
+            InsertOutcome::Failed(reason) => receipt.rejected.push(RejectedEvent {
+                event_id: event.event_id,
+                reason,
+            }),
+        }
+    }
+    Ok(stored)
+}
+
+/// Check the manifest, its signature and every checksum
+fn verify_bundle(
+    manifest_json: Option<&[u8]>,
+    signature: Option<&str>,
+    events: Option<&[u8]>,
+    staged: &HashMap<String, PathBuf>,
+    key: Option<&[u8]>,
+    allow_unverified: bool,
+) -> Result<Manifest> {
+    let manifest_json =
+        manifest_json.ok_or_else(|| invalid("bundle has no manifest".to_string()))?;
+    let manifest: Manifest = serde_json::from_slice(manifest_json)?;
+    if manifest.format != BUNDLE_FORMAT || manifest.version > FORMAT_VERSION {
+        return Err(invalid(format!(
+            "not a version {} bundle (format '{}', version {})",
+            FORMAT_VERSION, manifest.format, manifest.version
+        )));
+    }
+
+    match (key, signature) {
+        (Some(key), Some(signature)) if verify(key, manifest_json, signature.trim()) => {}
+        (Some(_), Some(_)) => {
+            return Err(invalid(
+                "bundle signature does not match the key".to_string(),
+            ))
+        }
+        (Some(_), None) => return Err(invalid("bundle is not signed".to_string())),
+        (None, Some(_)) if allow_unverified => warn!(
+            "Bundle {} is signed but no key was given; signature not checked",
+            manifest.bundle_id
+        ),
+        (None, Some(_)) => {
+            return Err(invalid(format!(
+                "bundle {} is signed; give --key to verify it, or --allow-unverified to import it unchecked",
+                manifest.bundle_id
+            )))
+        }
+        (None, None) => {}
+    }
+
+    let events = events.ok_or_else(|| invalid("bundle has no events file".to_string()))?;
+    if events.len() as u64 != manifest.events.size || sha256_hex(events) != manifest.events.sha256 {
+        return Err(invalid("events file checksum mismatch".to_string()));
+    }
+    for entry in &manifest.media {
+        if !is_sha256(&entry.sha256) || !staged.contains_key(&entry.sha256) {
+            return Err(invalid(format!(
+                "media file {} is missing or damaged",
+                entry.path
+            )));
+        }
+    }
+    Ok(manifest)
+}
+
+/// Outcome of applying a receipt on the exporting agent
+#[derive(Debug)]
+pub struct ReceiptSummary {
+    /// Events removed from the queue
+    pub synced: usize,
+    /// Rejected events that became dead letters
+    pub dead_lettered: usize,
+}
+
+/// Apply an import receipt to the destination queue it names
+pub fn apply_receipt(
+    db: &Database,
+    receipt: &Receipt,
+    max_reject_attempts: u32,
+) -> Result<ReceiptSummary> {
+    let synced = db.mark_synced(&receipt.destination, &receipt.accepted)?;
+    let rejections: Vec<(String, String)> = receipt
+        .rejected
+        .iter()
+        .map(|r| (r.event_id.clone(), r.reason.clone()))
+        .collect();
+    let dead_lettered =
+        db.record_sync_rejections(&receipt.destination, &rejections, max_reject_attempts)?;
+    Ok(ReceiptSummary {
+        synced,
+        dead_lettered,
+    })
+}
+
+/// Read a shared signing key (surrounding whitespace is ignored)
+pub fn load_key(path: &Path) -> Result<Vec<u8>> {
+    let key = fs::read_to_string(path)?.trim().as_bytes().to_vec();
+    if key.is_empty() {
+        return Err(invalid(format!("key file {} is empty", path.display())));
+    }
+    Ok(key)
+}
+
+fn header(size: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp().max(0) as u64);
+    header
+}
+
+fn append(archive: &mut tar::Builder<File>, path: &str, data: &[u8]) -> io::Result<()> {
+    archive.append_data(&mut header(data.len() as u64), path, data)
+}
+
+fn read_all(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Copy `reader` to `path`, returning the hex SHA-256 of what was written
+fn copy_hashed(reader: &mut impl Read, path: &Path) -> io::Result<String> {
+    let mut file = File::create(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        file.write_all(&buf[..n])?;
+    }
+    file.sync_all()?;
+    Ok(hex(&hasher.finalize()))
+}
+
+fn hash_file(path: &Path) -> io::Result<(String, u64)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher)?;
+    Ok((hex(&hasher.finalize()), size))
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn sign(key: &[u8], data: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    hex(&mac.finalize().into_bytes())
+}
+
+fn verify(key: &[u8], data: &[u8], signature: &str) -> bool {
+    let Some(expected) = unhex(signature) else {
+        return false;
+    };
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.verify_slice(&expected).is_ok()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn is_sha256(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn invalid(message: String) -> Error {
+    Error::Bundle(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{SyncCompression, Transport, ValidationMode};
+    use crate::db::EventFilter;
+    use crate::destination::{Destination, DestinationFilter};
+    use crate::event::test_support::test_event;
+    use tempfile::tempdir;
+
+    fn open(path: &Path, destinations: &[Destination]) -> Database {
+        let db = Database::open(path).unwrap();
+        db.migrate().unwrap();
+        db.set_destinations(destinations).unwrap();
+        db
+    }
+
+    fn usb() -> Destination {
+        Destination {
+            name: "usb".to_string(),
+            transport: Transport::Bundle,
+            hub_url: String::new(),
+            api_key: String::new(),
+            batch_size: 100,
+            max_batch_size: 1000,
+            compression: SyncCompression::Auto,
+            filter: DestinationFilter::default(),
+        }
+    }
+
+    fn file_uri(path: &Path) -> String {
+        format!("file://{}", path.display())
+    }
+
+    fn pending(db: &Database) -> i64 {
+        db.outbox_counts().unwrap().iter().map(|c| c.pending).sum()
+    }
+
+    #[test]
+    fn test_export_import_receipt() {
+        let dir = tempdir().unwrap();
+        let site_media = dir.path().join("site-media");
+        fs::create_dir(&site_media).unwrap();
+        let clip = site_media.join("clip.mp4");
+        fs::write(&clip, b"not really a video").unwrap();
+        let site = open(&dir.path().join("site.db"), &[usb()]);
+        let events = vec![
+            test_event("motion").attachment(&file_uri(&clip)).build(),
+            test_event("motion").build(),
+            test_event("motion").build(),
+        ];
+        site.insert_events(&events).unwrap();
+
+        let out = dir.path().join("site.bundle");
+        let options = ExportOptions {
+            include_exported: false,
+            media: Some(site_media),
+        };
+        let summary = export(&site, "usb", &out, &options, None).unwrap().unwrap();
+        assert_eq!((summary.events, summary.media_files), (3, 1));
+        // Exported events stay queued but are not exported twice
+        assert_eq!(pending(&site), 3);
+        assert!(
+            export(&site, "usb", &dir.path().join("again"), &options, None)
+                .unwrap()
+                .is_none()
+        );
+
+        let hub = open(&dir.path().join("hub.db"), &[]);
+        let validator = Validator::new(ValidationMode::Strict);
+        let media_dir = dir.path().join("media");
+        let imported = import(&hub, &validator, &out, &media_dir, None, false).unwrap();
+        assert_eq!(imported.stored, 3);
+        assert_eq!(imported.receipt.accepted.len(), 3);
+        assert_eq!(hub.event_count().unwrap(), 3);
+
+        // The attachment now points at the copy in the media directory
+        let page = hub.query_events(&EventFilter::default(), None, 10).unwrap();
+        let uri = page
+            .events
+            .iter()
+            .find_map(|e| e.attachments.as_ref())
+            .map(|a| a[0].uri.clone())
+            .unwrap();
+        let copy = PathBuf::from(uri.strip_prefix("file://").unwrap());
+        assert!(copy.starts_with(fs::canonicalize(&media_dir).unwrap()));
+        assert_eq!(fs::read(copy).unwrap(), b"not really a video");
+
+        // Importing the same bundle twice is harmless
+        let again = import(&hub, &validator, &out, &media_dir, None, false).unwrap();
+        assert_eq!((again.stored, again.receipt.accepted.len()), (0, 3));
+
+        // A rejected event goes back into the next bundle
+        let mut receipt = imported.receipt;
+        let rejected = receipt.accepted.pop().unwrap();
+        receipt.rejected.push(RejectedEvent {
+            event_id: rejected.clone(),
+            reason: "schema".to_string(),
+        });
+        let receipt_path = dir.path().join("receipt.json");
+        receipt.save(&receipt_path, None).unwrap();
+        let receipt = Receipt::load(&receipt_path, None).unwrap();
+        let applied = apply_receipt(&site, &receipt, 5).unwrap();
+        assert_eq!((applied.synced, applied.dead_lettered), (2, 0));
+        assert_eq!(pending(&site), 1);
+        let next = site.exportable_events("usb", false, None, 10).unwrap();
+        assert_eq!(next.len(), 1);
+        assert_eq!(next[0].event_id, rejected);
+    }
+
+    #[test]
+    fn test_export_reads_media_only_from_media_dir() {
+        let dir = tempdir().unwrap();
+        let site_media = dir.path().join("media");
+        fs::create_dir(&site_media).unwrap();
+        fs::write(site_media.join("clip.mp4"), b"clip").unwrap();
+        let secret = dir.path().join("secret.key");
+        fs::write(&secret, b"do not export").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&secret, site_media.join("link.mp4")).unwrap();
+
+        let site = open(&dir.path().join("site.db"), &[usb()]);
+        let events = vec![
+            test_event("motion")
+                .attachment(&file_uri(&site_media.join("clip.mp4")))
+                .build(),
+            test_event("motion").attachment(&file_uri(&secret)).build(),
+            test_event("motion")
+                .attachment(&file_uri(&site_media.join("../secret.key")))
+                .build(),
+            test_event("motion")
+                .attachment(&file_uri(&site_media.join("link.mp4")))
+                .build(),
+        ];
+        site.insert_events(&events).unwrap();
+
+        let out = dir.path().join("site.bundle");
+        let options = ExportOptions {
+            include_exported: false,
+            media: Some(site_media),
+        };
+        let summary = export(&site, "usb", &out, &options, None).unwrap().unwrap();
+        assert_eq!((summary.events, summary.media_files), (4, 1));
+
+        let mut archive = tar::Archive::new(File::open(&out).unwrap());
+        for entry in archive.entries().unwrap() {
+            let mut contents = Vec::new();
+            entry.unwrap().read_to_end(&mut contents).unwrap();
+            assert_ne!(contents, b"do not export");
+        }
+    }
+
+    #[test]
+    fn test_signatures() {
+        let dir = tempdir().unwrap();
+        let site = open(&dir.path().join("site.db"), &[usb()]);
+        site.insert_events(&[test_event("motion").build()]).unwrap();
+        let out = dir.path().join("site.bundle");
+        export(
+            &site,
+            "usb",
+            &out,
+            &ExportOptions::default(),
+            Some(b"secret"),
+        )
+        .unwrap()
+        .unwrap();
+
+        let hub = open(&dir.path().join("hub.db"), &[]);
+        let validator = Validator::new(ValidationMode::Strict);
+        let media_dir = dir.path().join("media");
+        assert!(import(&hub, &validator, &out, &media_dir, Some(b"wrong"), false).is_err());
+        // A signed bundle is not imported unchecked by accident
+        assert!(import(&hub, &validator, &out, &media_dir, None, false).is_err());
+        assert_eq!(hub.event_count().unwrap(), 0);
+        let imported = import(&hub, &validator, &out, &media_dir, Some(b"secret"), false).unwrap();
+        assert_eq!(imported.stored, 1);
+
+        let receipt_path = dir.path().join("receipt.json");
+        imported
+            .receipt
+            .save(&receipt_path, Some(b"secret"))
+            .unwrap();
+        assert!(Receipt::load(&receipt_path, Some(b"wrong")).is_err());
+        assert_eq!(
+            Receipt::load(&receipt_path, Some(b"secret")).unwrap(),
+            imported.receipt
+        );
+
+        // An unsigned bundle is refused when a key is expected
+        site.insert_events(&[test_event("motion").build()]).unwrap();
+        let unsigned = dir.path().join("unsigned.bundle");
+        export(&site, "usb", &unsigned, &ExportOptions::default(), None)
+            .unwrap()
+            .unwrap();
+        assert!(import(
+            &hub,
+            &validator,
+            &unsigned,
+            &media_dir,
+            Some(b"secret"),
+            false
+        )
+        .is_err());
+
+        // Unless asked for explicitly
+        let unchecked = import(&hub, &validator, &out, &media_dir, None, true).unwrap();
+        assert_eq!(unchecked.receipt.accepted.len(), 1);
+    }
+
+    #[test]
+    fn test_import_validates_events() {
+        let dir = tempdir().unwrap();
+        let site = open(&dir.path().join("site.db"), &[usb()]);
+        let valid = test_event("motion").build();
+        let mut invalid = test_event("motion").build();
+        invalid.event.category = "bogus".to_string();
+        site.insert_events(&[valid.clone(), invalid.clone()])
+            .unwrap();
+        let out = dir.path().join("site.bundle");
+        export(&site, "usb", &out, &ExportOptions::default(), None)
+            .unwrap()
+            .unwrap();
+
+        let hub = open(&dir.path().join("hub.db"), &[]);
+        let validator = Validator::new(ValidationMode::Strict);
+        let imported = import(
+            &hub,
+            &validator,
+            &out,
+            &dir.path().join("media"),
+            None,
+            false,
+        )
+        .unwrap();
+        assert_eq!(imported.receipt.accepted, vec![valid.event_id]);
+        assert_eq!(imported.receipt.rejected.len(), 1);
+        assert_eq!(imported.receipt.rejected[0].event_id, invalid.event_id);
+        assert!(imported.receipt.rejected[0].reason.contains("category"));
+        assert_eq!(hub.event_count().unwrap(), 1);
+    }
+}
@@ -0,0 +1,140 @@
+//! Payload compression for hub sync
+//!
+//! The hub advertises the content codings it accepts; the agent compresses
+//! uploads with the best one allowed by `sync.compression`. In hub mode the
+//! agent is on the receiving end and decompresses uploads itself.
+
+use flate2::write::GzEncoder;
+use std::io::{self, Read, Write};
+
+use crate::config::SyncCompression;
+
+/// HTTP content coding of a sync upload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Zstd,
+}
+
+/// zstd level: fast, and most of the gain for JSON
+const ZSTD_LEVEL: i32 = 3;
+
+/// Encodings accepted in hub mode, most preferred first
+pub const ACCEPTED: [Encoding; 2] = [Encoding::Zstd, Encoding::Gzip];
+
+impl Encoding {
+    /// `Content-Encoding` token
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    pub fn parse(token: &str) -> Option<Self> {
+        match token.trim().to_ascii_lowercase().as_str() {
+            "identity" => Some(Encoding::Identity),
+            "gzip" => Some(Encoding::Gzip),
+            "zstd" => Some(Encoding::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Identity => Ok(data.to_vec()),
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Encoding::Zstd => zstd::encode_all(data, ZSTD_LEVEL),
+        }
+    }
+
+    /// Decode an upload, failing once the output exceeds `limit` bytes
+    pub fn decompress(self, data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        let reader: Box<dyn Read + '_> = match self {
+            Encoding::Identity => Box::new(data),
+            Encoding::Gzip => Box::new(flate2::read::GzDecoder::new(data)),
+            Encoding::Zstd => Box::new(zstd::Decoder::new(data)?),
+        };
+        let mut out = Vec::new();
+        reader.take(limit as u64 + 1).read_to_end(&mut out)?;
+        if out.len() > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                format!("body exceeds {} bytes", limit),
+            ));
+        }
+        Ok(out)
+    }
+}
+
+/// Pick the upload encoding from the configured preference and what the hub accepts
+///
+/// `auto` prefers zstd, then gzip. An explicitly configured encoding the hub
+/// does not advertise falls back to identity.
+pub fn negotiate(preference: SyncCompression, advertised: &[Encoding]) -> Encoding {
+    let candidates: &[Encoding] = match preference {
+        SyncCompression::None => &[],
+        SyncCompression::Gzip => &[Encoding::Gzip],
+        SyncCompression::Zstd => &[Encoding::Zstd],
+        SyncCompression::Auto => &[Encoding::Zstd, Encoding::Gzip],
+    };
+    candidates
+        .iter()
+        .copied()
+        .find(|e| advertised.contains(e))
+        .unwrap_or(Encoding::Identity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let data = br#"[{"event_id":"a","data":{}}]"#.repeat(50);
+
+        let gzip = Encoding::Gzip.compress(&data).unwrap();
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(gzip.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+        assert!(gzip.len() < data.len());
+
+        let zstd = Encoding::Zstd.compress(&data).unwrap();
+        assert_eq!(zstd::decode_all(zstd.as_slice()).unwrap(), data);
+        assert!(zstd.len() < data.len());
+
+        assert_eq!(Encoding::Identity.compress(&data).unwrap(), data);
+
+        for encoding in [Encoding::Identity, Encoding::Gzip, Encoding::Zstd] {
+            let body = encoding.compress(&data).unwrap();
+            assert_eq!(encoding.decompress(&body, data.len()).unwrap(), data);
+            assert!(encoding.decompress(&body, data.len() - 1).is_err());
+        }
+        assert!(Encoding::Gzip.decompress(b"not gzip", 1024).is_err());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let both = [Encoding::Gzip, Encoding::Zstd];
+        assert_eq!(negotiate(SyncCompression::Auto, &both), Encoding::Zstd);
+        assert_eq!(
+            negotiate(SyncCompression::Auto, &[Encoding::Gzip]),
+            Encoding::Gzip
+        );
+        assert_eq!(negotiate(SyncCompression::Auto, &[]), Encoding::Identity);
+        assert_eq!(negotiate(SyncCompression::Gzip, &both), Encoding::Gzip);
+        assert_eq!(
+            negotiate(SyncCompression::Zstd, &[Encoding::Gzip]),
+            Encoding::Identity
+        );
+        assert_eq!(negotiate(SyncCompression::None, &both), Encoding::Identity);
+    }
+}
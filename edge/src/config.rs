@@ -1,12 +1,12 @@
 //! Configuration handling for EdgeKite
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 
 use crate::error::Result;
 
 /// Main configuration struct
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Config {
     /// Data directory for SQLite and media
     #[serde(default = "default_data_dir")]
@@ -22,12 +22,19 @@ pub struct Config {
 
     /// Retention configuration (used by cleanup worker)
     #[serde(default)]
-    #[allow(dead_code)]
     pub retention: RetentionConfig,
+
+    /// Ingest validation configuration
+    #[serde(default)]
+    pub validation: ValidationConfig,
+
+    /// Hub mode: accept sync uploads from downstream agents
+    #[serde(default)]
+    pub hub: HubConfig,
 }
 
 /// HTTP server configuration
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ServerConfig {
     /// Listen address
     #[serde(default = "default_listen")]
@@ -40,9 +47,89 @@ pub struct ServerConfig {
     /// Path to static UI files (used when SPA is added)
     #[allow(dead_code)]
     pub ui_path: Option<PathBuf>,
+
+    /// Require API keys (see `edge-kite keys create`); `/api/health` stays open
+    #[serde(default)]
+    pub auth_required: bool,
+
+    /// Ingest rate limiting
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+
+    /// Buffer between the ingest endpoints and the database
+    #[serde(default)]
+    pub ingest_queue: IngestQueueConfig,
+
+    /// Seconds to drain requests and flush sync after SIGTERM/SIGINT
+    /// (keep below the supervisor's stop timeout: Docker 10, systemd 90)
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout_seconds: u64,
+}
+
+/// Ingest rate limiting (token buckets per source, API key and client IP)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// Enable rate limiting on the ingest endpoints
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+
+    /// Events per `source.id`
+    #[serde(default = "default_source_limit")]
+    pub per_source: BucketConfig,
+
+    /// Requests per API key (only applies when `auth_required` is set)
+    #[serde(default = "default_key_limit")]
+    pub per_key: BucketConfig,
+
+    /// Requests per client IP
+    #[serde(default = "default_ip_limit")]
+    pub per_ip: BucketConfig,
+
+    /// Take the client IP from the first `X-Forwarded-For` entry
+    /// (only enable behind a trusted reverse proxy)
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+}
+
+/// Bounded buffer of events waiting to be stored
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IngestQueueConfig {
+    /// Events that may wait for the database at once
+    #[serde(default = "default_ingest_queue_capacity")]
+    pub capacity: usize,
+
+    /// What to do with events that arrive while the queue is full
+    #[serde(default)]
+    pub when_full: QueueFullPolicy,
+
+    /// `Retry-After` sent with 503 responses while the queue is full
+    #[serde(default = "default_ingest_retry_after")]
+    pub retry_after_seconds: u64,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Handling of events that arrive at a full ingest queue
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueFullPolicy {
+    /// Refuse the request with 503 and `Retry-After`
+    #[default]
+    Reject,
+    /// Make room by dropping the least severe waiting events, newest first;
+    /// an event no more severe than everything waiting is dropped itself
+    DropLowestSeverity,
+}
+
+/// Token bucket parameters
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BucketConfig {
+    /// Sustained rate (0 = unlimited)
+    pub per_second: f64,
+
+    /// Maximum burst above the sustained rate
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SyncConfig {
     /// Enable sync to hub
     #[serde(default)]
@@ -71,23 +158,180 @@ pub struct SyncConfig {
     /// Base delay for retry backoff (ms)
     #[serde(default = "default_retry_delay")]
     pub retry_base_delay_ms: u64,
+
+    /// Hub rejections after which an event is moved to the dead-letter state
+    #[serde(default = "default_max_reject_attempts")]
+    pub max_reject_attempts: u32,
+
+    /// Upload compression, limited to what the hub advertises
+    #[serde(default)]
+    pub compression: SyncCompression,
+
+    /// Upper bound for batches grown to make use of compression
+    #[serde(default = "default_max_batch_size")]
+    pub max_batch_size: usize,
+
+    /// Upload budget per day in MB (0 = unlimited)
+    #[serde(default)]
+    pub daily_budget_mb: f64,
+
+    /// Upload budget per calendar month in MB (0 = unlimited)
+    #[serde(default)]
+    pub monthly_budget_mb: f64,
+
+    /// Local-time windows for bulk sync, e.g. `["22:00-06:00"]` (empty = always)
+    #[serde(default)]
+    pub windows: Vec<String>,
+
+    /// Events at or above this severity bypass windows and budgets
+    #[serde(default = "default_priority_min_severity")]
+    pub priority_min_severity: String,
+
+    /// Event categories that bypass windows and budgets
+    #[serde(default = "default_priority_categories")]
+    pub priority_categories: Vec<String>,
+
+    /// Hubs to send events to; when empty, `hub_url` and `api_key` form a
+    /// single destination named `default`
+    #[serde(default)]
+    pub destinations: Vec<DestinationConfig>,
+}
+
+/// One hub events are sent to (`[[sync.destinations]]`)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DestinationConfig {
+    /// Unique name, used in the outbox, status and metrics
+    pub name: String,
+
+    /// How events reach this destination
+    #[serde(default)]
+    pub transport: Transport,
+
+    /// Hub URL (not used by bundle destinations)
+    #[serde(default)]
+    pub hub_url: String,
+
+    /// API key for this hub
+    #[serde(default)]
+    pub api_key: String,
+
+    /// Overrides `sync.batch_size`
+    #[serde(default)]
+    pub batch_size: Option<usize>,
+
+    /// Overrides `sync.max_batch_size`
+    #[serde(default)]
+    pub max_batch_size: Option<usize>,
+
+    /// Overrides `sync.compression`
+    #[serde(default)]
+    pub compression: Option<SyncCompression>,
+
+    /// Only send events in these categories (empty = all)
+    #[serde(default)]
+    pub categories: Vec<String>,
+
+    /// Only send events of these types (empty = all)
+    #[serde(default)]
+    pub types: Vec<String>,
+
+    /// Only send events at or above this severity
+    #[serde(default)]
+    pub min_severity: Option<String>,
+}
+
+/// How a destination receives its events
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// Uploaded by a sync worker
+    #[default]
+    Http,
+    /// Carried over by hand with `edge-kite bundle export` (air-gapped sites)
+    Bundle,
+}
+
+/// Sync upload compression
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncCompression {
+    /// Best encoding the hub supports (zstd, then gzip)
+    #[default]
+    Auto,
+    Zstd,
+    Gzip,
+    None,
 }
 
 /// Retention configuration (for cleanup worker)
-#[allow(dead_code)]
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RetentionConfig {
-    /// Days to retain events locally
+    /// Days to retain `standard` retention class events locally
     #[serde(default = "default_retention_days")]
     pub events_days: u32,
 
-    /// Days to retain media locally
+    /// Days to retain `short` retention class events
+    #[serde(default = "default_short_days")]
+    pub short_days: u32,
+
+    /// Days to retain `long` retention class events
+    #[serde(default = "default_long_days")]
+    pub long_days: u32,
+
+    /// Days to retain media locally (media cleanup not yet implemented)
     #[serde(default = "default_media_days")]
+    #[allow(dead_code)]
     pub media_days: u32,
 
-    /// Run cleanup at this hour (0-23)
+    /// Run cleanup at this hour (0-23, local time), or on the next run after
+    /// it if the agent was down
     #[serde(default = "default_cleanup_hour")]
     pub cleanup_hour: u32,
+
+    /// Maximum rows deleted per transaction
+    #[serde(default = "default_cleanup_batch_size")]
+    pub batch_size: usize,
+
+    /// Hard database size limit in MB (0 = unlimited). When exceeded, the
+    /// oldest events are evicted even if they have not been synced.
+    #[serde(default)]
+    pub max_db_mb: u64,
+}
+
+/// Hub (relay) mode configuration
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HubConfig {
+    /// Serve `/api/ingest/batch` to agents with a `relay` key
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Largest upload accepted, after decompression
+    #[serde(default = "default_hub_max_body_mb")]
+    pub max_body_mb: u64,
+}
+
+/// Ingest validation configuration
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ValidationConfig {
+    /// What to do with events that break the schema rules
+    #[serde(default)]
+    pub mode: ValidationMode,
+
+    /// Directory of per-type payload JSON Schemas (default: `{data_dir}/schemas`)
+    pub schemas_dir: Option<PathBuf>,
+}
+
+/// Validation mode
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValidationMode {
+    /// Reject invalid events
+    #[default]
+    Strict,
+    /// Store invalid events but log a warning
+    Warn,
+    /// Skip validation entirely
+    Off,
 }
 
 // Default value functions
@@ -99,6 +343,18 @@ fn default_listen() -> String {
     "0.0.0.0:8080".to_string()
 }
 
+fn default_shutdown_timeout() -> u64 {
+    8
+}
+
+fn default_ingest_queue_capacity() -> usize {
+    10_000
+}
+
+fn default_ingest_retry_after() -> u64 {
+    1
+}
+
 fn default_true() -> bool {
     true
 }
@@ -119,10 +375,59 @@ fn default_retry_delay() -> u64 {
     1000
 }
 
+fn default_source_limit() -> BucketConfig {
+    BucketConfig {
+        per_second: 50.0,
+        burst: 500,
+    }
+}
+
+fn default_key_limit() -> BucketConfig {
+    BucketConfig {
+        per_second: 100.0,
+        burst: 500,
+    }
+}
+
+fn default_ip_limit() -> BucketConfig {
+    BucketConfig {
+        per_second: 20.0,
+        burst: 100,
+    }
+}
+
+fn default_priority_min_severity() -> String {
+    "error".to_string()
+}
+
+fn default_priority_categories() -> Vec<String> {
+    vec!["security".to_string()]
+}
+
+fn default_max_batch_size() -> usize {
+    1000
+}
+
+fn default_max_reject_attempts() -> u32 {
+    5
+}
+
+fn default_hub_max_body_mb() -> u64 {
+    32
+}
+
 fn default_retention_days() -> u32 {
     30
 }
 
+fn default_short_days() -> u32 {
+    7
+}
+
+fn default_long_days() -> u32 {
+    365
+}
+
 fn default_media_days() -> u32 {
     7
 }
@@ -131,12 +436,55 @@ fn default_cleanup_hour() -> u32 {
     3
 }
 
+fn default_cleanup_batch_size() -> usize {
+    1000
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data_dir: default_data_dir(),
+            server: ServerConfig::default(),
+            sync: SyncConfig::default(),
+            retention: RetentionConfig::default(),
+            validation: ValidationConfig::default(),
+            hub: HubConfig::default(),
+        }
+    }
+}
+
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
             listen: default_listen(),
             cors_enabled: true,
             ui_path: None,
+            auth_required: false,
+            rate_limit: RateLimitConfig::default(),
+            ingest_queue: IngestQueueConfig::default(),
+            shutdown_timeout_seconds: default_shutdown_timeout(),
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            per_source: default_source_limit(),
+            per_key: default_key_limit(),
+            per_ip: default_ip_limit(),
+            trust_forwarded_for: false,
+        }
+    }
+}
+
+impl Default for IngestQueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: default_ingest_queue_capacity(),
+            when_full: QueueFullPolicy::default(),
+            retry_after_seconds: default_ingest_retry_after(),
         }
     }
 }
@@ -151,6 +499,24 @@ impl Default for SyncConfig {
             interval_seconds: default_sync_interval(),
             retry_max_attempts: default_max_retries(),
             retry_base_delay_ms: default_retry_delay(),
+            max_reject_attempts: default_max_reject_attempts(),
+            compression: SyncCompression::default(),
+            max_batch_size: default_max_batch_size(),
+            daily_budget_mb: 0.0,
+            monthly_budget_mb: 0.0,
+            windows: Vec::new(),
+            priority_min_severity: default_priority_min_severity(),
+            priority_categories: default_priority_categories(),
+            destinations: Vec::new(),
+        }
+    }
+}
+
+impl Default for HubConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_body_mb: default_hub_max_body_mb(),
         }
     }
 }
@@ -159,8 +525,12 @@ impl Default for RetentionConfig {
     fn default() -> Self {
         Self {
             events_days: default_retention_days(),
+            short_days: default_short_days(),
+            long_days: default_long_days(),
             media_days: default_media_days(),
             cleanup_hour: default_cleanup_hour(),
+            batch_size: default_cleanup_batch_size(),
+            max_db_mb: 0,
         }
     }
 }
@@ -175,4 +545,14 @@ impl Config {
         let config = builder.build()?;
         Ok(config.try_deserialize()?)
     }
+
+    /// SQLite database file
+    pub fn db_path(&self) -> PathBuf {
+        self.data_dir.join("events.db")
+    }
+
+    /// Attachment files; the only place bundles read media from
+    pub fn media_dir(&self) -> PathBuf {
+        self.data_dir.join("media")
+    }
 }
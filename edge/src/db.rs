@@ -1,16 +1,73 @@
 //! Database operations for EdgeKite
 
-use rusqlite::{params, Connection};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rusqlite::types::{Type, Value};
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Condvar, Mutex, RwLock};
+use tracing::{info, warn};
 
-use crate::error::Result;
-use crate::event::Event;
+use crate::aggregate::{Dimension, WebField};
+use crate::auth::ApiKey;
+use crate::destination::{Destination, DestinationFilter};
+use crate::error::{Error, Result};
+use crate::event::{severities_at_least, Correlation, Event, SEVERITIES};
+use crate::migrations::{self, Migration, MIGRATIONS};
+use crate::schedule::{severity_class, PriorityLane, LOWEST_CLASS, PRIORITY_CLASS};
 
-/// Database wrapper with thread-safe connection
+/// `config` table key holding the lane pending events were ranked with
+const LANE_KEY: &str = "sync.priority_lane";
+
+/// How newly stored events are queued in the outbox
+#[derive(Debug, Clone, Default)]
+struct Routing {
+    lane: PriorityLane,
+    /// Destination names and the events each one receives
+    destinations: Vec<(String, DestinationFilter)>,
+}
+
+/// Database handle: one writer thread plus a pool of read-only connections
+///
+/// SQLite allows a single writer at a time, so every write goes through a
+/// dedicated thread fed by a bounded queue. Inserts waiting in the queue are
+/// committed together in one transaction (group commit), which keeps
+/// ingestion fast under many concurrent requests. In exchange, every write
+/// pays a hand-off to that thread and back. Under load, an insert also waits
+/// for the group ahead of it (see ARCHITECTURE.md, "Storage Access"). Queries
+/// use their own read-only connections and, thanks to WAL, never wait for
+/// the writer.
+///
+/// All methods block; async callers should run them on the blocking pool.
 #[derive(Clone)]
 pub struct Database {
-    conn: Arc<Mutex<Connection>>,
+    writer: mpsc::SyncSender<Job>,
+    readers: Arc<ReadPool>,
+    routing: Arc<RwLock<Routing>>,
+    /// Last source served per `(destination, class)`; the next batch starts after it
+    share_cursors: Arc<Mutex<HashMap<(String, i64), String>>>,
+}
+
+/// Writes that can wait in the writer's queue before callers block
+const WRITE_QUEUE: usize = 1024;
+
+/// Most events committed in one group transaction
+const MAX_GROUP_EVENTS: usize = 4096;
+
+/// Read-only connections available to concurrent queries
+const READERS: usize = 4;
+
+/// Work for the writer thread
+enum Job {
+    /// Events to store; inserts queued together share one transaction
+    Insert {
+        events: Vec<Event>,
+        reply: mpsc::SyncSender<Result<Vec<InsertOutcome>>>,
+    },
+    /// Any other write, run on its own
+    Write(Box<dyn FnOnce(&mut Connection) + Send>),
 }
 
 impl Database {
@@ -23,7 +80,7 @@ impl Database {
 
         let conn = Connection::open(path)?;
 
-        // Enable WAL mode for better concurrency
+        // Enable WAL mode so readers and the writer do not block each other
         conn.execute_batch(
             "
             PRAGMA journal_mode = WAL;
@@ -33,424 +90,2283 @@ impl Database {
             ",
         )?;
 
+        let mut idle = Vec::with_capacity(READERS);
+        for _ in 0..READERS {
+            let reader = Connection::open_with_flags(
+                path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?;
+            reader.execute_batch(
+                "
+                PRAGMA cache_size = -2000;
+                PRAGMA busy_timeout = 5000;
+                ",
+            )?;
+            idle.push(reader);
+        }
+
+        let routing = Arc::new(RwLock::new(Routing::default()));
+        let (writer, jobs) = mpsc::sync_channel(WRITE_QUEUE);
+        std::thread::Builder::new()
+            .name("db-writer".to_string())
+            .spawn({
+                let routing = routing.clone();
+                move || run_writer(conn, jobs, routing)
+            })?;
+
         Ok(Self {
-            conn: Arc::new(Mutex::new(conn)),
+            writer,
+            readers: Arc::new(ReadPool {
+                idle: Mutex::new(idle),
+                returned: Condvar::new(),
+            }),
+            routing,
+            share_cursors: Arc::default(),
         })
     }
 
-    /// Run database migrations
-    pub fn migrate(&self) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
-
-        conn.execute_batch(
-            r#"
-            -- Core events table
-            CREATE TABLE IF NOT EXISTS events (
-                event_id TEXT PRIMARY KEY,
-                observed_at INTEGER NOT NULL,
-                received_at INTEGER NOT NULL,
-                source_type TEXT NOT NULL,
-                source_id TEXT NOT NULL,
-                source_seq INTEGER,
-                category TEXT NOT NULL,
-                type TEXT NOT NULL,
-                severity TEXT NOT NULL DEFAULT 'info',
-                correlation_id TEXT,
-                payload_json TEXT NOT NULL,
-                attachments_json TEXT,
-                pii INTEGER NOT NULL DEFAULT 0,
-                retention_class TEXT NOT NULL DEFAULT 'standard',
-                synced INTEGER NOT NULL DEFAULT 0
-            );
+    /// Run `f` on tokio's blocking pool, keeping database work off the async executor
+    pub async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Database) -> T + Send + 'static,
+    ) -> T {
+        let db = self.clone();
+        match tokio::task::spawn_blocking(move || f(&db)).await {
+            Ok(result) => result,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
 
-            -- Indexes for common queries
-            CREATE INDEX IF NOT EXISTS idx_events_observed ON events(observed_at);
-            CREATE INDEX IF NOT EXISTS idx_events_type_observed ON events(type, observed_at);
-            CREATE INDEX IF NOT EXISTS idx_events_source_observed ON events(source_id, observed_at);
-            CREATE INDEX IF NOT EXISTS idx_events_synced ON events(synced, observed_at);
-            CREATE INDEX IF NOT EXISTS idx_events_category ON events(category, observed_at);
-
-            -- Rollup table for Tier 1+ analytics
-            CREATE TABLE IF NOT EXISTS hourly_counts (
-                hour_bucket INTEGER NOT NULL,
-                source_id TEXT NOT NULL,
-                type TEXT NOT NULL,
-                count INTEGER NOT NULL,
-                PRIMARY KEY (hour_bucket, source_id, type)
-            );
+    /// Run `f` on the writer thread and wait for its result
+    fn write<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let (reply, result) = mpsc::sync_channel(1);
+        self.writer
+            .send(Job::Write(Box::new(move |conn| {
+                let _ = reply.send(f(conn));
+            })))
+            .map_err(|_| writer_stopped())?;
+        result.recv().map_err(|_| writer_stopped())?
+    }
 
-            -- Agent configuration
-            CREATE TABLE IF NOT EXISTS config (
-                key TEXT PRIMARY KEY,
-                value TEXT NOT NULL,
-                updated_at INTEGER NOT NULL
-            );
-            "#,
-        )?;
+    /// A read-only connection, waiting for one if all are in use
+    fn reader(&self) -> PooledReader<'_> {
+        let mut idle = self.readers.idle.lock().unwrap();
+        loop {
+            if let Some(conn) = idle.pop() {
+                return PooledReader {
+                    pool: &self.readers,
+                    conn: Some(conn),
+                };
+            }
+            idle = self.readers.returned.wait(idle).unwrap();
+        }
+    }
 
-        Ok(())
+    /// Current schema version (`PRAGMA user_version`)
+    pub fn schema_version(&self) -> Result<u32> {
+        let conn = self.reader();
+        let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        Ok(version)
     }
 
-    /// Insert a single event
-    pub fn insert_event(&self, event: &Event) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
+    /// Migrations not yet applied to this database
+    ///
+    /// Fails if the database was written by a newer version of EdgeKite.
+    pub fn pending_migrations(&self) -> Result<Vec<&'static Migration>> {
+        let current = self.schema_version()?;
+        let latest = migrations::latest_version();
+        if current > latest {
+            return Err(Error::Migration(format!(
+                "database schema version {} is newer than this build supports ({}); refusing to start",
+                current, latest
+            )));
+        }
 
-        let payload_json = serde_json::to_string(&event.event.data)?;
-        let attachments_json = event
-            .attachments
-            .as_ref()
-            .map(|a| serde_json::to_string(a))
-            .transpose()?;
+        Ok(MIGRATIONS.iter().filter(|m| m.version > current).collect())
+    }
 
-        let pii = event.privacy.as_ref().map(|p| p.pii).unwrap_or(false);
-        let retention_class = event
-            .privacy
-            .as_ref()
-            .map(|p| p.retention_class.as_str())
-            .unwrap_or("standard");
-        let synced = event.sync.as_ref().map(|s| s.synced).unwrap_or(false);
-        let source_seq = event.sync.as_ref().and_then(|s| s.source_seq);
-        let correlation_id = event.correlation.as_ref().and_then(|c| c.correlation_id.as_ref());
+    /// Run pending database migrations, each in its own transaction
+    pub fn migrate(&self) -> Result<()> {
+        let pending = self.pending_migrations()?;
 
-        conn.execute(
-            r#"
-            INSERT INTO events (
-                event_id, observed_at, received_at,
-                source_type, source_id, source_seq,
-                category, type, severity, correlation_id,
-                payload_json, attachments_json,
-                pii, retention_class, synced
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)
-            ON CONFLICT(event_id) DO NOTHING
-            "#,
-            params![
-                event.event_id,
-                event.observed_at.timestamp_millis(),
-                event.received_at.timestamp_millis(),
-                event.source.source_type,
-                event.source.id,
-                source_seq,
-                event.event.category,
-                event.event.event_type,
-                event.event.severity,
-                correlation_id,
-                payload_json,
-                attachments_json,
-                pii as i32,
-                retention_class,
-                synced as i32,
-            ],
-        )?;
+        self.write(move |conn| {
+            for migration in pending {
+                info!(
+                    "Applying migration {}: {}",
+                    migration.version, migration.description
+                );
+                let tx = conn.transaction()?;
+                tx.execute_batch(migration.sql)?;
+                tx.pragma_update(None, "user_version", migration.version)?;
+                tx.commit()?;
+            }
+            Ok(())
+        })
+    }
 
-        Ok(())
+    /// Insert a single event, returning false if it was a duplicate
+    ///
+    /// New events are queued for every destination whose filter they match,
+    /// unless they arrive already marked as synced.
+    #[cfg(test)]
+    pub fn insert_event(&self, event: &Event) -> Result<bool> {
+        match self.insert_events(std::slice::from_ref(event))?.pop() {
+            Some(InsertOutcome::Failed(e)) => Err(Error::Storage(e)),
+            outcome => Ok(matches!(outcome, Some(InsertOutcome::Inserted(_)))),
+        }
     }
 
-    /// Insert multiple events in a transaction
-    pub fn insert_events(&self, events: &[Event]) -> Result<usize> {
-        let mut conn = self.conn.lock().unwrap();
-        let tx = conn.transaction()?;
-        let mut count = 0;
+    /// Insert multiple events, reporting each event's outcome
+    ///
+    /// The events are committed together with any other inserts waiting for
+    /// the writer. Every event is inserted under its own savepoint, so a
+    /// failing row is rolled back on its own without affecting the rest.
+    pub fn insert_events(&self, events: &[Event]) -> Result<Vec<InsertOutcome>> {
+        let (reply, result) = mpsc::sync_channel(1);
+        self.writer
+            .send(Job::Insert {
+                events: events.to_vec(),
+                reply,
+            })
+            .map_err(|_| writer_stopped())?;
+        result.recv().map_err(|_| writer_stopped())?
+    }
 
-        for event in events {
-            let payload_json = serde_json::to_string(&event.event.data)?;
-            let attachments_json = event
-                .attachments
-                .as_ref()
-                .map(|a| serde_json::to_string(a))
-                .transpose()?;
+    /// Next outbox batch for a destination, skipping dead letters
+    ///
+    /// Lower outbox classes drain first (see `PriorityLane::class`). Within a
+    /// class sources take turns, oldest events first, so one chatty source
+    /// cannot starve the others; turns a source cannot fill go to the rest.
+    pub fn get_unsynced_events(&self, destination: &str, limit: usize) -> Result<Vec<Event>> {
+        self.outbox_batch(destination, LOWEST_CLASS, limit)
+    }
 
-            let pii = event.privacy.as_ref().map(|p| p.pii).unwrap_or(false);
-            let retention_class = event
-                .privacy
-                .as_ref()
-                .map(|p| p.retention_class.as_str())
-                .unwrap_or("standard");
-            let synced = event.sync.as_ref().map(|s| s.synced).unwrap_or(false);
-            let source_seq = event.sync.as_ref().and_then(|s| s.source_seq);
-            let correlation_id = event.correlation.as_ref().and_then(|c| c.correlation_id.as_ref());
+    /// Next outbox batch for a destination from the priority lane only
+    pub fn get_unsynced_priority_events(
+        &self,
+        destination: &str,
+        limit: usize,
+    ) -> Result<Vec<Event>> {
+        self.outbox_batch(destination, PRIORITY_CLASS, limit)
+    }
 
-            let rows = tx.execute(
+    fn outbox_batch(&self, destination: &str, max_class: i64, limit: usize) -> Result<Vec<Event>> {
+        let conn = self.reader();
+        let mut batch = Vec::with_capacity(limit);
+        for class in PRIORITY_CLASS..=max_class {
+            if batch.len() >= limit {
+                break;
+            }
+            let key = (destination.to_string(), class);
+            let after = self.share_cursors.lock().unwrap().get(&key).cloned();
+            let share = fair_share(&conn, destination, class, after, limit - batch.len())?;
+            if let Some(last) = share.last() {
+                self.share_cursors
+                    .lock()
+                    .unwrap()
+                    .insert(key, last.source.id.clone());
+            }
+            batch.extend(share);
+        }
+        Ok(batch)
+    }
+
+    /// Set the priority lane used to rank the outbox
+    ///
+    /// Pending events are re-ranked when the lane differs from the one they
+    /// were ranked with, so config changes also apply to the backlog.
+    pub fn set_priority_lane(&self, lane: PriorityLane) -> Result<()> {
+        let fingerprint = serde_json::to_string(&lane)?;
+        if self.get_setting(LANE_KEY)?.as_deref() != Some(fingerprint.as_str()) {
+            let (class, values) = class_sql(&lane);
+            let query = format!(
                 r#"
-                INSERT INTO events (
-                    event_id, observed_at, received_at,
-                    source_type, source_id, source_seq,
-                    category, type, severity, correlation_id,
-                    payload_json, attachments_json,
-                    pii, retention_class, synced
-                ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)
-                ON CONFLICT(event_id) DO NOTHING
+                UPDATE outbox SET sync_priority = COALESCE(
+                    (SELECT {} FROM events WHERE events.event_id = outbox.event_id),
+                    {}
+                )
+                WHERE dead_lettered_at IS NULL
                 "#,
-                params![
-                    event.event_id,
-                    event.observed_at.timestamp_millis(),
-                    event.received_at.timestamp_millis(),
-                    event.source.source_type,
-                    event.source.id,
-                    source_seq,
-                    event.event.category,
-                    event.event.event_type,
-                    event.event.severity,
-                    correlation_id,
-                    payload_json,
-                    attachments_json,
-                    pii as i32,
-                    retention_class,
-                    synced as i32,
-                ],
-            )?;
-            count += rows;
+                class, LOWEST_CLASS
+            );
+            let ranked =
+                self.write(move |conn| Ok(conn.execute(&query, params_from_iter(values))?))?;
+            if ranked > 0 {
+                info!(
+                    "Re-ranked {} pending events for the new priority lane",
+                    ranked
+                );
+            }
+            self.set_setting(LANE_KEY, &fingerprint)?;
         }
+        self.routing.write().unwrap().lane = lane;
+        Ok(())
+    }
 
-        tx.commit()?;
-        Ok(count)
+    /// Set the destinations newly stored events are queued for
+    ///
+    /// Queues of destinations that are no longer configured are kept, so a
+    /// renamed or mistyped destination does not lose its backlog; restoring
+    /// the name resumes it, and `purge_outbox` drops it for good.
+    pub fn set_destinations(&self, destinations: &[Destination]) -> Result<()> {
+        let names: Vec<Value> = destinations
+            .iter()
+            .map(|d| Value::Text(d.name.clone()))
+            .collect();
+        let placeholders: Vec<&str> = names.iter().map(|_| "?").collect();
+        let query = format!(
+            "SELECT destination, COUNT(*) FROM outbox WHERE destination NOT IN ({}) GROUP BY destination",
+            placeholders.join(",")
+        );
+        self.write(move |conn| {
+            let orphaned: Vec<(String, i64)> = conn
+                .prepare(&query)?
+                .query_map(params_from_iter(names), |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            for (destination, count) in orphaned {
+                warn!(
+                    "Destination '{}' is no longer configured; keeping its {} queued events \
+                     (restore it, or drop them with `edge-kite outbox purge {}`)",
+                    destination, count, destination
+                );
+            }
+            Ok(())
+        })?;
+
+        self.routing.write().unwrap().destinations = destinations
+            .iter()
+            .map(|d| (d.name.clone(), d.filter.clone()))
+            .collect();
+        Ok(())
     }
 
-    /// Get unsynced events (for sync worker)
-    pub fn get_unsynced_events(&self, limit: usize) -> Result<Vec<Event>> {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare(
+    /// Drop a destination's queue, dead letters included; returns the rows removed
+    pub fn purge_outbox(&self, destination: &str) -> Result<usize> {
+        let destination = destination.to_string();
+        self.write(move |conn| {
+            Ok(conn.execute("DELETE FROM outbox WHERE destination = ?", [&destination])?)
+        })
+    }
+
+    /// Query events newest-first with filters and keyset pagination
+    ///
+    /// Pages are ordered by `(observed_at, event_id)` descending; pass the
+    /// returned `next_cursor` back in to continue after the last event.
+    pub fn query_events(
+        &self,
+        filter: &EventFilter,
+        cursor: Option<&Cursor>,
+        limit: usize,
+    ) -> Result<EventPage> {
+        let (mut clauses, mut values) = filter.sql_clauses();
+        if let Some(cursor) = cursor {
+            clauses.push("(observed_at, event_id) < (?, ?)".to_string());
+            values.push(Value::Integer(cursor.observed_at));
+            values.push(Value::Text(cursor.event_id.clone()));
+        }
+
+        let where_clause = where_sql(&clauses);
+
+        // Fetch one extra row to learn whether another page exists
+        values.push(Value::Integer(limit as i64 + 1));
+
+        let conn = self.reader();
+        let mut stmt = conn.prepare(&format!(
             r#"
-            SELECT event_id, observed_at, received_at,
-                   source_type, source_id, source_seq,
-                   category, type, severity, correlation_id,
-                   payload_json, attachments_json,
-                   pii, retention_class
+            SELECT {EVENT_COLUMNS}
             FROM events
-            WHERE synced = 0
-            ORDER BY observed_at ASC
+            {where_clause}
+            ORDER BY observed_at DESC, event_id DESC
             LIMIT ?
-            "#,
-        )?;
+            "#
+        ))?;
 
-        let events = stmt
-            .query_map([limit], |row| {
-                Ok(EventRow {
-                    event_id: row.get(0)?,
-                    observed_at: row.get(1)?,
-                    received_at: row.get(2)?,
-                    source_type: row.get(3)?,
-                    source_id: row.get(4)?,
-                    source_seq: row.get(5)?,
-                    category: row.get(6)?,
-                    event_type: row.get(7)?,
-                    severity: row.get(8)?,
-                    correlation_id: row.get(9)?,
-                    payload_json: row.get(10)?,
-                    attachments_json: row.get(11)?,
-                    pii: row.get(12)?,
-                    retention_class: row.get(13)?,
-                })
+        let mut events = stmt
+            .query_map(params_from_iter(values), EventRow::from_row)?
+            .collect::<std::result::Result<Vec<_>, _>>()?
+            .into_iter()
+            .map(EventRow::into_event)
+            .collect::<Result<Vec<_>>>()?;
+
+        let next_cursor = if events.len() > limit {
+            events.truncate(limit);
+            events.last().map(|e| Cursor {
+                observed_at: e.observed_at.timestamp_millis(),
+                event_id: e.event_id.clone(),
+            })
+        } else {
+            None
+        };
+
+        Ok(EventPage {
+            events,
+            next_cursor,
+        })
+    }
+
+    /// Highest stream sequence committed so far
+    pub fn stream_head(&self) -> Result<i64> {
+        let conn = self.reader();
+        let head = conn
+            .query_row(
+                "SELECT CAST(value AS INTEGER) FROM config WHERE key = 'stream_seq'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(head.unwrap_or(0))
+    }
+
+    /// Stream sequence of a stored event, if it exists
+    pub fn stream_seq_of(&self, event_id: &str) -> Result<Option<i64>> {
+        let conn = self.reader();
+        let seq = conn
+            .query_row(
+                "SELECT stream_seq FROM events WHERE event_id = ?",
+                [event_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(seq.flatten())
+    }
+
+    /// Events with a stream sequence in `(after, until]`, oldest first, each
+    /// paired with its sequence
+    ///
+    /// Used to replay what a live stream subscriber missed. Sequences are
+    /// assigned at commit, so an event that committed late still sorts after
+    /// everything a subscriber could have seen before it.
+    pub fn events_in_stream(
+        &self,
+        after: i64,
+        until: i64,
+        filter: &EventFilter,
+        limit: usize,
+    ) -> Result<Vec<(i64, Event)>> {
+        let (mut clauses, mut values) = filter.sql_clauses();
+        clauses.push("stream_seq > ?".to_string());
+        values.push(Value::Integer(after));
+        clauses.push("stream_seq <= ?".to_string());
+        values.push(Value::Integer(until));
+        values.push(Value::Integer(limit as i64));
+
+        let where_clause = where_sql(&clauses);
+        let conn = self.reader();
+        let mut stmt = conn.prepare(&format!(
+            r#"
+            SELECT {EVENT_COLUMNS}, stream_seq
+            FROM events
+            {where_clause}
+            ORDER BY stream_seq ASC
+            LIMIT ?
+            "#
+        ))?;
+
+        let rows = stmt
+            .query_map(params_from_iter(values), |row| {
+                Ok((row.get::<_, i64>(21)?, EventRow::from_row(row)?))
             })?
-            .filter_map(|r| r.ok())
-            .filter_map(|row| row.into_event().ok())
-            .collect();
+            .collect::<std::result::Result<Vec<_>, _>>()?;
 
-        Ok(events)
+        rows.into_iter()
+            .map(|(seq, row)| Ok((seq, row.into_event()?)))
+            .collect()
     }
 
-    /// Mark events as synced
-    pub fn mark_synced(&self, event_ids: &[String]) -> Result<usize> {
+    /// Remove events a destination acknowledged from its queue
+    pub fn mark_synced(&self, destination: &str, event_ids: &[String]) -> Result<usize> {
         if event_ids.is_empty() {
             return Ok(0);
         }
 
-        let conn = self.conn.lock().unwrap();
         let placeholders: Vec<&str> = event_ids.iter().map(|_| "?").collect();
         let query = format!(
-            "UPDATE events SET synced = 1 WHERE event_id IN ({})",
+            "DELETE FROM outbox WHERE destination = ? AND event_id IN ({})",
             placeholders.join(",")
         );
 
-        let mut stmt = conn.prepare(&query)?;
-        let params: Vec<&dyn rusqlite::ToSql> = event_ids.iter().map(|s| s as &dyn rusqlite::ToSql).collect();
-        let count = stmt.execute(params.as_slice())?;
+        let mut values = vec![Value::Text(destination.to_string())];
+        values.extend(event_ids.iter().map(|id| Value::Text(id.clone())));
+        self.write(move |conn| Ok(conn.execute(&query, params_from_iter(values))?))
+    }
 
-        Ok(count)
+    /// Queued events for a bundle export, in `(observed_at, event_id)` order
+    ///
+    /// Events already in an earlier bundle are skipped unless
+    /// `include_exported` is set; `after` continues from the previous page.
+    pub fn exportable_events(
+        &self,
+        destination: &str,
+        include_exported: bool,
+        after: Option<&Cursor>,
+        limit: usize,
+    ) -> Result<Vec<Event>> {
+        let mut clauses = vec![
+            "destination = ?".to_string(),
+            "dead_lettered_at IS NULL".to_string(),
+        ];
+        let mut values = vec![Value::Text(destination.to_string())];
+        if !include_exported {
+            clauses.push("exported_at IS NULL".to_string());
+        }
+        if let Some(after) = after {
+            clauses.push("(observed_at, event_id) > (?, ?)".to_string());
+            values.push(Value::Integer(after.observed_at));
+            values.push(Value::Text(after.event_id.clone()));
+        }
+        values.push(Value::Integer(limit as i64));
+
+        let conn = self.reader();
+        let mut stmt = conn.prepare(&format!(
+            r#"
+            SELECT {EVENT_COLUMNS}
+            FROM events
+            WHERE event_id IN (
+                SELECT event_id FROM outbox
+                {}
+                ORDER BY observed_at, event_id
+                LIMIT ?
+            )
+            ORDER BY observed_at, event_id
+            "#,
+            where_sql(&clauses)
+        ))?;
+        let events = stmt
+            .query_map(params_from_iter(values), EventRow::from_row)?
+            .collect::<std::result::Result<Vec<_>, _>>()?
+            .into_iter()
+            .map(EventRow::into_event)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(events)
     }
 
-    /// Get event count
-    pub fn event_count(&self) -> Result<i64> {
-        let conn = self.conn.lock().unwrap();
-        let count: i64 = conn.query_row("SELECT COUNT(*) FROM events", [], |row| row.get(0))?;
-        Ok(count)
+    /// Record that events left in bundle `bundle_id`
+    ///
+    /// They stay queued until a receipt confirms the import.
+    pub fn mark_exported(
+        &self,
+        destination: &str,
+        bundle_id: &str,
+        event_ids: &[String],
+    ) -> Result<usize> {
+        let (destination, bundle_id) = (destination.to_string(), bundle_id.to_string());
+        let event_ids = event_ids.to_vec();
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            let now = Utc::now().timestamp_millis();
+            let mut marked = 0;
+            {
+                let mut stmt = tx.prepare(
+                    "UPDATE outbox SET exported_at = ?1, bundle_id = ?2 WHERE destination = ?3 AND event_id = ?4",
+                )?;
+                for event_id in &event_ids {
+                    marked += stmt.execute(params![now, bundle_id, destination, event_id])?;
+                }
+            }
+            tx.commit()?;
+            Ok(marked)
+        })
     }
 
-    /// Get pending sync count
-    pub fn pending_sync_count(&self) -> Result<i64> {
-        let conn = self.conn.lock().unwrap();
-        let count: i64 = conn.query_row("SELECT COUNT(*) FROM events WHERE synced = 0", [], |row| row.get(0))?;
-        Ok(count)
+    /// Record a destination's rejections: `(event_id, reason)` pairs
+    ///
+    /// Each rejection counts as a sync attempt; an event rejected
+    /// `max_attempts` times becomes a dead letter for that destination and is
+    /// no longer sent there. Otherwise it is retried, and goes into the next
+    /// bundle export. Returns how many events were dead-lettered by this call.
+    pub fn record_sync_rejections(
+        &self,
+        destination: &str,
+        rejections: &[(String, String)],
+        max_attempts: u32,
+    ) -> Result<usize> {
+        let destination = destination.to_string();
+        let rejections = rejections.to_vec();
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            let now = Utc::now().timestamp_millis();
+            let mut dead_lettered = 0;
+
+            {
+                let mut stmt = tx.prepare(
+                    r#"
+                    UPDATE outbox
+                    SET attempts = attempts + 1,
+                        error = ?1,
+                        dead_lettered_at = CASE WHEN attempts + 1 >= ?2 THEN ?3 END,
+                        exported_at = NULL,
+                        bundle_id = NULL
+                    WHERE destination = ?4 AND event_id = ?5 AND dead_lettered_at IS NULL
+                    RETURNING dead_lettered_at IS NOT NULL
+                    "#,
+                )?;
+                for (event_id, reason) in rejections {
+                    let dead: Option<bool> = stmt
+                        .query_row(
+                            params![reason, max_attempts, now, destination, event_id],
+                            |row| row.get(0),
+                        )
+                        .optional()?;
+                    if dead == Some(true) {
+                        dead_lettered += 1;
+                    }
+                }
+            }
+
+            tx.commit()?;
+            Ok(dead_lettered)
+        })
     }
-}
 
-/// Internal row representation
-struct EventRow {
-    event_id: String,
-    observed_at: i64,
-    received_at: i64,
-    source_type: String,
-    source_id: String,
-    source_seq: Option<i64>,
-    category: String,
-    event_type: String,
-    severity: String,
-    correlation_id: Option<String>,
-    payload_json: String,
-    attachments_json: Option<String>,
-    pii: i32,
-    retention_class: String,
-}
+    /// Dead-lettered events, most recently observed first, with keyset pagination
+    ///
+    /// Each event lists the destinations that gave up on it; `destination`
+    /// limits the page to one of them.
+    pub fn list_dead_letters(
+        &self,
+        destination: Option<&str>,
+        cursor: Option<&Cursor>,
+        limit: usize,
+    ) -> Result<DeadLetterPage> {
+        let only = if destination.is_some() {
+            " AND destination = ?"
+        } else {
+            ""
+        };
+        let mut clauses = vec![format!(
+            "event_id IN (SELECT event_id FROM outbox WHERE dead_lettered_at IS NOT NULL{})",
+            only
+        )];
+        let mut values: Vec<Value> = destination
+            .map(|d| Value::Text(d.to_string()))
+            .into_iter()
+            .collect();
+        if let Some(cursor) = cursor {
+            clauses.push("(observed_at, event_id) < (?, ?)".to_string());
+            values.push(Value::Integer(cursor.observed_at));
+            values.push(Value::Text(cursor.event_id.clone()));
+        }
+        values.push(Value::Integer(limit as i64 + 1));
 
-impl EventRow {
-    fn into_event(self) -> Result<Event> {
-        use chrono::TimeZone;
+        let conn = self.reader();
+        let mut stmt = conn.prepare(&format!(
+            r#"
+            SELECT {EVENT_COLUMNS}
+            FROM events
+            {}
+            ORDER BY observed_at DESC, event_id DESC
+            LIMIT ?
+            "#,
+            where_sql(&clauses)
+        ))?;
+        let mut events = stmt
+            .query_map(params_from_iter(values), EventRow::from_row)?
+            .collect::<std::result::Result<Vec<_>, _>>()?
+            .into_iter()
+            .map(EventRow::into_event)
+            .collect::<Result<Vec<_>>>()?;
 
-        let observed_at = Utc.timestamp_millis_opt(self.observed_at).single().unwrap_or_else(Utc::now);
-        let received_at = Utc.timestamp_millis_opt(self.received_at).single().unwrap_or_else(Utc::now);
+        let next_cursor = if events.len() > limit {
+            events.truncate(limit);
+            events.last().map(|e| Cursor {
+                observed_at: e.observed_at.timestamp_millis(),
+                event_id: e.event_id.clone(),
+            })
+        } else {
+            None
+        };
+        if events.is_empty() {
+            return Ok(DeadLetterPage {
+                dead_letters: Vec::new(),
+                next_cursor,
+            });
+        }
 
-        let data: serde_json::Value = serde_json::from_str(&self.payload_json)?;
-        let attachments: Option<Vec<crate::event::Attachment>> = self
-            .attachments_json
-            .as_ref()
-            .map(|s| serde_json::from_str(s))
-            .transpose()?;
+        let placeholders: Vec<&str> = events.iter().map(|_| "?").collect();
+        let mut values: Vec<Value> = events
+            .iter()
+            .map(|e| Value::Text(e.event_id.clone()))
+            .collect();
+        values.extend(destination.map(|d| Value::Text(d.to_string())));
+        let mut stmt = conn.prepare(&format!(
+            r#"
+            SELECT event_id, destination, attempts, error, dead_lettered_at
+            FROM outbox
+            WHERE dead_lettered_at IS NOT NULL AND event_id IN ({}){}
+            ORDER BY destination
+            "#,
+            placeholders.join(","),
+            only
+        ))?;
+        let mut failures: HashMap<String, Vec<FailedDelivery>> = HashMap::new();
+        for row in stmt.query_map(params_from_iter(values), |row| {
+            let dead_lettered_at: i64 = row.get(4)?;
+            Ok((
+                row.get::<_, String>(0)?,
+                FailedDelivery {
+                    destination: row.get(1)?,
+                    attempts: row.get(2)?,
+                    error: row.get(3)?,
+                    dead_lettered_at: DateTime::from_timestamp_millis(dead_lettered_at)
+                        .unwrap_or_default(),
+                },
+            ))
+        })? {
+            let (event_id, failure) = row?;
+            failures.entry(event_id).or_default().push(failure);
+        }
 
-        Ok(Event {
-            event_id: self.event_id,
-            observed_at,
-            received_at,
-            source: crate::event::Source {
-                source_type: self.source_type,
-                id: self.source_id,
-                version: None,
-                metadata: None,
-            },
-            event: crate::event::EventDetails {
-                category: self.category,
-                event_type: self.event_type,
-                severity: self.severity,
-                schema_version: None,
-                data,
-            },
-            correlation: self.correlation_id.map(|id| crate::event::Correlation {
-                correlation_id: Some(id),
-                session_id: None,
-                incident_id: None,
-            }),
-            attachments,
-            privacy: Some(crate::event::Privacy {
-                pii: self.pii != 0,
-                retention_class: self.retention_class,
-            }),
-            sync: Some(crate::event::SyncStatus {
-                synced: false,
-                source_seq: self.source_seq,
-            }),
+        let dead_letters = events
+            .into_iter()
+            .map(|event| DeadLetter {
+                destinations: failures.remove(&event.event_id).unwrap_or_default(),
+                event,
+            })
+            .collect();
+
+        Ok(DeadLetterPage {
+            dead_letters,
+            next_cursor,
         })
     }
-}
 
-use chrono::Utc;
+    /// Put dead letters back in their destination's queue with a fresh attempt count
+    ///
+    /// `destination` and `event_ids` narrow what is requeued; `None` matches
+    /// every destination or event. Returns how many deliveries were requeued.
+    pub fn requeue_dead_letters(
+        &self,
+        destination: Option<&str>,
+        event_ids: Option<&[String]>,
+    ) -> Result<usize> {
+        let mut clauses = vec!["dead_lettered_at IS NOT NULL".to_string()];
+        let mut values = Vec::new();
+        if let Some(destination) = destination {
+            clauses.push("destination = ?".to_string());
+            values.push(Value::Text(destination.to_string()));
+        }
+        if let Some(ids) = event_ids {
+            if ids.is_empty() {
+                return Ok(0);
+            }
+            let placeholders: Vec<&str> = ids.iter().map(|_| "?").collect();
+            clauses.push(format!("event_id IN ({})", placeholders.join(",")));
+            values.extend(ids.iter().map(|id| Value::Text(id.clone())));
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::tempdir;
+        self.write(move |conn| {
+            let requeued = conn.execute(
+                &format!(
+                    "UPDATE outbox SET attempts = 0, error = NULL, dead_lettered_at = NULL {}",
+                    where_sql(&clauses)
+                ),
+                params_from_iter(values),
+            )?;
+            Ok(requeued)
+        })
+    }
 
-    fn make_test_event(event_type: &str) -> Event {
-        Event {
-            event_id: uuid::Uuid::new_v4().to_string(),
-            observed_at: Utc::now(),
-            received_at: Utc::now(),
-            source: crate::event::Source {
-                source_type: "browser".to_string(),
-                id: "test-session".to_string(),
-                version: Some("1.0".to_string()),
-                metadata: None,
-            },
-            event: crate::event::EventDetails {
-                category: "web".to_string(),
-                event_type: event_type.to_string(),
-                severity: "info".to_string(),
-                schema_version: None,
-                data: serde_json::json!({"path": "/test"}),
-            },
-            correlation: None,
-            attachments: None,
-            privacy: None,
-            sync: None,
+    /// Delete up to `limit` events of a retention class observed before `cutoff_ms`
+    ///
+    /// `classes` lists the `retention_class` values to match; an empty list
+    /// matches every class not in `exclude`. Events still queued for any
+    /// destination are kept unless `include_unsynced` is set; dead letters are
+    /// not waiting and expire normally.
+    pub fn delete_expired_events(
+        &self,
+        classes: &[&str],
+        exclude: &[&str],
+        cutoff_ms: i64,
+        include_unsynced: bool,
+        limit: usize,
+    ) -> Result<usize> {
+        let mut clauses = vec!["observed_at < ?".to_string()];
+        let mut values = vec![Value::Integer(cutoff_ms)];
+
+        for (op, list) in [("IN", classes), ("NOT IN", exclude)] {
+            if !list.is_empty() {
+                let placeholders: Vec<&str> = list.iter().map(|_| "?").collect();
+                clauses.push(format!(
+                    "retention_class {} ({})",
+                    op,
+                    placeholders.join(",")
+                ));
+                values.extend(list.iter().map(|c| Value::Text(c.to_string())));
+            }
         }
-    }
+        if !include_unsynced {
+            clauses.push(
+                "NOT EXISTS (SELECT 1 FROM outbox WHERE outbox.event_id = events.event_id AND outbox.dead_lettered_at IS NULL)"
+                    .to_string(),
+            );
+        }
+        values.push(Value::Integer(limit as i64));
 
-    #[test]
-    fn test_open_and_migrate() {
-        let dir = tempdir().unwrap();
-        let db_path = dir.path().join("test.db");
-        let db = Database::open(&db_path).unwrap();
-        db.migrate().unwrap();
-        assert_eq!(db.event_count().unwrap(), 0);
+        self.write(move |conn| {
+            let deleted = conn.execute(
+                &format!(
+                    "DELETE FROM events WHERE rowid IN (SELECT rowid FROM events {} LIMIT ?)",
+                    where_sql(&clauses)
+                ),
+                params_from_iter(values),
+            )?;
+
+            Ok(deleted)
+        })
     }
 
-    #[test]
-    fn test_insert_and_count() {
-        let dir = tempdir().unwrap();
-        let db_path = dir.path().join("test.db");
-        let db = Database::open(&db_path).unwrap();
-        db.migrate().unwrap();
+    /// Delete the `limit` oldest events regardless of class or sync state
+    pub fn evict_oldest_events(&self, limit: usize) -> Result<usize> {
+        self.write(move |conn| {
+            let deleted = conn.execute(
+                "DELETE FROM events WHERE rowid IN (SELECT rowid FROM events ORDER BY observed_at ASC LIMIT ?)",
+                [limit as i64],
+            )?;
+            Ok(deleted)
+        })
+    }
 
-        let event = make_test_event("page_view");
-        db.insert_event(&event).unwrap();
+    /// Bytes of database pages in use (excludes free pages awaiting reuse)
+    pub fn used_bytes(&self) -> Result<u64> {
+        let conn = self.reader();
+        let page_size: i64 = conn.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+        let page_count: i64 = conn.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+        let freelist: i64 = conn.query_row("PRAGMA freelist_count", [], |row| row.get(0))?;
+        Ok(((page_count - freelist) * page_size).max(0) as u64)
+    }
 
-        assert_eq!(db.event_count().unwrap(), 1);
-        assert_eq!(db.pending_sync_count().unwrap(), 1);
+    /// Copy the WAL into the database file and truncate it
+    ///
+    /// Returns false if a reader or writer kept the checkpoint from finishing.
+    pub fn checkpoint(&self) -> Result<bool> {
+        self.write(move |conn| {
+            let busy: i64 =
+                conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| row.get(0))?;
+            Ok(busy == 0)
+        })
     }
 
-    #[test]
-    fn test_insert_batch() {
-        let dir = tempdir().unwrap();
-        let db_path = dir.path().join("test.db");
-        let db = Database::open(&db_path).unwrap();
-        db.migrate().unwrap();
+    /// Store a new API key record
+    pub fn insert_api_key(&self, key: &ApiKey, key_hash: &str) -> Result<()> {
+        let (key, key_hash) = (key.clone(), key_hash.to_string());
+        self.write(move |conn| {
+            let origins_json = key
+                .allowed_origins
+                .as_ref()
+                .map(serde_json::to_string)
+                .transpose()?;
+            conn.execute(
+                r#"
+                INSERT INTO api_keys (id, name, key_hash, scope, public, allowed_origins_json, created_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                "#,
+                params![
+                    key.id,
+                    key.name,
+                    key_hash,
+                    key.scope.as_str(),
+                    key.public as i32,
+                    origins_json,
+                    key.created_at.timestamp_millis(),
+                ],
+            )?;
+            Ok(())
+        })
+    }
 
-        let events: Vec<Event> = (0..10)
-            .map(|i| make_test_event(&format!("event_{}", i)))
-            .collect();
+    /// Look up an active (not revoked) API key by its hash
+    pub fn find_api_key(&self, key_hash: &str) -> Result<Option<ApiKey>> {
+        let conn = self.reader();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {API_KEY_COLUMNS} FROM api_keys WHERE key_hash = ? AND revoked_at IS NULL"
+        ))?;
+        let mut rows = stmt.query_map([key_hash], api_key_from_row)?;
+        Ok(rows.next().transpose()?)
+    }
 
-        let count = db.insert_events(&events).unwrap();
-        assert_eq!(count, 10);
-        assert_eq!(db.event_count().unwrap(), 10);
+    /// All API keys, including revoked ones
+    pub fn list_api_keys(&self) -> Result<Vec<(ApiKey, Option<i64>)>> {
+        let conn = self.reader();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {API_KEY_COLUMNS}, revoked_at FROM api_keys ORDER BY created_at"
+        ))?;
+        let keys = stmt
+            .query_map([], |row| Ok((api_key_from_row(row)?, row.get(6)?)))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(keys)
     }
 
-    #[test]
-    fn test_get_unsynced_and_mark_synced() {
-        let dir = tempdir().unwrap();
-        let db_path = dir.path().join("test.db");
-        let db = Database::open(&db_path).unwrap();
-        db.migrate().unwrap();
+    /// Revoke an API key by id, returning false if no active key matched
+    pub fn revoke_api_key(&self, id: &str) -> Result<bool> {
+        let id = id.to_string();
+        self.write(move |conn| {
+            let rows = conn.execute(
+                "UPDATE api_keys SET revoked_at = ? WHERE id = ? AND revoked_at IS NULL",
+                params![Utc::now().timestamp_millis(), id],
+            )?;
+            Ok(rows > 0)
+        })
+    }
 
-        // Insert events
-        let events: Vec<Event> = (0..5)
-            .map(|i| make_test_event(&format!("event_{}", i)))
-            .collect();
-        db.insert_events(&events).unwrap();
+    /// Read an agent setting from the `config` table
+    pub fn get_setting(&self, key: &str) -> Result<Option<String>> {
+        let conn = self.reader();
+        let value = conn
+            .query_row("SELECT value FROM config WHERE key = ?", [key], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        Ok(value)
+    }
+
+    /// Store an agent setting in the `config` table
+    pub fn set_setting(&self, key: &str, value: &str) -> Result<()> {
+        let (key, value) = (key.to_string(), value.to_string());
+        self.write(move |conn| {
+            conn.execute(
+                r#"
+                INSERT INTO config (key, value, updated_at) VALUES (?1, ?2, ?3)
+                ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at
+                "#,
+                params![key, value, Utc::now().timestamp_millis()],
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Events per `step`-wide slot of `[since, until)` and per `group_by` key
+    ///
+    /// Times are Unix milliseconds; `since` and `until` must be multiples of
+    /// `step`. Whole days are read from `daily_counts` and whole hours from
+    /// `hourly_counts`; finer steps fall back to the events table. Only the
+    /// filter's dimensions apply, as the rollups keep nothing else. Empty
+    /// slots are left out.
+    pub fn grouped_counts(
+        &self,
+        step: i64,
+        since: i64,
+        until: i64,
+        group_by: &[Dimension],
+        filter: &EventFilter,
+    ) -> Result<Vec<SlotCount>> {
+        let (table, slot, range, count) = if step % DAY_MS == 0 {
+            ("daily_counts", "day_bucket", DAY_MS, "SUM(count)")
+        } else if step % HOUR_MS == 0 {
+            ("hourly_counts", "hour_bucket", HOUR_MS, "SUM(count)")
+        } else {
+            ("events", "observed_at", 1, "COUNT(*)")
+        };
+
+        let (mut clauses, mut values) = filter.dimension_clauses();
+        clauses.push(format!("{slot} >= ? AND {slot} < ?"));
+        values.push(Value::Integer(since / range));
+        values.push(Value::Integer(until / range));
+
+        let per_slot = step / range;
+        let mut columns = vec![format!("{slot} / {per_slot}")];
+        columns.extend(group_by.iter().map(|d| d.column().to_string()));
+        let columns = columns.join(", ");
+
+        let conn = self.reader();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {columns}, {count} FROM {table} {} GROUP BY {columns}",
+            where_sql(&clauses)
+        ))?;
+        let counts = stmt
+            .query_map(params_from_iter(values), |row| {
+                let slot: i64 = row.get(0)?;
+                Ok(SlotCount {
+                    start: slot * step,
+                    key: (1..=group_by.len())
+                        .map(|i| row.get(i))
+                        .collect::<rusqlite::Result<_>>()?,
+                    count: row.get(group_by.len() + 1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(counts)
+    }
+
+    /// Most frequent values of a page view field in `[since, until)` (Unix ms)
+    ///
+    /// Page views without the field count under a `None` value.
+    pub fn top_page_views(
+        &self,
+        field: WebField,
+        since: i64,
+        until: i64,
+        limit: usize,
+    ) -> Result<TopValues> {
+        let column = field.column();
+        let conn = self.reader();
+        let total: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM page_views WHERE observed_at >= ?1 AND observed_at < ?2",
+            params![since, until],
+            |row| row.get(0),
+        )?;
+        let mut stmt = conn.prepare(&format!(
+            r#"
+            SELECT {column}, COUNT(*)
+            FROM page_views
+            WHERE observed_at >= ?1 AND observed_at < ?2
+            GROUP BY {column}
+            ORDER BY COUNT(*) DESC, {column}
+            LIMIT ?3
+            "#
+        ))?;
+        let values: Vec<TopValue> = stmt
+            .query_map(params![since, until, limit as i64], |row| {
+                Ok(TopValue {
+                    value: row.get(0)?,
+                    count: row.get(1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(TopValues {
+            total,
+            other: total - values.iter().map(|v| v.count).sum::<i64>(),
+            values,
+        })
+    }
+
+    /// Get event count
+    pub fn event_count(&self) -> Result<i64> {
+        let conn = self.reader();
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM events", [], |row| row.get(0))?;
+        Ok(count)
+    }
+
+    /// Deliveries waiting in the outbox, across all destinations
+    pub fn pending_sync_count(&self) -> Result<i64> {
+        let conn = self.reader();
+        let count: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM outbox WHERE dead_lettered_at IS NULL",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(count)
+    }
+
+    /// Deliveries the destinations permanently rejected
+    pub fn dead_letter_count(&self) -> Result<i64> {
+        let conn = self.reader();
+        let count: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM outbox WHERE dead_lettered_at IS NOT NULL",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(count)
+    }
+
+    /// Pending and dead-lettered deliveries per destination
+    pub fn outbox_counts(&self) -> Result<Vec<OutboxCount>> {
+        let conn = self.reader();
+        let mut stmt = conn.prepare(
+            r#"
+            SELECT destination,
+                SUM(dead_lettered_at IS NULL),
+                SUM(dead_lettered_at IS NOT NULL)
+            FROM outbox
+            GROUP BY destination
+            ORDER BY destination
+            "#,
+        )?;
+        let routing = self.routing.read().unwrap();
+        let counts = stmt
+            .query_map([], |row| {
+                let destination: String = row.get(0)?;
+                Ok(OutboxCount {
+                    configured: routing.destinations.iter().any(|(d, _)| *d == destination),
+                    destination,
+                    pending: row.get(1)?,
+                    dead_letters: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(counts)
+    }
+}
+
+/// Column list matching `api_key_from_row`
+const API_KEY_COLUMNS: &str = "id, name, scope, public, allowed_origins_json, created_at";
+
+/// Map an `api_keys` row
+fn api_key_from_row(row: &Row<'_>) -> rusqlite::Result<ApiKey> {
+    let invalid = |column: usize, e: Error| {
+        rusqlite::Error::FromSqlConversionFailure(column, Type::Text, Box::new(e))
+    };
+    let scope: String = row.get(2)?;
+    let origins_json: Option<String> = row.get(4)?;
+    let public: i32 = row.get(3)?;
+    let created_at: i64 = row.get(5)?;
+
+    Ok(ApiKey {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        scope: scope.parse().map_err(|e| invalid(2, e))?,
+        public: public != 0,
+        allowed_origins: origins_json
+            .map(|json| serde_json::from_str(&json))
+            .transpose()
+            .map_err(|e| invalid(4, e.into()))?,
+        created_at: DateTime::from_timestamp_millis(created_at).unwrap_or_default(),
+    })
+}
+
+/// Result of inserting one event of a batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertOutcome {
+    /// Stored as a new event with this stream sequence
+    Inserted(i64),
+    /// An event with the same event_id was already stored
+    Duplicate,
+    /// Could not be stored; the rest of the batch is unaffected
+    Failed(String),
+}
+
+/// Idle read-only connections
+struct ReadPool {
+    idle: Mutex<Vec<Connection>>,
+    returned: Condvar,
+}
+
+/// A read-only connection borrowed from the pool, returned on drop
+struct PooledReader<'a> {
+    pool: &'a ReadPool,
+    conn: Option<Connection>,
+}
+
+impl std::ops::Deref for PooledReader<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledReader<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.idle.lock().unwrap().push(conn);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+fn writer_stopped() -> Error {
+    Error::Storage("database writer has stopped".to_string())
+}
+
+/// Writer thread: run jobs in order until every `Database` handle is gone
+fn run_writer(mut conn: Connection, jobs: mpsc::Receiver<Job>, routing: Arc<RwLock<Routing>>) {
+    let mut next = None;
+    while let Some(job) = next.take().or_else(|| jobs.recv().ok()) {
+        let (events, reply) = match job {
+            Job::Write(f) => {
+                f(&mut conn);
+                continue;
+            }
+            Job::Insert { events, reply } => (events, reply),
+        };
+
+        // Take the inserts already queued behind this one into the same
+        // transaction; another kind of job ends the group and runs after it
+        let mut count = events.len();
+        let mut group = vec![(events, reply)];
+        while count < MAX_GROUP_EVENTS {
+            match jobs.try_recv() {
+                Ok(Job::Insert { events, reply }) => {
+                    count += events.len();
+                    group.push((events, reply));
+                }
+                Ok(job) => {
+                    next = Some(job);
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+
+        let routing = routing.read().unwrap().clone();
+        let batches: Vec<&[Event]> = group.iter().map(|(events, _)| events.as_slice()).collect();
+        let results = store(&mut conn, &batches, &routing);
+        for ((_, reply), result) in group.into_iter().zip(results) {
+            let _ = reply.send(result);
+        }
+    }
+}
+
+/// Commit a group of requests' events, with one result per request
+///
+/// If the group transaction fails as a whole rather than one of its rows,
+/// each request is retried in a transaction of its own, so a request that
+/// cannot be committed does not fail the others.
+fn store(
+    conn: &mut Connection,
+    batches: &[&[Event]],
+    routing: &Routing,
+) -> Vec<Result<Vec<InsertOutcome>>> {
+    let failed = |events: &[Event], e: Error| {
+        warn!("Failed to store {} events: {}", events.len(), e);
+        Err(Error::Storage(e.to_string()))
+    };
+    match commit_group(conn, batches, routing) {
+        Ok(outcomes) => outcomes.into_iter().map(Ok).collect(),
+        Err(e) if batches.len() > 1 => {
+            warn!(
+                "Group commit of {} requests failed, retrying each on its own: {}",
+                batches.len(),
+                e
+            );
+            batches
+                .iter()
+                .map(|events| match commit_group(conn, &[events], routing) {
+                    Ok(mut outcomes) => Ok(outcomes.remove(0)),
+                    Err(e) => failed(events, e),
+                })
+                .collect()
+        }
+        Err(e) => vec![failed(batches[0], e)],
+    }
+}
+
+/// Insert several requests' events in one transaction, each under its own savepoint
+///
+/// Every new row takes the next stream sequence. The high-water mark lives in
+/// the `config` table rather than being derived from the rows, so numbers
+/// freed by retention are never handed out again.
+fn commit_group(
+    conn: &mut Connection,
+    batches: &[&[Event]],
+    routing: &Routing,
+) -> Result<Vec<Vec<InsertOutcome>>> {
+    let mut tx = conn.transaction()?;
+    let mut results = Vec::with_capacity(batches.len());
+    let mut seq: i64 = tx
+        .query_row(
+            "SELECT CAST(value AS INTEGER) FROM config WHERE key = 'stream_seq'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?
+        .unwrap_or(0);
+    let first_seq = seq;
+
+    for events in batches {
+        let mut outcomes = Vec::with_capacity(events.len());
+        for event in events.iter() {
+            let sp = tx.savepoint()?;
+            let outcome = match insert_row(&sp, event, seq + 1, routing) {
+                Ok(0) => InsertOutcome::Duplicate,
+                Ok(_) => {
+                    seq += 1;
+                    InsertOutcome::Inserted(seq)
+                }
+                Err(e) => InsertOutcome::Failed(e.to_string()),
+            };
+            if !matches!(outcome, InsertOutcome::Failed(_)) {
+                sp.commit()?;
+            }
+            outcomes.push(outcome);
+        }
+        results.push(outcomes);
+    }
+
+    if seq != first_seq {
+        tx.execute(
+            r#"
+            INSERT INTO config (key, value, updated_at) VALUES ('stream_seq', ?1, ?2)
+            ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at
+            "#,
+            params![seq, Utc::now().timestamp_millis()],
+        )?;
+    }
+    tx.commit()?;
+    Ok(results)
+}
+
+/// Insert one event row at stream sequence `seq` and queue it; returns 0 if
+/// the event_id already exists
+fn insert_row(conn: &Connection, event: &Event, seq: i64, routing: &Routing) -> Result<usize> {
+    let payload_json = serde_json::to_string(&event.event.data)?;
+    let attachments_json = event
+        .attachments
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+    let source_metadata_json = event
+        .source
+        .metadata
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+
+    let pii = event.privacy.as_ref().map(|p| p.pii).unwrap_or(false);
+    let retention_class = event
+        .privacy
+        .as_ref()
+        .map(|p| p.retention_class.as_str())
+        .unwrap_or("standard");
+    let synced = event.sync.as_ref().map(|s| s.synced).unwrap_or(false);
+    let source_seq = event.sync.as_ref().and_then(|s| s.source_seq);
+    let relayed_at = event
+        .sync
+        .as_ref()
+        .and_then(|s| s.relayed_at)
+        .map(|t| t.timestamp_millis());
+    let correlation = event.correlation.as_ref();
+
+    let rows = conn.prepare_cached(
+        r#"
+        INSERT INTO events (
+            event_id, observed_at, received_at,
+            source_type, source_id, source_seq,
+            category, type, severity, correlation_id,
+            payload_json, attachments_json,
+            pii, retention_class,
+            session_id, incident_id, schema_version,
+            source_version, source_metadata_json, relayed_at, stream_seq
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21)
+        ON CONFLICT(event_id) DO NOTHING
+        "#,
+    )?
+    .execute(params![
+            event.event_id,
+            event.observed_at.timestamp_millis(),
+            event.received_at.timestamp_millis(),
+            event.source.source_type,
+            event.source.id,
+            source_seq,
+            event.event.category,
+            event.event.event_type,
+            event.event.severity,
+            correlation.and_then(|c| c.correlation_id.as_ref()),
+            payload_json,
+            attachments_json,
+            pii as i32,
+            retention_class,
+            correlation.and_then(|c| c.session_id.as_ref()),
+            correlation.and_then(|c| c.incident_id.as_ref()),
+            event.event.schema_version,
+            event.source.version,
+            source_metadata_json,
+            relayed_at,
+            seq,
+    ])?;
+
+    if rows > 0 && !synced {
+        let mut stmt = conn.prepare_cached(
+            r#"
+            INSERT INTO outbox (destination, event_id, sync_priority, source_id, observed_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            "#,
+        )?;
+        let class = routing.lane.class(event);
+        for (destination, filter) in &routing.destinations {
+            if filter.matches(event) {
+                stmt.execute(params![
+                    destination,
+                    event.event_id,
+                    class,
+                    event.source.id,
+                    event.observed_at.timestamp_millis(),
+                ])?;
+            }
+        }
+    }
+
+    Ok(rows)
+}
+
+/// Up to `limit` events of one outbox class queued for `destination`,
+/// shared fairly between sources
+///
+/// Sources take turns: every source's oldest event comes before any source's
+/// second oldest, and so on. Each round starts with the first source after
+/// `after` (the last one served by the previous batch), so with more sources
+/// than fit in a batch the ones left out are the first in line next time.
+fn fair_share(
+    conn: &Connection,
+    destination: &str,
+    class: i64,
+    after: Option<String>,
+    limit: usize,
+) -> Result<Vec<Event>> {
+    let mut stmt = conn.prepare_cached(&format!(
+        r#"
+        SELECT {EVENT_COLUMNS}
+        FROM (
+            SELECT event_id AS pending_id, source_id AS pending_source,
+                ROW_NUMBER() OVER (
+                    PARTITION BY source_id ORDER BY observed_at, event_id
+                ) AS round
+            FROM outbox
+            WHERE destination = ?1 AND sync_priority = ?2 AND dead_lettered_at IS NULL
+        )
+        JOIN events ON events.event_id = pending_id
+        ORDER BY round, pending_source <= ?4, pending_source
+        LIMIT ?3
+        "#
+    ))?;
+    let rows: Vec<EventRow> = stmt
+        .query_map(
+            params![destination, class, limit as i64, after],
+            EventRow::from_row,
+        )?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| row.into_event().ok())
+        .collect())
+}
+
+/// SQL expression computing `PriorityLane::class` for an events row
+fn class_sql(lane: &PriorityLane) -> (String, Vec<Value>) {
+    let mut values = Vec::new();
+    let mut lane_clauses = Vec::new();
+    for (column, list) in [
+        (
+            "severity",
+            lane.severities.iter().map(|s| s.to_string()).collect(),
+        ),
+        ("category", lane.categories.clone()),
+    ] {
+        if !list.is_empty() {
+            let placeholders: Vec<&str> = list.iter().map(|_| "?").collect();
+            lane_clauses.push(format!("{} IN ({})", column, placeholders.join(",")));
+            values.extend(list.into_iter().map(Value::Text));
+        }
+    }
+
+    let mut sql = String::from("CASE");
+    if !lane_clauses.is_empty() {
+        sql.push_str(&format!(
+            " WHEN {} THEN {}",
+            lane_clauses.join(" OR "),
+            PRIORITY_CLASS
+        ));
+    }
+    for severity in SEVERITIES {
+        sql.push_str(&format!(
+            " WHEN severity = '{}' THEN {}",
+            severity,
+            severity_class(severity)
+        ));
+    }
+    sql.push_str(&format!(" ELSE {} END", LOWEST_CLASS));
+    (sql, values)
+}
+
+/// Internal row representation
+struct EventRow {
+    event_id: String,
+    observed_at: i64,
+    received_at: i64,
+    source_type: String,
+    source_id: String,
+    source_seq: Option<i64>,
+    category: String,
+    event_type: String,
+    severity: String,
+    correlation_id: Option<String>,
+    payload_json: String,
+    attachments_json: Option<String>,
+    pii: i32,
+    retention_class: String,
+    synced: i32,
+    session_id: Option<String>,
+    incident_id: Option<String>,
+    schema_version: Option<String>,
+    source_version: Option<String>,
+    source_metadata_json: Option<String>,
+    relayed_at: Option<i64>,
+}
+
+/// Column list matching `EventRow::from_row`
+const EVENT_COLUMNS: &str = "event_id, observed_at, received_at, \
+     source_type, source_id, source_seq, \
+     category, type, severity, correlation_id, \
+     payload_json, attachments_json, \
+     pii, retention_class, \
+     NOT EXISTS (SELECT 1 FROM outbox WHERE outbox.event_id = events.event_id), \
+     session_id, incident_id, schema_version, \
+     source_version, source_metadata_json, relayed_at";
+
+impl EventRow {
+    fn from_row(row: &Row<'_>) -> rusqlite::Result<Self> {
+        Ok(EventRow {
+            event_id: row.get(0)?,
+            observed_at: row.get(1)?,
+            received_at: row.get(2)?,
+            source_type: row.get(3)?,
+            source_id: row.get(4)?,
+            source_seq: row.get(5)?,
+            category: row.get(6)?,
+            event_type: row.get(7)?,
+            severity: row.get(8)?,
+            correlation_id: row.get(9)?,
+            payload_json: row.get(10)?,
+            attachments_json: row.get(11)?,
+            pii: row.get(12)?,
+            retention_class: row.get(13)?,
+            synced: row.get(14)?,
+            session_id: row.get(15)?,
+            incident_id: row.get(16)?,
+            schema_version: row.get(17)?,
+            source_version: row.get(18)?,
+            source_metadata_json: row.get(19)?,
+            relayed_at: row.get(20)?,
+        })
+    }
+
+    fn into_event(self) -> Result<Event> {
+        use chrono::TimeZone;
+
+        let observed_at = Utc.timestamp_millis_opt(self.observed_at).single().unwrap_or_else(Utc::now);
+        let received_at = Utc.timestamp_millis_opt(self.received_at).single().unwrap_or_else(Utc::now);
+
+        let data: serde_json::Value = serde_json::from_str(&self.payload_json)?;
+        let attachments: Option<Vec<crate::event::Attachment>> = self
+            .attachments_json
+            .as_ref()
+            .map(|s| serde_json::from_str(s))
+            .transpose()?;
+        let metadata: Option<serde_json::Value> = self
+            .source_metadata_json
+            .as_ref()
+            .map(|s| serde_json::from_str(s))
+            .transpose()?;
+
+        let correlation = if self.correlation_id.is_some()
+            || self.session_id.is_some()
+            || self.incident_id.is_some()
+        {
+            Some(crate::event::Correlation {
+                correlation_id: self.correlation_id,
+                session_id: self.session_id,
+                incident_id: self.incident_id,
+            })
+        } else {
+            None
+        };
+
+        Ok(Event {
+            event_id: self.event_id,
+            observed_at,
+            received_at,
+            source: crate::event::Source {
+                source_type: self.source_type,
+                id: self.source_id,
+                version: self.source_version,
+                metadata,
+            },
+            event: crate::event::EventDetails {
+                category: self.category,
+                event_type: self.event_type,
+                severity: self.severity,
+                schema_version: self.schema_version,
+                data,
+            },
+            correlation,
+            attachments,
+            privacy: Some(crate::event::Privacy {
+                pii: self.pii != 0,
+                retention_class: self.retention_class,
+            }),
+            sync: Some(crate::event::SyncStatus {
+                synced: self.synced != 0,
+                source_seq: self.source_seq,
+                relayed_at: self
+                    .relayed_at
+                    .and_then(|ms| Utc.timestamp_millis_opt(ms).single()),
+            }),
+        })
+    }
+}
+
+/// Timeline query filters (all optional, combined with AND)
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    /// Inclusive lower bound on `observed_at`
+    pub since: Option<DateTime<Utc>>,
+    /// Exclusive upper bound on `observed_at`
+    pub until: Option<DateTime<Utc>>,
+    pub category: Option<String>,
+    pub event_type: Option<String>,
+    /// Exact severity match
+    pub severity: Option<String>,
+    /// Minimum severity (e.g. `warn` matches warn, error, critical)
+    pub min_severity: Option<String>,
+    pub source_type: Option<String>,
+    pub source_id: Option<String>,
+    pub correlation_id: Option<String>,
+    pub session_id: Option<String>,
+    pub incident_id: Option<String>,
+}
+
+impl EventFilter {
+    /// SQL conditions and bound values for this filter
+    fn sql_clauses(&self) -> (Vec<String>, Vec<Value>) {
+        let mut clauses: Vec<String> = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+
+        if let Some(since) = self.since {
+            clauses.push("observed_at >= ?".to_string());
+            values.push(Value::Integer(since.timestamp_millis()));
+        }
+        if let Some(until) = self.until {
+            clauses.push("observed_at < ?".to_string());
+            values.push(Value::Integer(until.timestamp_millis()));
+        }
+        for (column, value) in [
+            ("correlation_id", &self.correlation_id),
+            ("session_id", &self.session_id),
+            ("incident_id", &self.incident_id),
+        ] {
+            if let Some(value) = value {
+                clauses.push(format!("{} = ?", column));
+                values.push(Value::Text(value.clone()));
+            }
+        }
+        let (dimensions, dimension_values) = self.dimension_clauses();
+        clauses.extend(dimensions);
+        values.extend(dimension_values);
+        (clauses, values)
+    }
+
+    /// Conditions on the columns the rollup tables keep
+    fn dimension_clauses(&self) -> (Vec<String>, Vec<Value>) {
+        let mut clauses: Vec<String> = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+
+        for (column, value) in [
+            ("category", &self.category),
+            ("type", &self.event_type),
+            ("severity", &self.severity),
+            ("source_type", &self.source_type),
+            ("source_id", &self.source_id),
+        ] {
+            if let Some(value) = value {
+                clauses.push(format!("{} = ?", column));
+                values.push(Value::Text(value.clone()));
+            }
+        }
+        if let Some(min) = self.min_severity.as_deref() {
+            let allowed = severities_at_least(min);
+            let placeholders: Vec<&str> = allowed.iter().map(|_| "?").collect();
+            clauses.push(format!("severity IN ({})", placeholders.join(",")));
+            values.extend(allowed.iter().map(|s| Value::Text(s.to_string())));
+        }
+        (clauses, values)
+    }
+
+    /// In-memory equivalent of `sql_clauses`, for live streams
+    pub fn matches(&self, event: &Event) -> bool {
+        let eq = |want: &Option<String>, have: &str| want.as_deref().is_none_or(|w| w == have);
+        let correlated = |want: &Option<String>, field: fn(&Correlation) -> &Option<String>| {
+            want.as_deref().is_none_or(|w| {
+                event.correlation.as_ref().and_then(|c| field(c).as_deref()) == Some(w)
+            })
+        };
+
+        self.since.is_none_or(|t| event.observed_at >= t)
+            && self.until.is_none_or(|t| event.observed_at < t)
+            && eq(&self.category, &event.event.category)
+            && eq(&self.event_type, &event.event.event_type)
+            && eq(&self.severity, &event.event.severity)
+            && eq(&self.source_type, &event.source.source_type)
+            && eq(&self.source_id, &event.source.id)
+            && correlated(&self.correlation_id, |c| &c.correlation_id)
+            && correlated(&self.session_id, |c| &c.session_id)
+            && correlated(&self.incident_id, |c| &c.incident_id)
+            && self
+                .min_severity
+                .as_deref()
+                .is_none_or(|min| severities_at_least(min).contains(&event.event.severity.as_str()))
+    }
+}
+
+fn where_sql(clauses: &[String]) -> String {
+    if clauses.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", clauses.join(" AND "))
+    }
+}
+
+/// Keyset pagination position: the last `(observed_at, event_id)` returned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    pub observed_at: i64,
+    pub event_id: String,
+}
+
+impl Cursor {
+    /// Encode as an opaque URL-safe token
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(format!("{}:{}", self.observed_at, self.event_id))
+    }
+
+    /// Decode a token produced by `encode`
+    pub fn decode(token: &str) -> Option<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(token).ok()?;
+        let raw = String::from_utf8(bytes).ok()?;
+        let (observed_at, event_id) = raw.split_once(':')?;
+        Some(Self {
+            observed_at: observed_at.parse().ok()?,
+            event_id: event_id.to_string(),
+        })
+    }
+}
+
+const HOUR_MS: i64 = 3_600_000;
+const DAY_MS: i64 = 86_400_000;
+
+/// Width of a time bucket for counts and charts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Interval {
+    Minute,
+    #[default]
+    Hour,
+    Day,
+}
+
+impl Interval {
+    /// Nominal width; local days in time zones with DST may be an hour off
+    pub fn millis(self) -> i64 {
+        match self {
+            Interval::Minute => 60_000,
+            Interval::Hour => HOUR_MS,
+            Interval::Day => DAY_MS,
+        }
+    }
+}
+
+/// Events of one group key in the slot starting at `start` (Unix ms)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlotCount {
+    pub start: i64,
+    /// Values of the requested `group_by` dimensions, in order
+    pub key: Vec<String>,
+    pub count: i64,
+}
+
+/// Page views sharing one value of a field
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TopValue {
+    pub value: Option<String>,
+    pub count: i64,
+}
+
+/// The most frequent values of a page view field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopValues {
+    /// Page views in the window
+    pub total: i64,
+    /// Most frequent first
+    pub values: Vec<TopValue>,
+    /// Page views with any other value
+    pub other: i64,
+}
+
+/// One page of timeline results
+#[derive(Debug)]
+pub struct EventPage {
+    pub events: Vec<Event>,
+    pub next_cursor: Option<Cursor>,
+}
+
+/// An event one or more destinations gave up on
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeadLetter {
+    pub event: Event,
+    pub destinations: Vec<FailedDelivery>,
+}
+
+/// A destination that rejected an event `attempts` times
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FailedDelivery {
+    pub destination: String,
+    pub attempts: u32,
+    /// Most recent rejection reason from the hub
+    pub error: Option<String>,
+    pub dead_lettered_at: DateTime<Utc>,
+}
+
+/// Outbox size of one destination
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OutboxCount {
+    pub destination: String,
+    pub pending: i64,
+    pub dead_letters: i64,
+    /// False for queues kept after their destination left the configuration
+    pub configured: bool,
+}
+
+/// One page of dead letters
+#[derive(Debug)]
+pub struct DeadLetterPage {
+    pub dead_letters: Vec<DeadLetter>,
+    pub next_cursor: Option<Cursor>,
+}
+
+use chrono::{DateTime, Utc};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::test_support::test_event;
+    use tempfile::tempdir;
+
+    fn destination(name: &str, filter: DestinationFilter) -> Destination {
+        Destination {
+            name: name.to_string(),
+            transport: crate::config::Transport::Http,
+            hub_url: format!("https://{}.example.com", name),
+            api_key: String::new(),
+            batch_size: 100,
+            max_batch_size: 1000,
+            compression: crate::config::SyncCompression::Auto,
+            filter,
+        }
+    }
+
+    /// A migrated database queueing events for a single unfiltered `hub`
+    fn open_with_hub(path: &Path) -> Database {
+        let db = Database::open(path).unwrap();
+        db.migrate().unwrap();
+        db.set_destinations(&[destination("hub", DestinationFilter::default())])
+            .unwrap();
+        db
+    }
+
+    #[test]
+    fn test_open_and_migrate() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("test.db");
+        let db = Database::open(&db_path).unwrap();
+        db.migrate().unwrap();
+        assert_eq!(db.event_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_migrate_tracks_version() {
+        let dir = tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db")).unwrap();
+        assert_eq!(db.schema_version().unwrap(), 0);
+        assert_eq!(db.pending_migrations().unwrap().len(), MIGRATIONS.len());
+
+        db.migrate().unwrap();
+        assert_eq!(db.schema_version().unwrap(), migrations::latest_version());
+        assert!(db.pending_migrations().unwrap().is_empty());
+
+        // Re-running is a no-op
+        db.migrate().unwrap();
+        assert_eq!(db.schema_version().unwrap(), migrations::latest_version());
+    }
+
+    #[test]
+    fn test_migrate_adopts_unversioned_database() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("test.db");
+        {
+            // Database created before migrations were versioned
+            let conn = Connection::open(&db_path).unwrap();
+            conn.execute_batch(MIGRATIONS[0].sql).unwrap();
+        }
+
+        let db = Database::open(&db_path).unwrap();
+        assert_eq!(db.schema_version().unwrap(), 0);
+        db.write(|conn| {
+            Ok(conn.execute(
+                "INSERT INTO events (event_id, observed_at, received_at, source_type, source_id, category, type, payload_json)
+                 VALUES ('legacy', 0, 0, 'browser', 's', 'web', 'page_view', '{}')",
+                [],
+            )?)
+        })
+        .unwrap();
+        db.migrate().unwrap();
+        assert_eq!(db.schema_version().unwrap(), migrations::latest_version());
+        assert_eq!(db.event_count().unwrap(), 1);
+
+        // Existing events are backfilled into the rollups
+        let all = EventFilter::default();
+        assert_eq!(per_slot(&db, 86_400_000, 0, 86_400_000, &all), [1]);
+        assert_eq!(per_slot(&db, 3_600_000, 0, 3_600_000, &all), [1]);
+    }
+
+    #[test]
+    fn test_migrate_refuses_newer_schema() {
+        let dir = tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db")).unwrap();
+        db.migrate().unwrap();
+        db.write(|conn| {
+            Ok(conn.pragma_update(None, "user_version", migrations::latest_version() + 1)?)
+        })
+        .unwrap();
+
+        assert!(matches!(db.migrate(), Err(Error::Migration(_))));
+    }
+
+    #[test]
+    fn test_insert_and_count() {
+        let dir = tempdir().unwrap();
+        let db = open_with_hub(&dir.path().join("test.db"));
+
+        let event = test_event("page_view").build();
+        db.insert_event(&event).unwrap();
+
+        assert_eq!(db.event_count().unwrap(), 1);
+        assert_eq!(db.pending_sync_count().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_insert_batch() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("test.db");
+        let db = Database::open(&db_path).unwrap();
+        db.migrate().unwrap();
+
+        let events: Vec<Event> = (0..10)
+            .map(|i| test_event(&format!("event_{}", i)).build())
+            .collect();
+
+        let outcomes = db.insert_events(&events).unwrap();
+        let expected: Vec<_> = (1..=10).map(InsertOutcome::Inserted).collect();
+        assert_eq!(outcomes, expected);
+        assert_eq!(db.event_count().unwrap(), 10);
+    }
+
+    #[test]
+    fn test_failed_group_commit_retries_each_request() {
+        let dir = tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db")).unwrap();
+        db.migrate().unwrap();
+
+        // Rows of type 'poison' pass their savepoint but fail at COMMIT, as a
+        // deferred constraint does
+        db.write(|conn| {
+            conn.execute_batch(
+                r#"
+                PRAGMA foreign_keys = ON;
+                CREATE TEMP TABLE poison_parent (id TEXT PRIMARY KEY);
+                CREATE TEMP TABLE poison (
+                    event_id TEXT REFERENCES poison_parent(id) DEFERRABLE INITIALLY DEFERRED
+                );
+                CREATE TEMP TRIGGER poison_on_commit AFTER INSERT ON main.events
+                WHEN new.type = 'poison' BEGIN
+                    INSERT INTO poison VALUES (new.event_id);
+                END;
+                "#,
+            )?;
+            Ok(())
+        })
+        .unwrap();
+
+        let first = vec![test_event("page_view").build()];
+        let poisoned = vec![test_event("poison").build()];
+        let last = vec![test_event("click").build(), test_event("click").build()];
+        let results = db
+            .write(move |conn| {
+                let routing = Routing::default();
+                Ok(store(conn, &[&first, &poisoned, &last], &routing))
+            })
+            .unwrap();
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(
+            results[0].as_ref().unwrap(),
+            &vec![InsertOutcome::Inserted(1)]
+        );
+        assert!(results[1].is_err());
+        assert_eq!(
+            results[2].as_ref().unwrap(),
+            &vec![InsertOutcome::Inserted(2), InsertOutcome::Inserted(3)]
+        );
+        assert_eq!(db.event_count().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_insert_batch_reports_duplicates() {
+        let dir = tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db")).unwrap();
+        db.migrate().unwrap();
+
+        let existing = test_event("page_view").build();
+        db.insert_event(&existing).unwrap();
+
+        let fresh = test_event("click").build();
+        let batch = vec![existing.clone(), fresh.clone(), fresh];
+        let outcomes = db.insert_events(&batch).unwrap();
+        assert_eq!(
+            outcomes,
+            vec![
+                InsertOutcome::Duplicate,
+                InsertOutcome::Inserted(2),
+                InsertOutcome::Duplicate
+            ]
+        );
+        assert_eq!(db.event_count().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_get_unsynced_and_mark_synced() {
+        let dir = tempdir().unwrap();
+        let db = open_with_hub(&dir.path().join("test.db"));
+
+        // Insert events
+        let events: Vec<Event> = (0..5)
+            .map(|i| test_event(&format!("event_{}", i)).build())
+            .collect();
+        db.insert_events(&events).unwrap();
 
         // Get unsynced
-        let unsynced = db.get_unsynced_events(10).unwrap();
+        let unsynced = db.get_unsynced_events("hub", 10).unwrap();
         assert_eq!(unsynced.len(), 5);
 
         // Mark first 3 as synced
         let ids: Vec<String> = unsynced.iter().take(3).map(|e| e.event_id.clone()).collect();
-        db.mark_synced(&ids).unwrap();
+        db.mark_synced("hub", &ids).unwrap();
 
         // Check counts
         assert_eq!(db.event_count().unwrap(), 5);
         assert_eq!(db.pending_sync_count().unwrap(), 2);
 
         // Get unsynced again
-        let unsynced2 = db.get_unsynced_events(10).unwrap();
+        let unsynced2 = db.get_unsynced_events("hub", 10).unwrap();
         assert_eq!(unsynced2.len(), 2);
     }
 
+    #[test]
+    fn test_dead_letters() {
+        let dir = tempdir().unwrap();
+        let db = open_with_hub(&dir.path().join("test.db"));
+
+        let events: Vec<Event> = (0..3).map(|_| test_event("page_view").build()).collect();
+        db.insert_events(&events).unwrap();
+        let bad = vec![(events[0].event_id.clone(), "unknown type".to_string())];
+
+        // Below the limit the event is retried
+        assert_eq!(db.record_sync_rejections("hub", &bad, 2).unwrap(), 0);
+        assert_eq!(db.get_unsynced_events("hub", 10).unwrap().len(), 3);
+
+        assert_eq!(db.record_sync_rejections("hub", &bad, 2).unwrap(), 1);
+        assert_eq!(db.get_unsynced_events("hub", 10).unwrap().len(), 2);
+        assert_eq!(db.pending_sync_count().unwrap(), 2);
+        assert_eq!(db.dead_letter_count().unwrap(), 1);
+
+        let page = db.list_dead_letters(None, None, 10).unwrap();
+        assert_eq!(page.dead_letters.len(), 1);
+        let dead = &page.dead_letters[0];
+        assert_eq!(dead.event.event_id, events[0].event_id);
+        assert_eq!(dead.destinations.len(), 1);
+        assert_eq!(dead.destinations[0].destination, "hub");
+        assert_eq!(dead.destinations[0].attempts, 2);
+        assert_eq!(dead.destinations[0].error.as_deref(), Some("unknown type"));
+        assert!(db
+            .list_dead_letters(Some("other"), None, 10)
+            .unwrap()
+            .dead_letters
+            .is_empty());
+        assert!(page.next_cursor.is_none());
+
+        // Dead letters are not waiting for the hub, so retention may expire them
+        let future = Utc::now().timestamp_millis() + 1000;
+        assert_eq!(
+            db.delete_expired_events(&[], &[], future, false, 10)
+                .unwrap(),
+            1
+        );
+        db.insert_event(&events[0]).unwrap();
+        db.record_sync_rejections("hub", &bad, 1).unwrap();
+
+        assert_eq!(db.requeue_dead_letters(None, Some(&[])).unwrap(), 0);
+        assert_eq!(db.requeue_dead_letters(Some("other"), None).unwrap(), 0);
+        assert_eq!(db.requeue_dead_letters(None, None).unwrap(), 1);
+        assert_eq!(db.dead_letter_count().unwrap(), 0);
+        assert_eq!(db.pending_sync_count().unwrap(), 3);
+
+        // The attempt count starts over after a requeue
+        assert_eq!(db.record_sync_rejections("hub", &bad, 2).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_outbox_order() {
+        let dir = tempdir().unwrap();
+        let db = open_with_hub(&dir.path().join("test.db"));
+
+        let base = Utc::now();
+        let event = |source: &str, severity: &str, age: i64| {
+            let mut e = test_event("page_view").build();
+            e.source.id = source.to_string();
+            e.event.severity = severity.to_string();
+            e.observed_at = base - chrono::Duration::seconds(age);
+            e
+        };
+        // A chatty source with a long backlog, a quiet one, and newer alerts
+        let mut events: Vec<Event> = (0..10).map(|i| event("chatty", "info", 100 + i)).collect();
+        events.push(event("quiet", "info", 1));
+        events.push(event("chatty", "warn", 0));
+        events.push(event("camera", "critical", 0));
+        db.insert_events(&events).unwrap();
+
+        let batch = db.get_unsynced_events("hub", 5).unwrap();
+        let ids: Vec<&str> = batch.iter().map(|e| e.event_id.as_str()).collect();
+        assert_eq!(ids[0], events[12].event_id);
+        assert_eq!(ids[1], events[11].event_id);
+        // The quiet source is not starved by the chatty one's older backlog
+        assert!(ids.contains(&events[10].event_id.as_str()));
+        assert_eq!(batch.iter().filter(|e| e.source.id == "chatty").count(), 3);
+        assert!(ids.contains(&events[9].event_id.as_str()));
+
+        assert_eq!(db.get_unsynced_events("hub", 100).unwrap().len(), 13);
+        let priority = db.get_unsynced_priority_events("hub", 100).unwrap();
+        assert_eq!(priority.len(), 1);
+
+        // Changing the lane re-ranks the pending backlog
+        db.set_priority_lane(PriorityLane {
+            severities: severities_at_least("warn"),
+            categories: Vec::new(),
+        })
+        .unwrap();
+        assert_eq!(
+            db.get_unsynced_priority_events("hub", 100).unwrap().len(),
+            2
+        );
+        db.insert_event(&event("quiet", "warn", 0)).unwrap();
+        assert_eq!(
+            db.get_unsynced_priority_events("hub", 100).unwrap().len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_outbox_shares_many_sources() {
+        let dir = tempdir().unwrap();
+        let db = open_with_hub(&dir.path().join("test.db"));
+
+        // Browser sessions: far more sources than fit in a batch, the ones
+        // late in the alphabet holding the oldest events
+        let base = Utc::now();
+        let mut events = Vec::new();
+        for i in 0..300 {
+            for n in 0..3 {
+                let mut e = test_event("page_view").build();
+                e.source.id = format!("session-{:03}", i);
+                e.observed_at = base - chrono::Duration::seconds(i * 10 - n);
+                events.push(e);
+            }
+        }
+        db.insert_events(&events).unwrap();
+
+        let mut sent = HashMap::new();
+        for round in 0..6 {
+            let batch = db.get_unsynced_events("hub", 50).unwrap();
+            assert_eq!(batch.len(), 50);
+            for event in &batch {
+                *sent.entry(event.source.id.clone()).or_insert(0) += 1;
+            }
+            if round == 1 {
+                assert_eq!(batch[0].source.id, "session-050");
+                assert_eq!(batch[49].source.id, "session-099");
+            }
+            let ids: Vec<String> = batch.into_iter().map(|e| e.event_id).collect();
+            db.mark_synced("hub", &ids).unwrap();
+        }
+        // Every session got one event out before any got a second
+        assert_eq!(sent.len(), 300);
+        assert!(sent.values().all(|n| *n == 1));
+    }
+
+    #[test]
+    fn test_destination_queues() {
+        let dir = tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db")).unwrap();
+        db.migrate().unwrap();
+        let security = DestinationFilter {
+            categories: vec!["security".to_string()],
+            ..Default::default()
+        };
+        db.set_destinations(&[
+            destination("hub", DestinationFilter::default()),
+            destination("customer", security),
+        ])
+        .unwrap();
+
+        let mut alert = test_event("door_forced").build();
+        alert.event.category = "security".to_string();
+        let events = vec![test_event("page_view").build(), alert];
+        db.insert_events(&events).unwrap();
+        assert_eq!(db.get_unsynced_events("hub", 10).unwrap().len(), 2);
+        let customer = db.get_unsynced_events("customer", 10).unwrap();
+        assert_eq!(customer.len(), 1);
+        assert_eq!(customer[0].event_id, events[1].event_id);
+
+        // Each destination keeps its own cursor
+        let ids = vec![events[1].event_id.clone()];
+        assert_eq!(db.mark_synced("customer", &ids).unwrap(), 1);
+        assert!(db.get_unsynced_events("customer", 10).unwrap().is_empty());
+        assert_eq!(db.get_unsynced_events("hub", 10).unwrap().len(), 2);
+        let counts = db.outbox_counts().unwrap();
+        assert_eq!(counts.len(), 1);
+        assert_eq!(counts[0].destination, "hub");
+        assert_eq!(counts[0].pending, 2);
+
+        // An event counts as synced once every destination has it
+        let synced = |id: &str| {
+            let page = db.query_events(&EventFilter::default(), None, 10).unwrap();
+            let event = page.events.into_iter().find(|e| e.event_id == id).unwrap();
+            event.sync.is_some_and(|s| s.synced)
+        };
+        assert!(!synced(&ids[0]));
+        db.mark_synced("hub", &ids).unwrap();
+        assert!(synced(&ids[0]));
+
+        // Dropping a destination keeps its queue until it is purged
+        db.set_destinations(&[destination("customer", DestinationFilter::default())])
+            .unwrap();
+        let counts = db.outbox_counts().unwrap();
+        assert_eq!(counts[0].destination, "hub");
+        assert!(!counts[0].configured);
+        db.insert_event(&test_event("page_view").build()).unwrap();
+        assert_eq!(db.get_unsynced_events("customer", 10).unwrap().len(), 1);
+        assert_eq!(db.get_unsynced_events("hub", 10).unwrap().len(), 1);
+        assert_eq!(db.purge_outbox("hub").unwrap(), 1);
+        assert!(db.get_unsynced_events("hub", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_removed_destination_keeps_backlog() {
+        let dir = tempdir().unwrap();
+        let db = open_with_hub(&dir.path().join("test.db"));
+        let events: Vec<Event> = (0..3).map(|_| test_event("page_view").build()).collect();
+        db.insert_events(&events).unwrap();
+
+        // A reload with the destination renamed by mistake, then fixed
+        db.set_destinations(&[destination("hub-typo", DestinationFilter::default())])
+            .unwrap();
+        db.insert_event(&test_event("page_view").build()).unwrap();
+        db.set_destinations(&[destination("hub", DestinationFilter::default())])
+            .unwrap();
+
+        let backlog = db.get_unsynced_events("hub", 10).unwrap();
+        assert_eq!(backlog.len(), 3);
+        assert!(db
+            .outbox_counts()
+            .unwrap()
+            .iter()
+            .all(|c| { c.configured == (c.destination == "hub") }));
+    }
+
+    #[test]
+    fn test_concurrent_inserts_share_writer() {
+        let dir = tempdir().unwrap();
+        let db = open_with_hub(&dir.path().join("test.db"));
+        let shared = test_event("page_view").build();
+
+        // Every thread sends the shared event plus its own; whichever group
+        // stores the shared one first wins, the rest see a duplicate
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let db = db.clone();
+                let events = vec![shared.clone(), test_event("click").build()];
+                std::thread::spawn(move || db.insert_events(&events).unwrap())
+            })
+            .collect();
+        let outcomes: Vec<_> = threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect();
+
+        let mut seqs: Vec<i64> = outcomes
+            .iter()
+            .filter_map(|o| match o {
+                InsertOutcome::Inserted(seq) => Some(*seq),
+                _ => None,
+            })
+            .collect();
+        seqs.sort_unstable();
+        assert_eq!(seqs, (1..=9).collect::<Vec<_>>());
+        let inserted = seqs.len();
+        assert_eq!(outcomes.len() - inserted, 7);
+        assert_eq!(db.event_count().unwrap(), 9);
+        assert_eq!(db.pending_sync_count().unwrap(), 9);
+    }
+
+    /// Concurrent single-event ingest while a reader polls the outbox and the
+    /// event list, as the server does under load. Run with
+    /// `cargo test --release -- --ignored --nocapture bench_`.
+    #[test]
+    #[ignore]
+    fn bench_concurrent_ingest() {
+        const WRITERS: usize = 16;
+        const EVENTS_PER_WRITER: usize = 500;
+
+        let dir = tempdir().unwrap();
+        let db = open_with_hub(&dir.path().join("test.db"));
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        let reader = std::thread::spawn({
+            let db = db.clone();
+            let done = done.clone();
+            move || {
+                let mut polls = 0;
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    db.get_unsynced_events("hub", 500).unwrap();
+                    db.query_events(&EventFilter::default(), None, 100).unwrap();
+                    polls += 1;
+                }
+                polls
+            }
+        });
+
+        let started = std::time::Instant::now();
+        let writers: Vec<_> = (0..WRITERS)
+            .map(|_| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    let mut latencies = Vec::with_capacity(EVENTS_PER_WRITER);
+                    for _ in 0..EVENTS_PER_WRITER {
+                        let event = test_event("page_view").build();
+                        let sent = std::time::Instant::now();
+                        assert!(db.insert_event(&event).unwrap());
+                        latencies.push(sent.elapsed());
+                    }
+                    latencies
+                })
+            })
+            .collect();
+        let mut latencies: Vec<_> = writers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect();
+        let elapsed = started.elapsed();
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+        let polls = reader.join().unwrap();
+
+        latencies.sort();
+        let percentile = |p: usize| latencies[latencies.len() * p / 100];
+        println!(
+            "{} events from {} writers in {:?}: {:.0} events/s, latency p50 {:?} p99 {:?}, {} reader polls",
+            latencies.len(),
+            WRITERS,
+            elapsed,
+            latencies.len() as f64 / elapsed.as_secs_f64(),
+            percentile(50),
+            percentile(99),
+            polls
+        );
+        assert_eq!(db.event_count().unwrap(), latencies.len() as i64);
+    }
+
     #[test]
     fn test_idempotent_insert() {
         let dir = tempdir().unwrap();
@@ -458,10 +2374,521 @@ mod tests {
         let db = Database::open(&db_path).unwrap();
         db.migrate().unwrap();
 
-        let event = make_test_event("page_view");
+        let event = test_event("page_view").build();
         db.insert_event(&event).unwrap();
         db.insert_event(&event).unwrap(); // Same event_id
 
         assert_eq!(db.event_count().unwrap(), 1); // Should still be 1
     }
+
+    #[test]
+    fn test_query_events_filters() {
+        let dir = tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db")).unwrap();
+        db.migrate().unwrap();
+
+        let mut alert = test_event("person_detected").build();
+        alert.event.category = "iot".to_string();
+        alert.event.severity = "critical".to_string();
+        alert.source.id = "camera-01".to_string();
+        db.insert_event(&alert).unwrap();
+        db.insert_event(&test_event("page_view").build()).unwrap();
+        db.insert_event(&test_event("click").build()).unwrap();
+
+        let by_type = EventFilter {
+            event_type: Some("page_view".to_string()),
+            ..Default::default()
+        };
+        let page = db.query_events(&by_type, None, 10).unwrap();
+        assert_eq!(page.events.len(), 1);
+        assert_eq!(page.events[0].event.event_type, "page_view");
+
+        let by_severity = EventFilter {
+            min_severity: Some("warn".to_string()),
+            ..Default::default()
+        };
+        let page = db.query_events(&by_severity, None, 10).unwrap();
+        assert_eq!(page.events.len(), 1);
+        assert_eq!(page.events[0].event_id, alert.event_id);
+
+        let by_source = EventFilter {
+            source_id: Some("camera-01".to_string()),
+            category: Some("web".to_string()),
+            ..Default::default()
+        };
+        assert!(db
+            .query_events(&by_source, None, 10)
+            .unwrap()
+            .events
+            .is_empty());
+    }
+
+    /// Ungrouped counts for every `step` slot of `[since, until)`
+    fn per_slot(
+        db: &Database,
+        step: i64,
+        since: i64,
+        until: i64,
+        filter: &EventFilter,
+    ) -> Vec<i64> {
+        let counts = db.grouped_counts(step, since, until, &[], filter).unwrap();
+        (since..until)
+            .step_by(step as usize)
+            .map(|start| {
+                counts
+                    .iter()
+                    .find(|c| c.start == start)
+                    .map_or(0, |c| c.count)
+            })
+            .collect()
+    }
+
+    /// Rows of `table` that differ from a fresh GROUP BY over the events
+    fn rollup_drift(db: &Database, table: &str, bucket: &str, width: i64) -> i64 {
+        let sql = format!(
+            r#"
+            SELECT COUNT(*) FROM (
+                SELECT * FROM (
+                    SELECT {bucket}, category, type, severity, source_type, source_id, count
+                    FROM {table}
+                    EXCEPT
+                    SELECT observed_at / {width}, category, type, severity, source_type, source_id, COUNT(*)
+                    FROM events GROUP BY 1, 2, 3, 4, 5, 6
+                )
+                UNION ALL
+                SELECT * FROM (
+                    SELECT observed_at / {width}, category, type, severity, source_type, source_id, COUNT(*)
+                    FROM events GROUP BY 1, 2, 3, 4, 5, 6
+                    EXCEPT
+                    SELECT {bucket}, category, type, severity, source_type, source_id, count
+                    FROM {table}
+                )
+            )
+            "#
+        );
+        db.reader().query_row(&sql, [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn test_rollups_follow_events() {
+        let dir = tempdir().unwrap();
+        let db = open_with_hub(&dir.path().join("test.db"));
+        let assert_in_step = || {
+            assert_eq!(
+                rollup_drift(&db, "hourly_counts", "hour_bucket", 3_600_000),
+                0
+            );
+            assert_eq!(
+                rollup_drift(&db, "daily_counts", "day_bucket", 86_400_000),
+                0
+            );
+        };
+
+        let start = DateTime::parse_from_rfc3339("2026-03-01T22:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let mut events = Vec::new();
+        for i in 0..12 {
+            let mut event = test_event(if i % 3 == 0 { "click" } else { "page_view" }).build();
+            // Every 20 minutes for four hours, across midnight
+            event.observed_at = start + chrono::Duration::minutes(20 * i);
+            if i % 4 == 0 {
+                event.event.severity = "error".to_string();
+                event.source.source_type = "server".to_string();
+            }
+            events.push(event);
+        }
+        db.insert_events(&events).unwrap();
+        assert_in_step();
+
+        // Duplicates are not counted twice
+        let outcomes = db.insert_events(&events[..4]).unwrap();
+        assert!(outcomes.iter().all(|o| *o == InsertOutcome::Duplicate));
+        assert_in_step();
+
+        let all = EventFilter::default();
+        let (since, hour) = (start.timestamp_millis(), 3_600_000);
+        let until = since + 4 * hour;
+        assert_eq!(per_slot(&db, hour, since, until, &all), [3, 3, 3, 3]);
+        let day = since - since % 86_400_000;
+        assert_eq!(
+            per_slot(&db, 86_400_000, day, day + 2 * 86_400_000, &all),
+            [6, 6]
+        );
+        // Steps finer than an hour are read from the events themselves
+        assert_eq!(per_slot(&db, 1_200_000, since, until, &all), [1; 12]);
+
+        let errors = EventFilter {
+            min_severity: Some("warn".to_string()),
+            source_type: Some("server".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(per_slot(&db, hour, since, until, &errors), [1, 1, 1, 0]);
+
+        let mut by_type = std::collections::BTreeMap::new();
+        for slot in db
+            .grouped_counts(hour, since, until, &[Dimension::Type], &all)
+            .unwrap()
+        {
+            *by_type.entry(slot.key[0].clone()).or_insert(0) += slot.count;
+        }
+        assert_eq!(by_type["click"], 4);
+        assert_eq!(by_type["page_view"], 8);
+
+        // Retention takes its counts along and leaves no empty rows behind
+        let cutoff = since + 2 * hour;
+        assert_eq!(
+            db.delete_expired_events(&[], &[], cutoff, true, 100)
+                .unwrap(),
+            6
+        );
+        assert_in_step();
+        assert_eq!(per_slot(&db, hour, since, until, &all), [0, 0, 3, 3]);
+        let rows: i64 = db
+            .reader()
+            .query_row(
+                "SELECT COUNT(*) FROM hourly_counts WHERE count <= 0",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(rows, 0);
+
+        db.evict_oldest_events(5).unwrap();
+        assert_in_step();
+    }
+
+    #[test]
+    fn test_page_view_dimensions() {
+        let dir = tempdir().unwrap();
+        let db = open_with_hub(&dir.path().join("test.db"));
+
+        let page_view = |path: &str, referrer: Option<&str>, metadata| {
+            let mut event = test_event("page_view").build();
+            event.event.data = serde_json::json!({"path": path, "referrer": referrer});
+            event.source.metadata = metadata;
+            event
+        };
+        // As sent by the JS tracker: UTM, device and referrer host in the metadata
+        let tracker = serde_json::json!({
+            "device_type": "mobile",
+            "browser": "Firefox",
+            "referrer_domain": "news.example.com",
+            "utm": {"source": "newsletter", "medium": "email"}
+        });
+        let events = vec![
+            page_view(
+                "/pricing",
+                Some("https://news.example.com/story"),
+                Some(tracker),
+            ),
+            page_view(
+                "/pricing",
+                Some("HTTPS://Search.Example.org:8443/q?x=1"),
+                None,
+            ),
+            page_view("/", Some("https://search.example.org"), None),
+            page_view("/", None, None),
+            page_view("/docs", None, None),
+            test_event("click").build(),
+        ];
+        db.insert_events(&events).unwrap();
+        db.insert_events(&events[..1]).unwrap();
+
+        let (since, until) = (0, i64::MAX);
+        let top = |field, limit| db.top_page_views(field, since, until, limit).unwrap();
+        let value = |v: &str, count| TopValue {
+            value: Some(v.to_string()),
+            count,
+        };
+
+        let paths = top(WebField::Path, 2);
+        assert_eq!(paths.total, 5);
+        assert_eq!(paths.values, [value("/", 2), value("/pricing", 2)]);
+        assert_eq!(paths.other, 1);
+
+        let referrers = top(WebField::ReferrerHost, 10);
+        assert_eq!(
+            referrers.values,
+            [
+                // Page views without a referrer come first among equals
+                TopValue {
+                    value: None,
+                    count: 2
+                },
+                value("search.example.org", 2),
+                value("news.example.com", 1),
+            ]
+        );
+        assert_eq!(
+            top(WebField::UtmSource, 10).values[1],
+            value("newsletter", 1)
+        );
+        assert_eq!(top(WebField::Browser, 10).values[1], value("Firefox", 1));
+
+        // Retention removes page views along with their events
+        let cutoff = Utc::now().timestamp_millis() + 1000;
+        db.delete_expired_events(&[], &[], cutoff, true, 100)
+            .unwrap();
+        assert_eq!(top(WebField::Path, 10).total, 0);
+    }
+
+    #[test]
+    fn test_query_events_cursor_pagination() {
+        let dir = tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db")).unwrap();
+        db.migrate().unwrap();
+
+        // Several events share a timestamp so the event_id tiebreak matters
+        let base = Utc::now();
+        let events: Vec<Event> = (0..7)
+            .map(|i| {
+                let mut e = test_event("page_view").build();
+                e.observed_at = base - chrono::Duration::seconds(i / 2);
+                e
+            })
+            .collect();
+        db.insert_events(&events).unwrap();
+
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = db
+                .query_events(&EventFilter::default(), cursor.as_ref(), 3)
+                .unwrap();
+            seen.extend(page.events.into_iter().map(|e| (e.observed_at, e.event_id)));
+            match page.next_cursor {
+                Some(next) => cursor = Some(Cursor::decode(&next.encode()).unwrap()),
+                None => break,
+            }
+        }
+
+        assert_eq!(seen.len(), 7);
+        let mut sorted = seen.clone();
+        sorted.sort_by(|a, b| b.cmp(a));
+        assert_eq!(seen, sorted);
+    }
+
+    #[test]
+    fn test_cursor_rejects_garbage() {
+        assert!(Cursor::decode("not a cursor").is_none());
+        assert!(Cursor::decode(&URL_SAFE_NO_PAD.encode("abc:def")).is_none());
+    }
+
+    #[test]
+    fn test_events_in_stream() {
+        let dir = tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db")).unwrap();
+        db.migrate().unwrap();
+        assert_eq!(db.stream_head().unwrap(), 0);
+
+        let events: Vec<Event> = (0..4)
+            .map(|i| test_event(if i % 2 == 0 { "page_view" } else { "click" }).build())
+            .collect();
+        db.insert_events(&events).unwrap();
+        assert_eq!(db.stream_head().unwrap(), 4);
+        assert_eq!(db.stream_seq_of(&events[2].event_id).unwrap(), Some(3));
+
+        let after = db
+            .events_in_stream(1, 4, &EventFilter::default(), 10)
+            .unwrap();
+        let ids: Vec<&str> = after.iter().map(|(_, e)| e.event_id.as_str()).collect();
+        let expected: Vec<&str> = events[1..].iter().map(|e| e.event_id.as_str()).collect();
+        assert_eq!(ids, expected);
+        let seqs: Vec<i64> = after.iter().map(|(seq, _)| *seq).collect();
+        assert_eq!(seqs, vec![2, 3, 4]);
+
+        let clicks = EventFilter {
+            event_type: Some("click".to_string()),
+            ..Default::default()
+        };
+        let after = db.events_in_stream(0, 3, &clicks, 10).unwrap();
+        assert_eq!(after.len(), 1);
+        assert_eq!(after[0].1.event_id, events[1].event_id);
+
+        // Relayed with a day-old received_at, but it committed last
+        let mut relayed = test_event("click").build();
+        relayed.received_at = Utc::now() - chrono::Duration::days(1);
+        let relayed = relayed.into_relayed();
+        db.insert_event(&relayed).unwrap();
+        let after = db
+            .events_in_stream(4, i64::MAX, &EventFilter::default(), 10)
+            .unwrap();
+        assert_eq!(after.len(), 1);
+        assert_eq!((after[0].0, &after[0].1.event_id), (5, &relayed.event_id));
+
+        // Deleting the newest event does not free its number
+        let id = relayed.event_id.clone();
+        db.write(move |conn| {
+            conn.execute("DELETE FROM events WHERE event_id = ?", [id])?;
+            Ok(())
+        })
+        .unwrap();
+        let outcomes = db.insert_events(&[test_event("click").build()]).unwrap();
+        assert_eq!(outcomes, vec![InsertOutcome::Inserted(6)]);
+    }
+
+    mod roundtrip {
+        use super::*;
+        use crate::event::{Attachment, EventDetails, Privacy, Source, SyncStatus};
+        use proptest::prelude::*;
+        use serde_json::Value as Json;
+
+        fn arb_json() -> impl Strategy<Value = Json> {
+            let leaf = prop_oneof![
+                Just(Json::Null),
+                any::<bool>().prop_map(Json::from),
+                any::<i64>().prop_map(Json::from),
+                any::<f64>()
+                    .prop_filter("finite", |f| f.is_finite())
+                    .prop_map(Json::from),
+                ".*".prop_map(Json::from),
+            ];
+            leaf.prop_recursive(3, 24, 4, |inner| {
+                prop_oneof![
+                    prop::collection::vec(inner.clone(), 0..4).prop_map(Json::from),
+                    prop::collection::btree_map(".*", inner, 0..4)
+                        .prop_map(|m| Json::Object(m.into_iter().collect())),
+                ]
+            })
+        }
+
+        fn arb_time() -> impl Strategy<Value = DateTime<Utc>> {
+            (0i64..4_102_444_800_000, 0u32..1_000_000).prop_map(|(ms, ns)| {
+                DateTime::from_timestamp_millis(ms).unwrap()
+                    + chrono::Duration::nanoseconds(ns as i64)
+            })
+        }
+
+        fn arb_attachment() -> impl Strategy<Value = Attachment> {
+            (
+                ".*",
+                ".*",
+                proptest::option::of(".*"),
+                proptest::option::of(any::<u64>()),
+                proptest::option::of(".*"),
+            )
+                .prop_map(|(kind, uri, sha256, size_bytes, mime_type)| Attachment {
+                    kind,
+                    uri,
+                    sha256,
+                    size_bytes,
+                    mime_type,
+                })
+        }
+
+        fn arb_event() -> impl Strategy<Value = Event> {
+            let source = (
+                ".*",
+                ".*",
+                proptest::option::of(".*"),
+                proptest::option::of(arb_json()),
+            )
+                .prop_map(|(source_type, id, version, metadata)| Source {
+                    source_type,
+                    id,
+                    version,
+                    metadata,
+                });
+            let details = (".*", ".*", ".*", proptest::option::of(".*"), arb_json()).prop_map(
+                |(category, event_type, severity, schema_version, data)| EventDetails {
+                    category,
+                    event_type,
+                    severity,
+                    schema_version,
+                    data,
+                },
+            );
+            let correlation = proptest::option::of(
+                (
+                    proptest::option::of(".*"),
+                    proptest::option::of(".*"),
+                    proptest::option::of(".*"),
+                )
+                    .prop_map(|(correlation_id, session_id, incident_id)| {
+                        Correlation {
+                            correlation_id,
+                            session_id,
+                            incident_id,
+                        }
+                    }),
+            );
+            let privacy =
+                proptest::option::of((any::<bool>(), ".*").prop_map(|(pii, retention_class)| {
+                    Privacy {
+                        pii,
+                        retention_class,
+                    }
+                }));
+            let sync = proptest::option::of(
+                (
+                    any::<bool>(),
+                    proptest::option::of(any::<i64>()),
+                    proptest::option::of(arb_time()),
+                )
+                    .prop_map(|(synced, source_seq, relayed_at)| SyncStatus {
+                        synced,
+                        source_seq,
+                        relayed_at,
+                    }),
+            );
+
+            (
+                ".+",
+                arb_time(),
+                arb_time(),
+                source,
+                details,
+                correlation,
+                proptest::option::of(prop::collection::vec(arb_attachment(), 0..3)),
+                privacy,
+                sync,
+            )
+                .prop_map(
+                    |(
+                        event_id,
+                        observed_at,
+                        received_at,
+                        source,
+                        event,
+                        correlation,
+                        attachments,
+                        privacy,
+                        sync,
+                    )| {
+                        let mut event = Event {
+                            event_id,
+                            observed_at,
+                            received_at,
+                            source,
+                            event,
+                            correlation,
+                            attachments,
+                            privacy,
+                            sync,
+                        };
+                        event.normalize();
+                        event
+                    },
+                )
+        }
+
+        proptest! {
+            #![proptest_config(ProptestConfig::with_cases(64))]
+
+            #[test]
+            fn stored_event_round_trips(event in arb_event()) {
+                let dir = tempdir().unwrap();
+                let db = open_with_hub(&dir.path().join("test.db"));
+                prop_assert!(db.insert_event(&event).unwrap());
+
+                let page = db.query_events(&EventFilter::default(), None, 10).unwrap();
+                prop_assert_eq!(&page.events, &vec![event.clone()]);
+
+                let unsynced = db.get_unsynced_events("hub", 10).unwrap();
+                let synced = event.sync.as_ref().is_some_and(|s| s.synced);
+                prop_assert_eq!(unsynced, if synced { vec![] } else { vec![event] });
+            }
+        }
+    }
 }
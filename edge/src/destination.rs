@@ -0,0 +1,218 @@
+//! Sync destinations
+//!
+//! Events fan out to one or more hubs. Each destination has its own API key,
+//! batch size and event filter, and its own queue in the `outbox` table, so a
+//! slow or failing hub never holds back the others.
+
+use serde::Serialize;
+
+use crate::config::{SyncCompression, SyncConfig, Transport};
+use crate::error::{Error, Result};
+use crate::event::{severities_at_least, Event};
+
+/// Name of the destination formed by the top-level `sync.hub_url`
+///
+/// Migration 6 queues the backlog of single-hub installs under this name.
+pub const DEFAULT_DESTINATION: &str = "default";
+
+/// Which events a destination receives
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DestinationFilter {
+    /// Empty = all
+    pub categories: Vec<String>,
+    /// Empty = all
+    pub types: Vec<String>,
+    /// None = all
+    pub severities: Option<&'static [&'static str]>,
+}
+
+impl DestinationFilter {
+    pub fn matches(&self, event: &Event) -> bool {
+        (self.categories.is_empty() || self.categories.contains(&event.event.category))
+            && (self.types.is_empty() || self.types.contains(&event.event.event_type))
+            && self
+                .severities
+                .is_none_or(|s| s.contains(&event.event.severity.as_str()))
+    }
+}
+
+/// A hub with its own credentials, batching and filter
+#[derive(Debug, Clone, PartialEq)]
+pub struct Destination {
+    pub name: String,
+    pub transport: Transport,
+    pub hub_url: String,
+    pub api_key: String,
+    pub batch_size: usize,
+    pub max_batch_size: usize,
+    pub compression: SyncCompression,
+    pub filter: DestinationFilter,
+}
+
+impl Destination {
+    /// Destinations configured under `[sync]`
+    ///
+    /// `[[sync.destinations]]` entries take precedence; without them, a
+    /// non-empty `hub_url` forms a single unfiltered `default` destination.
+    pub fn from_config(config: &SyncConfig) -> Result<Vec<Self>> {
+        if config.destinations.is_empty() {
+            if config.hub_url.is_empty() {
+                return Ok(Vec::new());
+            }
+            return Ok(vec![Self {
+                name: DEFAULT_DESTINATION.to_string(),
+                transport: Transport::Http,
+                hub_url: config.hub_url.clone(),
+                api_key: config.api_key.clone(),
+                batch_size: config.batch_size,
+                max_batch_size: config.max_batch_size,
+                compression: config.compression,
+                filter: DestinationFilter::default(),
+            }]);
+        }
+
+        let mut destinations: Vec<Self> = Vec::new();
+        for entry in &config.destinations {
+            if entry.name.is_empty() || destinations.iter().any(|d| d.name == entry.name) {
+                return Err(invalid(format!(
+                    "sync.destinations names must be unique and non-empty (got '{}')",
+                    entry.name
+                )));
+            }
+            if entry.transport == Transport::Http && entry.hub_url.is_empty() {
+                return Err(invalid(format!(
+                    "sync destination '{}' has no hub_url",
+                    entry.name
+                )));
+            }
+            let severities = match &entry.min_severity {
+                Some(min) => match severities_at_least(min) {
+                    [] => {
+                        return Err(invalid(format!(
+                            "sync destination '{}': min_severity '{}' is not a severity level",
+                            entry.name, min
+                        )))
+                    }
+                    levels => Some(levels),
+                },
+                None => None,
+            };
+
+            destinations.push(Self {
+                name: entry.name.clone(),
+                transport: entry.transport,
+                hub_url: entry.hub_url.clone(),
+                api_key: entry.api_key.clone(),
+                batch_size: entry.batch_size.unwrap_or(config.batch_size),
+                max_batch_size: entry.max_batch_size.unwrap_or(config.max_batch_size),
+                compression: entry.compression.unwrap_or(config.compression),
+                filter: DestinationFilter {
+                    categories: entry.categories.clone(),
+                    types: entry.types.clone(),
+                    severities,
+                },
+            });
+        }
+        Ok(destinations)
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::Config(config::ConfigError::Message(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DestinationConfig;
+    use crate::event::test_support::test_event;
+
+    #[test]
+    fn test_from_config() {
+        let legacy = SyncConfig {
+            hub_url: "https://hub.example.com".to_string(),
+            api_key: "ek_hub".to_string(),
+            ..Default::default()
+        };
+        let destinations = Destination::from_config(&legacy).unwrap();
+        assert_eq!(destinations.len(), 1);
+        assert_eq!(destinations[0].name, DEFAULT_DESTINATION);
+        assert_eq!(destinations[0].filter, DestinationFilter::default());
+        assert!(Destination::from_config(&SyncConfig::default())
+            .unwrap()
+            .is_empty());
+
+        let customer = DestinationConfig {
+            name: "customer".to_string(),
+            hub_url: "https://customer.example.com".to_string(),
+            batch_size: Some(10),
+            categories: vec!["security".to_string()],
+            min_severity: Some("warn".to_string()),
+            ..Default::default()
+        };
+        let config = SyncConfig {
+            destinations: vec![customer.clone()],
+            ..legacy.clone()
+        };
+        let destinations = Destination::from_config(&config).unwrap();
+        assert_eq!(destinations.len(), 1);
+        assert_eq!(destinations[0].batch_size, 10);
+        assert_eq!(destinations[0].max_batch_size, config.max_batch_size);
+        assert_eq!(
+            destinations[0].filter.severities,
+            Some(&["warn", "error", "critical"][..])
+        );
+
+        for broken in [
+            vec![customer.clone(), customer.clone()],
+            vec![DestinationConfig {
+                hub_url: String::new(),
+                ..customer.clone()
+            }],
+            vec![DestinationConfig {
+                name: String::new(),
+                ..customer.clone()
+            }],
+            vec![DestinationConfig {
+                min_severity: Some("loud".to_string()),
+                ..customer.clone()
+            }],
+        ] {
+            let config = SyncConfig {
+                destinations: broken,
+                ..Default::default()
+            };
+            assert!(Destination::from_config(&config).is_err());
+        }
+
+        // Bundle destinations are carried by hand and need no hub
+        let usb = DestinationConfig {
+            name: "usb".to_string(),
+            transport: Transport::Bundle,
+            ..Default::default()
+        };
+        let config = SyncConfig {
+            destinations: vec![usb],
+            ..Default::default()
+        };
+        let destinations = Destination::from_config(&config).unwrap();
+        assert_eq!(destinations[0].transport, Transport::Bundle);
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut event = test_event("door_forced").category("security").build();
+
+        assert!(DestinationFilter::default().matches(&event));
+        let filter = DestinationFilter {
+            categories: vec!["security".to_string()],
+            types: Vec::new(),
+            severities: Some(severities_at_least("warn")),
+        };
+        assert!(!filter.matches(&event));
+        event.event.severity = "critical".to_string();
+        assert!(filter.matches(&event));
+        event.event.category = "web".to_string();
+        assert!(!filter.matches(&event));
+    }
+}
@@ -24,8 +24,23 @@ pub enum Error {
     #[error("Invalid event: {0}")]
     InvalidEvent(String),
 
+    #[error("Schema error: {0}")]
+    Schema(String),
+
+    #[error("Storage error: {0}")]
+    Storage(String),
+
+    #[error("Migration error: {0}")]
+    Migration(String),
+
+    #[error("Auth error: {0}")]
+    Auth(String),
+
     #[error("Sync error: {0}")]
     Sync(String),
+
+    #[error("Bundle error: {0}")]
+    Bundle(String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
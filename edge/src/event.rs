@@ -1,11 +1,11 @@
 //! Event types and schema for EdgeKite
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, SubsecRound, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 /// Source of an event
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Source {
     /// Type of source (browser, edge_device, server, mobile)
     #[serde(rename = "type")]
@@ -24,7 +24,7 @@ pub struct Source {
 }
 
 /// Event details
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct EventDetails {
     /// Category (web, iot, app, ops, security)
     pub category: String,
@@ -50,7 +50,7 @@ fn default_severity() -> String {
 }
 
 /// Correlation information for linking events
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
 pub struct Correlation {
     /// Correlation ID for related events
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -66,7 +66,7 @@ pub struct Correlation {
 }
 
 /// Attachment reference (media stored out-of-band)
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Attachment {
     /// Kind of attachment (thumbnail, clip, log, report)
     pub kind: String,
@@ -88,7 +88,7 @@ pub struct Attachment {
 }
 
 /// Privacy and retention settings
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Privacy {
     /// Contains PII
     #[serde(default)]
@@ -103,8 +103,17 @@ fn default_retention_class() -> String {
     "standard".to_string()
 }
 
+impl Default for Privacy {
+    fn default() -> Self {
+        Self {
+            pii: false,
+            retention_class: default_retention_class(),
+        }
+    }
+}
+
 /// Sync status (internal)
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
 pub struct SyncStatus {
     /// Has been synced to hub
     #[serde(default)]
@@ -113,10 +122,14 @@ pub struct SyncStatus {
     /// Monotonic sequence per source
     #[serde(skip_serializing_if = "Option::is_none")]
     pub source_seq: Option<i64>,
+
+    /// When this agent received the event from a downstream agent or bundle
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relayed_at: Option<DateTime<Utc>>,
 }
 
 /// Complete event envelope
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Event {
     /// Unique event identifier
     pub event_id: String,
@@ -150,8 +163,43 @@ pub struct Event {
     pub sync: Option<SyncStatus>,
 }
 
+impl Event {
+    /// Bring the envelope into the canonical form it is stored in
+    ///
+    /// Timestamps are kept at millisecond precision, an empty correlation
+    /// is dropped, and privacy/sync defaults are made explicit, so that an
+    /// event read back from SQLite compares equal to the one ingested.
+    pub fn normalize(&mut self) {
+        self.observed_at = self.observed_at.trunc_subsecs(3);
+        self.received_at = self.received_at.trunc_subsecs(3);
+        if self.correlation.as_ref().is_some_and(|c| {
+            c.correlation_id.is_none() && c.session_id.is_none() && c.incident_id.is_none()
+        }) {
+            self.correlation = None;
+        }
+        self.privacy.get_or_insert_with(Privacy::default);
+        let sync = self.sync.get_or_insert_with(SyncStatus::default);
+        sync.relayed_at = sync.relayed_at.map(|t| t.trunc_subsecs(3));
+    }
+
+    /// Prepare an event forwarded by a downstream agent for storage
+    ///
+    /// The envelope is kept as sent, including `received_at` and
+    /// `source_seq`. `synced` is cleared, since it described the sender's
+    /// outbox and the event still has to go upstream from here, and
+    /// `relayed_at` records when it arrived here.
+    pub fn into_relayed(mut self) -> Self {
+        self.normalize();
+        if let Some(sync) = &mut self.sync {
+            sync.synced = false;
+            sync.relayed_at = Some(Utc::now().trunc_subsecs(3));
+        }
+        self
+    }
+}
+
 /// Incoming event (before processing)
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct IncomingEvent {
     /// Event ID (optional, will be generated if missing)
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -185,7 +233,7 @@ impl IncomingEvent {
     pub fn into_event(self) -> Event {
         let now = Utc::now();
 
-        Event {
+        let mut event = Event {
             event_id: self.event_id.unwrap_or_else(|| Uuid::new_v4().to_string()),
             observed_at: self.observed_at.unwrap_or(now),
             received_at: now,
@@ -195,24 +243,131 @@ impl IncomingEvent {
             attachments: self.attachments,
             privacy: self.privacy,
             sync: Some(SyncStatus::default()),
-        }
+        };
+        event.normalize();
+        event
     }
 }
 
+/// Event categories
+pub const CATEGORIES: [&str; 5] = ["web", "iot", "app", "ops", "security"];
+
 /// Validate event category
-#[allow(dead_code)]
 pub fn validate_category(category: &str) -> bool {
-    matches!(category, "web" | "iot" | "app" | "ops" | "security")
+    CATEGORIES.contains(&category)
 }
 
 /// Validate source type
-#[allow(dead_code)]
 pub fn validate_source_type(source_type: &str) -> bool {
     matches!(source_type, "browser" | "edge_device" | "server" | "mobile")
 }
 
 /// Validate severity
-#[allow(dead_code)]
 pub fn validate_severity(severity: &str) -> bool {
     matches!(severity, "debug" | "info" | "warn" | "error" | "critical")
 }
+
+/// Severity levels, lowest to highest
+pub const SEVERITIES: [&str; 5] = ["debug", "info", "warn", "error", "critical"];
+
+/// Severities at or above `min` (empty if `min` is not a known level)
+pub fn severities_at_least(min: &str) -> &'static [&'static str] {
+    match SEVERITIES.iter().position(|s| *s == min) {
+        Some(i) => &SEVERITIES[i..],
+        None => &[],
+    }
+}
+
+/// Event fixtures shared by the unit tests
+#[cfg(test)]
+pub mod test_support {
+    use super::*;
+
+    /// Builds an event for a test, starting from a valid browser event
+    pub struct EventBuilder(Event);
+
+    /// An `info` web event of `event_type` with a fresh event_id, observed and
+    /// received now
+    pub fn test_event(event_type: &str) -> EventBuilder {
+        let now = Utc::now();
+        EventBuilder(Event {
+            event_id: Uuid::new_v4().to_string(),
+            observed_at: now,
+            received_at: now,
+            source: Source {
+                source_type: "browser".to_string(),
+                id: "test-session".to_string(),
+                version: Some("1.0".to_string()),
+                metadata: None,
+            },
+            event: EventDetails {
+                category: "web".to_string(),
+                event_type: event_type.to_string(),
+                severity: "info".to_string(),
+                schema_version: None,
+                data: serde_json::json!({"path": "/test"}),
+            },
+            correlation: None,
+            attachments: None,
+            privacy: None,
+            sync: None,
+        })
+    }
+
+    impl EventBuilder {
+        pub fn category(mut self, category: &str) -> Self {
+            self.0.event.category = category.to_string();
+            self
+        }
+
+        pub fn severity(mut self, severity: &str) -> Self {
+            self.0.event.severity = severity.to_string();
+            self
+        }
+
+        pub fn data(mut self, data: serde_json::Value) -> Self {
+            self.0.event.data = data;
+            self
+        }
+
+        pub fn source(mut self, source_type: &str, id: &str) -> Self {
+            self.0.source.source_type = source_type.to_string();
+            self.0.source.id = id.to_string();
+            self
+        }
+
+        /// Observed and received at `at`
+        pub fn observed_at(mut self, at: DateTime<Utc>) -> Self {
+            self.0.observed_at = at;
+            self.0.received_at = at;
+            self
+        }
+
+        pub fn retention_class(mut self, retention_class: &str) -> Self {
+            self.0.privacy = Some(Privacy {
+                pii: false,
+                retention_class: retention_class.to_string(),
+            });
+            self
+        }
+
+        /// Adds a `clip` attachment at `uri`
+        pub fn attachment(mut self, uri: &str) -> Self {
+            self.0
+                .attachments
+                .get_or_insert_with(Vec::new)
+                .push(Attachment {
+                    kind: "clip".to_string(),
+                    uri: uri.to_string(),
+                    sha256: None,
+                    size_bytes: None,
+                    mime_type: None,
+                });
+            self
+        }
+
+        pub fn build(self) -> Event {
+            self.0
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! Bounded ingest queue
+//!
+//! Ingest handlers hand their events to this queue instead of writing to the
+//! database themselves; one drain task stores what is waiting in batches.
+//! The queue holds at most `server.ingest_queue.capacity` events, so when
+//! SQLite stalls (an SD card hiccup, a long checkpoint) new requests get an
+//! answer at once instead of piling up behind the writer: a 503 with
+//! `Retry-After`, or with `when_full = "drop_lowest_severity"`, room made
+//! by dropping the least severe waiting events.
+
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::Response;
+use std::collections::VecDeque;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::{oneshot, Notify};
+
+use crate::config::{IngestQueueConfig, QueueFullPolicy};
+use crate::db::{Database, InsertOutcome};
+use crate::event::{Event, SEVERITIES};
+
+/// Most events handed to the database in one write
+const DRAIN_BATCH: usize = 1000;
+
+/// What became of a queued event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// Reached the database
+    Stored(InsertOutcome),
+    /// The write failed as a whole; nothing in its batch was stored
+    Failed(String),
+    /// Dropped from the full queue in favour of more severe events
+    Dropped,
+}
+
+/// The queue was full and the request was refused; its events come back
+#[derive(Debug)]
+pub struct Full {
+    pub events: Vec<Event>,
+    pub retry_after: Duration,
+}
+
+/// Reason given for events refused or dropped because the queue was full
+pub const QUEUE_FULL: &str = "ingest queue full";
+
+struct Waiting {
+    /// Arrival order across lanes
+    seq: u64,
+    event: Event,
+    reply: oneshot::Sender<(Event, Outcome)>,
+}
+
+#[derive(Default)]
+struct Lanes {
+    /// Waiting events by severity, lowest first; each lane oldest first
+    by_severity: [VecDeque<Waiting>; SEVERITIES.len()],
+    depth: usize,
+    next_seq: u64,
+}
+
+impl Lanes {
+    fn push(&mut self, event: Event, reply: oneshot::Sender<(Event, Outcome)>) {
+        let lane = lane(&event);
+        self.by_severity[lane].push_back(Waiting {
+            seq: self.next_seq,
+            event,
+            reply,
+        });
+        self.next_seq += 1;
+        self.depth += 1;
+    }
+
+    /// Drop the newest event of the lowest lane below `lane`, if any
+    fn evict_below(&mut self, lane: usize) -> bool {
+        let Some(victim) = self.by_severity[..lane]
+            .iter_mut()
+            .find_map(|queue| queue.pop_back())
+        else {
+            return false;
+        };
+        self.depth -= 1;
+        let _ = victim.reply.send((victim.event, Outcome::Dropped));
+        true
+    }
+
+    /// Up to `max` waiting events in arrival order
+    fn take(&mut self, max: usize) -> Vec<Waiting> {
+        let mut batch = Vec::with_capacity(max.min(self.depth));
+        while batch.len() < max {
+            let oldest = self
+                .by_severity
+                .iter_mut()
+                .filter(|queue| !queue.is_empty())
+                .min_by_key(|queue| queue[0].seq);
+            match oldest.and_then(|queue| queue.pop_front()) {
+                Some(waiting) => batch.push(waiting),
+                None => break,
+            }
+        }
+        self.depth -= batch.len();
+        batch
+    }
+}
+
+/// Lane of an event's severity (unknown severities rank lowest)
+fn lane(event: &Event) -> usize {
+    SEVERITIES
+        .iter()
+        .position(|s| *s == event.event.severity)
+        .unwrap_or(0)
+}
+
+/// Events waiting to be stored
+pub struct IngestQueue {
+    lanes: Mutex<Lanes>,
+    ready: Notify,
+    config: RwLock<IngestQueueConfig>,
+}
+
+impl IngestQueue {
+    pub fn new(config: IngestQueueConfig) -> Self {
+        Self {
+            lanes: Mutex::new(Lanes::default()),
+            ready: Notify::new(),
+            config: RwLock::new(config),
+        }
+    }
+
+    /// Apply a reloaded configuration; events already waiting stay queued
+    pub fn reconfigure(&self, config: IngestQueueConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    /// How long clients should wait before retrying refused or dropped events
+    pub fn retry_after(&self) -> Duration {
+        Duration::from_secs(self.config.read().unwrap().retry_after_seconds)
+    }
+
+    /// Events currently waiting
+    pub fn depth(&self) -> usize {
+        self.lanes.lock().unwrap().depth
+    }
+
+    /// Queue `events` and wait until each has been stored or dropped
+    ///
+    /// Outcomes come back in submission order with their events. A request
+    /// that does not fit is refused as a whole, unless the queue is empty:
+    /// a batch larger than the capacity is still taken on its own.
+    pub async fn store(
+        &self,
+        events: Vec<Event>,
+    ) -> std::result::Result<Vec<(Event, Outcome)>, Full> {
+        let replies = self.submit(events)?;
+        let mut outcomes = Vec::with_capacity(replies.len());
+        for reply in replies {
+            outcomes.push(reply.await.expect("ingest queue drain task stopped"));
+        }
+        Ok(outcomes)
+    }
+
+    fn submit(
+        &self,
+        events: Vec<Event>,
+    ) -> std::result::Result<Vec<oneshot::Receiver<(Event, Outcome)>>, Full> {
+        let config = self.config.read().unwrap().clone();
+        let mut lanes = self.lanes.lock().unwrap();
+
+        let fits = lanes.depth == 0 || lanes.depth + events.len() <= config.capacity;
+        if !fits && config.when_full == QueueFullPolicy::Reject {
+            return Err(Full {
+                events,
+                retry_after: Duration::from_secs(config.retry_after_seconds),
+            });
+        }
+
+        let mut replies = Vec::with_capacity(events.len());
+        for event in events {
+            let (reply, outcome) = oneshot::channel();
+            replies.push(outcome);
+            if fits || lanes.depth < config.capacity || lanes.evict_below(lane(&event)) {
+                lanes.push(event, reply);
+            } else {
+                let _ = reply.send((event, Outcome::Dropped));
+            }
+        }
+        drop(lanes);
+        self.ready.notify_one();
+        Ok(replies)
+    }
+
+    /// Store waiting events until the runtime shuts down
+    pub async fn drain(&self, db: Database) {
+        loop {
+            let batch = self.lanes.lock().unwrap().take(DRAIN_BATCH);
+            if batch.is_empty() {
+                self.ready.notified().await;
+                continue;
+            }
+
+            let (events, replies): (Vec<_>, Vec<_>) = batch
+                .into_iter()
+                .map(|waiting| (waiting.event, waiting.reply))
+                .unzip();
+            let (events, result) = db
+                .blocking(move |db| {
+                    let result = db.insert_events(&events);
+                    (events, result)
+                })
+                .await;
+
+            match result {
+                Ok(outcomes) => {
+                    for ((event, reply), outcome) in events.into_iter().zip(replies).zip(outcomes) {
+                        let _ = reply.send((event, Outcome::Stored(outcome)));
+                    }
+                }
+                Err(e) => {
+                    for (event, reply) in events.into_iter().zip(replies) {
+                        let _ = reply.send((event, Outcome::Failed(e.to_string())));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Turn a response into a 503 with a `Retry-After` header (whole seconds, at least 1)
+pub fn service_unavailable(mut response: Response, wait: Duration) -> Response {
+    *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    let seconds = wait.as_secs_f64().ceil().max(1.0) as u64;
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::test_support::test_event;
+    use std::sync::Arc;
+
+    fn event(severity: &str) -> Event {
+        test_event("motion").severity(severity).build()
+    }
+
+    fn queue(capacity: usize, when_full: QueueFullPolicy) -> Arc<IngestQueue> {
+        Arc::new(IngestQueue::new(IngestQueueConfig {
+            capacity,
+            when_full,
+            retry_after_seconds: 2,
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_reject_when_full() {
+        let queue = queue(2, QueueFullPolicy::Reject);
+        // Nothing drains, so submitted events stay queued
+        let waiting = queue.submit(vec![event("info"), event("info")]).unwrap();
+        assert_eq!(queue.depth(), 2);
+        let full = queue.submit(vec![event("critical")]).unwrap_err();
+        assert_eq!(full.events.len(), 1);
+        assert_eq!(full.retry_after, Duration::from_secs(2));
+        assert_eq!(queue.depth(), 2);
+        drop(waiting);
+
+        // An empty queue takes an oversized batch rather than starve it
+        let queue = self::queue(2, QueueFullPolicy::Reject);
+        assert_eq!(queue.submit(vec![event("info"); 3]).unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_drop_lowest_severity() {
+        let queue = queue(3, QueueFullPolicy::DropLowestSeverity);
+        let mut first = queue
+            .submit(vec![event("info"), event("debug"), event("warn")])
+            .unwrap();
+
+        // An error pushes out the debug event, the next one the info event
+        let mut second = queue.submit(vec![event("error"), event("error")]).unwrap();
+        assert_eq!(first[1].try_recv().unwrap().1, Outcome::Dropped);
+        assert_eq!(first[0].try_recv().unwrap().1, Outcome::Dropped);
+        assert!(first[2].try_recv().is_err());
+
+        // Nothing waiting is less severe than debug: it is dropped itself
+        let mut third = queue.submit(vec![event("debug")]).unwrap();
+        assert_eq!(third[0].try_recv().unwrap().1, Outcome::Dropped);
+        assert_eq!(queue.depth(), 3);
+
+        // The rest drain in arrival order
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db")).unwrap();
+        db.migrate().unwrap();
+        tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.drain(db).await }
+        });
+        let warn = first.pop().unwrap().await.unwrap();
+        assert_eq!(warn.0.event.severity, "warn");
+        assert_eq!(warn.1, Outcome::Stored(InsertOutcome::Inserted(1)));
+        for (reply, seq) in second.drain(..).zip(2..) {
+            assert_eq!(
+                reply.await.unwrap().1,
+                Outcome::Stored(InsertOutcome::Inserted(seq))
+            );
+        }
+        assert_eq!(queue.depth(), 0);
+    }
+}
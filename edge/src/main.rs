@@ -2,17 +2,33 @@
 //!
 //! This is the main entry point for the edge agent.
 
-use clap::Parser;
-use std::path::PathBuf;
-use tracing::{info, Level};
+use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
+mod aggregate;
+mod auth;
+mod bundle;
+mod compression;
 mod config;
 mod db;
+mod destination;
 mod error;
 mod event;
+mod ingest;
+mod metrics;
+mod migrations;
+mod ratelimit;
+mod reload;
+mod retention;
+mod schedule;
 mod server;
+mod shutdown;
 mod sync;
+mod validate;
 
 use config::Config;
 use error::Result;
@@ -38,6 +54,127 @@ struct Args {
     /// Enable verbose logging
     #[arg(short, long)]
     verbose: bool,
+
+    /// Apply pending schema migrations and exit
+    #[arg(long)]
+    migrate_only: bool,
+
+    /// List pending schema migrations without applying them, then exit
+    #[arg(long)]
+    dry_run: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage API keys
+    Keys {
+        #[command(subcommand)]
+        action: KeyCommand,
+    },
+    /// Carry events to and from air-gapped sites
+    Bundle {
+        #[command(subcommand)]
+        action: BundleCommand,
+    },
+    /// Inspect and clean up sync queues
+    Outbox {
+        #[command(subcommand)]
+        action: OutboxCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum OutboxCommand {
+    /// List queues, including those of destinations no longer configured
+    List,
+    /// Drop the queue of a destination that is no longer configured
+    Purge {
+        /// Destination name (from `outbox list`)
+        destination: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum BundleCommand {
+    /// Write queued events to a bundle file and mark them as exported
+    Export {
+        /// Bundle file to write
+        out: PathBuf,
+
+        /// Destination queue to export (default: the only bundle destination)
+        #[arg(long)]
+        destination: Option<String>,
+
+        /// Also export events already in an earlier bundle
+        #[arg(long)]
+        include_exported: bool,
+
+        /// Include referenced file:// attachments
+        #[arg(long)]
+        media: bool,
+
+        /// File with a shared secret to sign the bundle with
+        #[arg(long)]
+        key: Option<PathBuf>,
+    },
+    /// Store a bundle's events and write a receipt for the exporting agent
+    Import {
+        /// Bundle file to import
+        bundle: PathBuf,
+
+        /// Receipt file to write (default: <bundle>.receipt.json)
+        #[arg(long)]
+        receipt: Option<PathBuf>,
+
+        /// File with the shared secret; unsigned bundles are refused
+        #[arg(long)]
+        key: Option<PathBuf>,
+
+        /// Import a signed bundle without a key, leaving its signature unchecked
+        #[arg(long, conflicts_with = "key")]
+        allow_unverified: bool,
+    },
+    /// Apply an import receipt: accepted events leave the queue
+    Receipt {
+        /// Receipt file from `bundle import`
+        receipt: PathBuf,
+
+        /// File with the shared secret; unsigned receipts are refused
+        #[arg(long)]
+        key: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum KeyCommand {
+    /// Create a key and print its token (shown only once)
+    Create {
+        /// Label for the key
+        #[arg(long)]
+        name: String,
+
+        /// Scope: ingest, read, relay or admin
+        #[arg(long, default_value = "ingest")]
+        scope: auth::Scope,
+
+        /// Public ingest key for embedding in web pages
+        #[arg(long)]
+        public: bool,
+
+        /// Allowed Origin for a public key (repeatable)
+        #[arg(long = "origin")]
+        origins: Vec<String>,
+    },
+    /// Revoke a key by id
+    Revoke {
+        /// Key id (from `keys list`)
+        id: String,
+    },
+    /// List keys
+    List,
 }
 
 #[tokio::main]
@@ -49,46 +186,337 @@ async fn main() -> Result<()> {
     FmtSubscriber::builder()
         .with_max_level(level)
         .with_target(false)
+        .with_writer(std::io::stderr)
         .compact()
         .init();
 
     info!("EdgeKite v{}", env!("CARGO_PKG_VERSION"));
 
     // Load configuration
-    let mut config = Config::load(&args.config)?;
-
-    // Apply CLI overrides
-    if let Some(data_dir) = args.data_dir {
-        config.data_dir = data_dir;
-    }
-    if let Some(listen) = args.listen {
-        config.server.listen = listen;
-    }
+    let config = load_config(&args.config, args.data_dir.as_ref(), args.listen.as_ref())?;
 
     info!("Data directory: {:?}", config.data_dir);
     info!("Listening on: {}", config.server.listen);
 
     // Initialize database
-    let db_path = config.data_dir.join("events.db");
+    let db_path = config.db_path();
     let db = db::Database::open(&db_path)?;
+
+    if args.dry_run {
+        let pending = db.pending_migrations()?;
+        info!(
+            "Schema version {}, latest {}, {} pending migration(s)",
+            db.schema_version()?,
+            migrations::latest_version(),
+            pending.len()
+        );
+        for migration in pending {
+            info!(
+                "  would apply {}: {}",
+                migration.version, migration.description
+            );
+        }
+        return Ok(());
+    }
+
     db.migrate()?;
+    info!("Schema version {}", db.schema_version()?);
+
+    if args.migrate_only {
+        return Ok(());
+    }
 
-    // Start sync worker (if enabled)
-    let sync_handle = if config.sync.enabled {
-        info!("Sync enabled, hub: {}", config.sync.hub_url);
-        Some(sync::start_worker(db.clone(), config.sync.clone()))
-    } else {
-        info!("Sync disabled (offline mode)");
-        None
+    if let Some(Command::Keys { action }) = args.command {
+        return run_key_command(&db, action);
+    }
+
+    if let Some(Command::Outbox { action }) = args.command {
+        let destinations = destination::Destination::from_config(&config.sync)?;
+        db.set_destinations(&destinations)?;
+        return run_outbox_command(&db, action);
+    }
+
+    if let Some(Command::Bundle { action }) = args.command {
+        let destinations = destination::Destination::from_config(&config.sync)?;
+        db.set_destinations(&destinations)?;
+        return run_bundle_command(&db, &config, &destinations, action);
+    }
+
+    // Queue routing is in place before the server accepts events
+    let (destinations, schedule) = sync::configure(&db, &config.sync)?;
+    let metrics = Arc::new(metrics::Metrics::new());
+    let sync_state = sync::SyncState::new(&config.sync, &destinations);
+    let shutdown = shutdown::Shutdown::new();
+    let reloader = reload::Reloader::new(config.clone(), move || {
+        load_config(&args.config, args.data_dir.as_ref(), args.listen.as_ref())
+    });
+    reload::reload_on_sighup(reloader.clone());
+
+    // Start sync workers (restarted when [sync] is reloaded)
+    let mut sync_handle = tokio::spawn(sync::supervise(
+        db.clone(),
+        reloader.subscribe(),
+        destinations,
+        schedule,
+        sync_state.clone(),
+        metrics.clone(),
+        shutdown.clone(),
+    ));
+
+    // Start retention cleanup worker
+    info!(
+        "Retention: {} days (short {}, long {}), cleanup at {:02}:00",
+        config.retention.events_days,
+        config.retention.short_days,
+        config.retention.long_days,
+        config.retention.cleanup_hour
+    );
+    let retention_handle = retention::start_worker(db.clone(), reloader.subscribe());
+
+    // Load ingest validation rules
+    let validator = validate::Validator::load(&config.validation, &config.data_dir)?;
+    info!("Validation mode: {:?}", config.validation.mode);
+
+    // Start HTTP server; it drains in-flight requests once shutdown triggers
+    let server = server::run(
+        reloader.clone(),
+        db.clone(),
+        validator,
+        metrics,
+        sync_state,
+        shutdown.clone(),
+    );
+    tokio::pin!(server);
+    let deadline = tokio::select! {
+        result = &mut server => {
+            // Only returns early on a listener error
+            result?;
+            return Ok(());
+        }
+        signal = shutdown::signal() => {
+            let deadline =
+                Duration::from_secs(reloader.current().server.shutdown_timeout_seconds);
+            info!("Received {}, shutting down (deadline {}s)", signal, deadline.as_secs());
+            shutdown.trigger();
+            deadline
+        }
     };
 
-    // Start HTTP server
-    server::run(config.server, db, db_path).await?;
+    // Let the server drain and the sync workers flush, up to the deadline
+    retention_handle.abort();
+    let drain = async {
+        if let Err(e) = server.await {
+            warn!("Server error during shutdown: {}", e);
+        }
+        let _ = (&mut sync_handle).await;
+    };
+    if tokio::time::timeout(deadline, drain).await.is_err() {
+        warn!("Shutdown deadline reached; abandoning unfinished sync batches (they stay queued)");
+        sync_handle.abort();
+        let _ = sync_handle.await;
+    }
 
-    // Cleanup
-    if let Some(handle) = sync_handle {
-        handle.abort();
+    match db.checkpoint() {
+        Ok(true) => info!("WAL checkpointed"),
+        Ok(false) => warn!("WAL checkpoint incomplete (database busy)"),
+        Err(e) => warn!("WAL checkpoint failed: {}", e),
     }
+    info!("Shutdown complete");
+
+    Ok(())
+}
 
+/// Load the configuration file and apply CLI overrides
+fn load_config(path: &Path, data_dir: Option<&PathBuf>, listen: Option<&String>) -> Result<Config> {
+    let mut config = Config::load(path)?;
+    if let Some(data_dir) = data_dir {
+        config.data_dir = data_dir.clone();
+    }
+    if let Some(listen) = listen {
+        config.server.listen = listen.clone();
+    }
+    Ok(config)
+}
+
+/// Handle `edge-kite bundle ...`
+fn run_bundle_command(
+    db: &db::Database,
+    config: &Config,
+    destinations: &[destination::Destination],
+    action: BundleCommand,
+) -> Result<()> {
+    let load_key = |path: Option<PathBuf>| path.as_deref().map(bundle::load_key).transpose();
+
+    match action {
+        BundleCommand::Export {
+            out,
+            destination,
+            include_exported,
+            media,
+            key,
+        } => {
+            let destination = match destination {
+                Some(name) if destinations.iter().any(|d| d.name == name) => name,
+                Some(name) => {
+                    return Err(error::Error::Bundle(format!(
+                        "no destination named '{}' is configured",
+                        name
+                    )))
+                }
+                None => {
+                    let mut bundled = destinations
+                        .iter()
+                        .filter(|d| d.transport == config::Transport::Bundle);
+                    match (bundled.next(), bundled.next()) {
+                        (Some(only), None) => only.name.clone(),
+                        _ => return Err(error::Error::Bundle(
+                            "specify --destination (there is not exactly one bundle destination)"
+                                .to_string(),
+                        )),
+                    }
+                }
+            };
+            let options = bundle::ExportOptions {
+                include_exported,
+                media: media.then(|| config.media_dir()),
+            };
+            let key = load_key(key)?;
+            match bundle::export(db, &destination, &out, &options, key.as_deref())? {
+                Some(summary) => println!(
+                    "Exported {} events and {} media files for '{}' to {} (bundle {})",
+                    summary.events,
+                    summary.media_files,
+                    destination,
+                    out.display(),
+                    summary.bundle_id
+                ),
+                None => println!("Nothing to export for '{}'", destination),
+            }
+        }
+        BundleCommand::Import {
+            bundle: path,
+            receipt,
+            key,
+            allow_unverified,
+        } => {
+            let key = load_key(key)?;
+            let validator = validate::Validator::load(&config.validation, &config.data_dir)?;
+            let summary = bundle::import(
+                db,
+                &validator,
+                &path,
+                &config.media_dir(),
+                key.as_deref(),
+                allow_unverified,
+            )?;
+            let receipt_path = receipt.unwrap_or_else(|| {
+                let mut name = path.clone().into_os_string();
+                name.push(".receipt.json");
+                PathBuf::from(name)
+            });
+            summary.receipt.save(&receipt_path, key.as_deref())?;
+            println!(
+                "Imported bundle {}: {} new, {} already present, {} rejected, {} media files",
+                summary.receipt.bundle_id,
+                summary.stored,
+                summary.receipt.accepted.len() - summary.stored,
+                summary.receipt.rejected.len(),
+                summary.media_files
+            );
+            println!(
+                "Carry {} back and run `edge-kite bundle receipt` there",
+                receipt_path.display()
+            );
+        }
+        BundleCommand::Receipt { receipt, key } => {
+            let key = load_key(key)?;
+            let receipt = bundle::Receipt::load(&receipt, key.as_deref())?;
+            let summary = bundle::apply_receipt(db, &receipt, config.sync.max_reject_attempts)?;
+            println!(
+                "Bundle {} for '{}': {} events synced, {} rejected ({} now dead letters)",
+                receipt.bundle_id,
+                receipt.destination,
+                summary.synced,
+                receipt.rejected.len(),
+                summary.dead_lettered
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Handle `edge-kite outbox ...`
+fn run_outbox_command(db: &db::Database, action: OutboxCommand) -> Result<()> {
+    match action {
+        OutboxCommand::List => {
+            for count in db.outbox_counts()? {
+                let status = if count.configured {
+                    "configured"
+                } else {
+                    "unconfigured"
+                };
+                println!(
+                    "{:<12} pending={:<8} dead_letters={:<8} {}",
+                    status, count.pending, count.dead_letters, count.destination
+                );
+            }
+        }
+        OutboxCommand::Purge { destination } => {
+            let configured = db
+                .outbox_counts()?
+                .iter()
+                .any(|c| c.destination == destination && c.configured);
+            if configured {
+                return Err(error::Error::Storage(format!(
+                    "destination '{}' is still configured; remove it from [sync] first",
+                    destination
+                )));
+            }
+            let purged = db.purge_outbox(&destination)?;
+            println!("Dropped {} queued deliveries for '{}'", purged, destination);
+        }
+    }
+    Ok(())
+}
+
+/// Handle `edge-kite keys ...`
+fn run_key_command(db: &db::Database, action: KeyCommand) -> Result<()> {
+    match action {
+        KeyCommand::Create {
+            name,
+            scope,
+            public,
+            origins,
+        } => {
+            let (key, token) = auth::create_key(db, &name, scope, public, origins)?;
+            println!("Created {} key '{}' (id {})", key.scope, key.name, key.id);
+            println!("{}", token);
+            println!("Store this token now; it cannot be shown again.");
+        }
+        KeyCommand::Revoke { id } => {
+            if db.revoke_api_key(&id)? {
+                println!("Revoked key {}", id);
+            } else {
+                println!("No active key with id {}", id);
+            }
+        }
+        KeyCommand::List => {
+            for (key, revoked_at) in db.list_api_keys()? {
+                let status = if revoked_at.is_some() {
+                    "revoked"
+                } else {
+                    "active"
+                };
+                let origins = key
+                    .allowed_origins
+                    .map(|o| o.join(","))
+                    .unwrap_or_else(|| "-".to_string());
+                println!(
+                    "{}  {:<7} {:<7} public={:<5} origins={}  {}",
+                    key.id, key.scope, status, key.public, origins, key.name
+                );
+            }
+        }
+    }
     Ok(())
 }
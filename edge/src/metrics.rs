@@ -0,0 +1,552 @@
+//! Prometheus / OpenMetrics instrumentation for EdgeKite
+//!
+//! Counters and histograms are updated as events flow through ingest and
+//! sync; gauges describing current state (backlog, file sizes, CPU/RAM) are
+//! sampled when `/metrics` is scraped.
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use chrono::Utc;
+use prometheus_client::encoding::text::encode;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::{Registry, Unit};
+use std::collections::HashSet;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::compression::Encoding;
+use crate::db::OutboxCount;
+use crate::event::{Event, CATEGORIES, SEVERITIES};
+
+/// Content type of the text exposition format produced by `encode`
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Label value for client-supplied values outside the known set
+const OTHER: &str = "other";
+
+/// Distinct event types with their own series; later ones count as `OTHER`
+const MAX_EVENT_TYPES: usize = 100;
+
+/// `value` if it is one of `known`, else `OTHER`
+///
+/// Labels on event metrics come from client input; folding the unexpected
+/// keeps a misbehaving client from creating unbounded series.
+fn bounded(known: &[&'static str], value: &str) -> &'static str {
+    known
+        .iter()
+        .find(|k| **k == value)
+        .copied()
+        .unwrap_or(OTHER)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct EventLabels {
+    category: &'static str,
+    r#type: String,
+    severity: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct ReasonLabels {
+    reason: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct SeverityLabels {
+    severity: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct RequestLabels {
+    endpoint: String,
+    status: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct EndpointLabels {
+    endpoint: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct DestinationLabels {
+    destination: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct EncodingLabels {
+    destination: String,
+    encoding: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct ResultLabels {
+    destination: String,
+    result: &'static str,
+}
+
+/// Why an event was not stored
+#[derive(Debug, Clone, Copy)]
+pub enum Rejection {
+    /// Not a parseable event envelope
+    Malformed,
+    /// Failed envelope or payload schema validation
+    Invalid,
+    /// Over its source's rate limit
+    RateLimited,
+    /// SQLite refused the write
+    Storage,
+    /// Refused or dropped because the ingest queue was full
+    QueueFull,
+}
+
+impl Rejection {
+    fn as_str(self) -> &'static str {
+        match self {
+            Rejection::Malformed => "malformed",
+            Rejection::Invalid => "invalid",
+            Rejection::RateLimited => "rate_limited",
+            Rejection::Storage => "storage",
+            Rejection::QueueFull => "queue_full",
+        }
+    }
+}
+
+/// Point-in-time values gathered by the `/metrics` handler
+#[derive(Debug, Default)]
+pub struct Sampled {
+    pub outbox: Vec<OutboxCount>,
+    pub db_bytes: u64,
+    pub wal_bytes: u64,
+    pub cpu_percent: f32,
+    pub memory_bytes: u64,
+    pub ingest_queue_depth: usize,
+}
+
+/// All metrics exported by the agent
+pub struct Metrics {
+    registry: Registry,
+    events_ingested: Family<EventLabels, Counter>,
+    /// Types given their own `events_ingested` series so far
+    event_types: Mutex<HashSet<String>>,
+    events_duplicate: Counter,
+    events_rejected: Family<ReasonLabels, Counter>,
+    ingest_requests: Family<RequestLabels, Counter>,
+    ingest_duration: Family<EndpointLabels, Histogram>,
+    ingest_queue_depth: Gauge,
+    ingest_queue_dropped: Family<SeverityLabels, Counter>,
+    sync_batches: Family<ResultLabels, Counter>,
+    sync_events: Family<DestinationLabels, Counter>,
+    sync_rejected: Family<DestinationLabels, Counter>,
+    sync_uncompressed: Family<EncodingLabels, Counter>,
+    sync_sent: Family<EncodingLabels, Counter>,
+    sync_last_success: Family<DestinationLabels, Gauge<f64, AtomicU64>>,
+    pending_sync: Family<DestinationLabels, Gauge>,
+    dead_letters: Family<DestinationLabels, Gauge>,
+    db_size: Gauge,
+    wal_size: Gauge,
+    cpu_usage: Gauge<f64, AtomicU64>,
+    memory: Gauge,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let mut registry = Registry::with_prefix("edgekite");
+
+        let events_ingested = Family::<EventLabels, Counter>::default();
+        registry.register(
+            "events_ingested",
+            "Events stored, by category, type and severity",
+            events_ingested.clone(),
+        );
+        let events_duplicate = Counter::default();
+        registry.register(
+            "events_duplicate",
+            "Events skipped because their event_id was already stored",
+            events_duplicate.clone(),
+        );
+        let events_rejected = Family::<ReasonLabels, Counter>::default();
+        registry.register(
+            "events_rejected",
+            "Events not stored, by reason",
+            events_rejected.clone(),
+        );
+        let ingest_requests = Family::<RequestLabels, Counter>::default();
+        registry.register(
+            "ingest_requests",
+            "Ingest HTTP requests, by endpoint and response status",
+            ingest_requests.clone(),
+        );
+        let ingest_duration = Family::<EndpointLabels, Histogram>::new_with_constructor(
+            ingest_histogram as fn() -> _,
+        );
+        registry.register_with_unit(
+            "ingest_request_duration",
+            "Time to handle an ingest request",
+            Unit::Seconds,
+            ingest_duration.clone(),
+        );
+        let ingest_queue_depth = Gauge::default();
+        registry.register(
+            "ingest_queue_depth",
+            "Events waiting in the ingest queue to be stored",
+            ingest_queue_depth.clone(),
+        );
+        let ingest_queue_dropped = Family::<SeverityLabels, Counter>::default();
+        registry.register(
+            "ingest_queue_dropped",
+            "Events refused or dropped because the ingest queue was full, by severity",
+            ingest_queue_dropped.clone(),
+        );
+        let sync_batches = Family::<ResultLabels, Counter>::default();
+        registry.register(
+            "sync_batches",
+            "Batches sent to each destination, by result",
+            sync_batches.clone(),
+        );
+        let sync_events = Family::<DestinationLabels, Counter>::default();
+        registry.register(
+            "sync_events",
+            "Events acknowledged by each destination",
+            sync_events.clone(),
+        );
+        let sync_rejected = Family::<DestinationLabels, Counter>::default();
+        registry.register(
+            "sync_rejected",
+            "Events each destination rejected",
+            sync_rejected.clone(),
+        );
+        let sync_uncompressed = Family::<EncodingLabels, Counter>::default();
+        registry.register_with_unit(
+            "sync_uncompressed",
+            "Sync upload size before compression, by destination and encoding",
+            Unit::Bytes,
+            sync_uncompressed.clone(),
+        );
+        let sync_sent = Family::<EncodingLabels, Counter>::default();
+        registry.register_with_unit(
+            "sync_sent",
+            "Sync upload size on the wire, by destination and encoding",
+            Unit::Bytes,
+            sync_sent.clone(),
+        );
+        let sync_last_success = Family::<DestinationLabels, Gauge<f64, AtomicU64>>::default();
+        registry.register(
+            "sync_last_success_timestamp_seconds",
+            "Unix time of each destination's last successful sync batch",
+            sync_last_success.clone(),
+        );
+        let pending_sync = Family::<DestinationLabels, Gauge>::default();
+        registry.register(
+            "sync_pending_events",
+            "Events queued for each destination",
+            pending_sync.clone(),
+        );
+        let dead_letters = Family::<DestinationLabels, Gauge>::default();
+        registry.register(
+            "sync_dead_letters",
+            "Events no longer sent to each destination after repeated rejections",
+            dead_letters.clone(),
+        );
+        let db_size = Gauge::default();
+        registry.register_with_unit(
+            "db_size",
+            "SQLite database file size",
+            Unit::Bytes,
+            db_size.clone(),
+        );
+        let wal_size = Gauge::default();
+        registry.register_with_unit(
+            "db_wal_size",
+            "SQLite write-ahead log file size",
+            Unit::Bytes,
+            wal_size.clone(),
+        );
+        let cpu_usage = Gauge::<f64, AtomicU64>::default();
+        registry.register(
+            "cpu_usage_percent",
+            "CPU usage averaged across cores",
+            cpu_usage.clone(),
+        );
+        let memory = Gauge::default();
+        registry.register_with_unit(
+            "process_resident_memory",
+            "Resident memory of the agent process",
+            Unit::Bytes,
+            memory.clone(),
+        );
+
+        Self {
+            registry,
+            events_ingested,
+            event_types: Mutex::new(HashSet::new()),
+            events_duplicate,
+            events_rejected,
+            ingest_requests,
+            ingest_duration,
+            ingest_queue_depth,
+            ingest_queue_dropped,
+            sync_batches,
+            sync_events,
+            sync_rejected,
+            sync_uncompressed,
+            sync_sent,
+            sync_last_success,
+            pending_sync,
+            dead_letters,
+            db_size,
+            wal_size,
+            cpu_usage,
+            memory,
+        }
+    }
+
+    /// A newly stored event
+    pub fn event_ingested(&self, event: &Event) {
+        self.events_ingested
+            .get_or_create(&EventLabels {
+                category: bounded(&CATEGORIES, &event.event.category),
+                r#type: self.type_label(&event.event.event_type),
+                severity: bounded(&SEVERITIES, &event.event.severity),
+            })
+            .inc();
+    }
+
+    /// `event_type` while fewer than `MAX_EVENT_TYPES` are tracked, else `OTHER`
+    fn type_label(&self, event_type: &str) -> String {
+        let mut seen = self.event_types.lock().unwrap();
+        if seen.contains(event_type) {
+            return event_type.to_string();
+        }
+        if seen.len() < MAX_EVENT_TYPES {
+            seen.insert(event_type.to_string());
+            return event_type.to_string();
+        }
+        OTHER.to_string()
+    }
+
+    pub fn event_duplicate(&self) {
+        self.events_duplicate.inc();
+    }
+
+    pub fn event_rejected(&self, reason: Rejection) {
+        self.events_rejected
+            .get_or_create(&ReasonLabels {
+                reason: reason.as_str(),
+            })
+            .inc();
+    }
+
+    /// An event refused or dropped because the ingest queue was full
+    pub fn event_shed(&self, event: &Event) {
+        self.event_rejected(Rejection::QueueFull);
+        self.ingest_queue_dropped
+            .get_or_create(&SeverityLabels {
+                severity: bounded(&SEVERITIES, &event.event.severity),
+            })
+            .inc();
+    }
+
+    /// A batch the destination acknowledged, with the number of events marked synced
+    pub fn sync_succeeded(&self, destination: &str, events: usize) {
+        self.sync_batches
+            .get_or_create(&ResultLabels {
+                destination: destination.to_string(),
+                result: "success",
+            })
+            .inc();
+        let labels = DestinationLabels {
+            destination: destination.to_string(),
+        };
+        self.sync_events
+            .get_or_create(&labels)
+            .inc_by(events as u64);
+        self.sync_last_success
+            .get_or_create(&labels)
+            .set(Utc::now().timestamp_millis() as f64 / 1000.0);
+    }
+
+    pub fn sync_rejected(&self, destination: &str, events: usize) {
+        self.sync_rejected
+            .get_or_create(&DestinationLabels {
+                destination: destination.to_string(),
+            })
+            .inc_by(events as u64);
+    }
+
+    /// An upload of `raw` bytes sent as `sent` bytes with `encoding`
+    pub fn sync_bytes(&self, destination: &str, encoding: Encoding, raw: usize, sent: usize) {
+        let labels = EncodingLabels {
+            destination: destination.to_string(),
+            encoding: encoding.as_str(),
+        };
+        self.sync_uncompressed
+            .get_or_create(&labels)
+            .inc_by(raw as u64);
+        self.sync_sent.get_or_create(&labels).inc_by(sent as u64);
+    }
+
+    pub fn sync_failed(&self, destination: &str) {
+        self.sync_batches
+            .get_or_create(&ResultLabels {
+                destination: destination.to_string(),
+                result: "failure",
+            })
+            .inc();
+    }
+
+    /// Render all metrics in the OpenMetrics text format
+    pub fn encode(&self, sampled: &Sampled) -> String {
+        // Rebuilt on every scrape so removed destinations disappear
+        self.pending_sync.clear();
+        self.dead_letters.clear();
+        for count in &sampled.outbox {
+            let labels = DestinationLabels {
+                destination: count.destination.clone(),
+            };
+            self.pending_sync.get_or_create(&labels).set(count.pending);
+            self.dead_letters
+                .get_or_create(&labels)
+                .set(count.dead_letters);
+        }
+        self.db_size.set(sampled.db_bytes as i64);
+        self.wal_size.set(sampled.wal_bytes as i64);
+        self.cpu_usage.set(sampled.cpu_percent as f64);
+        self.memory.set(sampled.memory_bytes as i64);
+        self.ingest_queue_depth
+            .set(sampled.ingest_queue_depth as i64);
+
+        let mut out = String::new();
+        encode(&mut out, &self.registry).expect("writing to a String cannot fail");
+        out
+    }
+}
+
+/// 0.5 ms to ~4 s
+fn ingest_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(0.0005, 2.0, 14))
+}
+
+/// Record the duration and response status of ingest requests
+pub async fn track_ingest(
+    State(metrics): State<Arc<Metrics>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let endpoint = req.uri().path().to_string();
+    let started = Instant::now();
+    let response = next.run(req).await;
+
+    metrics
+        .ingest_duration
+        .get_or_create(&EndpointLabels {
+            endpoint: endpoint.clone(),
+        })
+        .observe(started.elapsed().as_secs_f64());
+    metrics
+        .ingest_requests
+        .get_or_create(&RequestLabels {
+            endpoint,
+            status: response.status().as_u16(),
+        })
+        .inc();
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::test_support::test_event;
+
+    #[test]
+    fn test_encode() {
+        let metrics = Metrics::new();
+        let event = test_event("person_detected").category("iot").build();
+
+        metrics.event_ingested(&event);
+        metrics.event_ingested(&event);
+        // Unvalidated client input does not mint new series
+        let mut odd = event.clone();
+        odd.event.category = "x-1".to_string();
+        odd.event.severity = "loud".to_string();
+        metrics.event_ingested(&odd);
+        metrics.event_shed(&odd);
+        metrics.event_rejected(Rejection::Invalid);
+        metrics.event_shed(&event);
+        metrics.sync_succeeded("hub", 2);
+        metrics.sync_failed("backup");
+
+        let text = metrics.encode(&Sampled {
+            outbox: vec![OutboxCount {
+                destination: "hub".to_string(),
+                pending: 7,
+                dead_letters: 0,
+                configured: true,
+            }],
+            wal_bytes: 4096,
+            ingest_queue_depth: 3,
+            ..Default::default()
+        });
+        assert!(text.contains(
+            r#"edgekite_events_ingested_total{category="iot",type="person_detected",severity="info"} 2"#
+        ));
+        assert!(text.contains(
+            r#"edgekite_events_ingested_total{category="other",type="person_detected",severity="other"} 1"#
+        ));
+        assert!(text.contains(r#"edgekite_events_rejected_total{reason="invalid"} 1"#));
+        assert!(text.contains(r#"edgekite_events_rejected_total{reason="queue_full"} 2"#));
+        assert!(text.contains(r#"edgekite_ingest_queue_dropped_total{severity="info"} 1"#));
+        assert!(text.contains(r#"edgekite_ingest_queue_dropped_total{severity="other"} 1"#));
+        assert!(text.contains("edgekite_ingest_queue_depth 3"));
+        assert!(text
+            .contains(r#"edgekite_sync_batches_total{destination="backup",result="failure"} 1"#));
+        assert!(text.contains(r#"edgekite_sync_events_total{destination="hub"} 2"#));
+        assert!(text.contains(r#"edgekite_sync_pending_events{destination="hub"} 7"#));
+        assert!(text.contains("edgekite_db_wal_size_bytes 4096"));
+        assert!(text.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn test_event_types_are_capped() {
+        let metrics = Metrics::new();
+        let mut event: Event = serde_json::from_value(serde_json::json!({
+            "event_id": "e-1",
+            "observed_at": Utc::now(),
+            "received_at": Utc::now(),
+            "source": {"type": "browser", "id": "s"},
+            "event": {"category": "web", "type": "t", "severity": "info", "data": {}}
+        }))
+        .unwrap();
+        for i in 0..MAX_EVENT_TYPES {
+            event.event.event_type = format!("type_{}", i);
+            metrics.event_ingested(&event);
+        }
+        event.event.event_type = "one_too_many".to_string();
+        metrics.event_ingested(&event);
+        // Types already tracked keep their series
+        event.event.event_type = "type_0".to_string();
+        metrics.event_ingested(&event);
+
+        let text = metrics.encode(&Sampled::default());
+        assert!(!text.contains("one_too_many"));
+        assert!(text.contains(
+            r#"edgekite_events_ingested_total{category="web",type="other",severity="info"} 1"#
+        ));
+        assert!(text.contains(
+            r#"edgekite_events_ingested_total{category="web",type="type_0",severity="info"} 2"#
+        ));
+    }
+}
@@ -0,0 +1,354 @@
+//! Schema migrations for EdgeKite
+//!
+//! Migrations are applied in order and tracked with `PRAGMA user_version`.
+//! Never edit a released migration; append a new one instead.
+
+/// A single schema change
+pub struct Migration {
+    /// Schema version after this migration is applied
+    pub version: u32,
+    /// Short human-readable summary, shown in dry runs
+    pub description: &'static str,
+    /// SQL applied inside the migration transaction
+    pub sql: &'static str,
+}
+
+/// All migrations, in version order
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        // IF NOT EXISTS so databases created before versioning adopt cleanly
+        sql: r#"
+            -- Core events table
+            CREATE TABLE IF NOT EXISTS events (
+                event_id TEXT PRIMARY KEY,
+                observed_at INTEGER NOT NULL,
+                received_at INTEGER NOT NULL,
+                source_type TEXT NOT NULL,
+                source_id TEXT NOT NULL,
+                source_seq INTEGER,
+                category TEXT NOT NULL,
+                type TEXT NOT NULL,
+                severity TEXT NOT NULL DEFAULT 'info',
+                correlation_id TEXT,
+                payload_json TEXT NOT NULL,
+                attachments_json TEXT,
+                pii INTEGER NOT NULL DEFAULT 0,
+                retention_class TEXT NOT NULL DEFAULT 'standard',
+                synced INTEGER NOT NULL DEFAULT 0
+            );
+
+            -- Indexes for common queries
+            CREATE INDEX IF NOT EXISTS idx_events_observed ON events(observed_at);
+            CREATE INDEX IF NOT EXISTS idx_events_type_observed ON events(type, observed_at);
+            CREATE INDEX IF NOT EXISTS idx_events_source_observed ON events(source_id, observed_at);
+            CREATE INDEX IF NOT EXISTS idx_events_synced ON events(synced, observed_at);
+            CREATE INDEX IF NOT EXISTS idx_events_category ON events(category, observed_at);
+            CREATE INDEX IF NOT EXISTS idx_events_received ON events(received_at, event_id);
+
+            -- Rollup table for Tier 1+ analytics
+            CREATE TABLE IF NOT EXISTS hourly_counts (
+                hour_bucket INTEGER NOT NULL,
+                source_id TEXT NOT NULL,
+                type TEXT NOT NULL,
+                count INTEGER NOT NULL,
+                PRIMARY KEY (hour_bucket, source_id, type)
+            );
+
+            -- Agent configuration
+            CREATE TABLE IF NOT EXISTS config (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            );
+        "#,
+    },
+    Migration {
+        version: 2,
+        description: "store full event envelope",
+        sql: r#"
+            ALTER TABLE events ADD COLUMN session_id TEXT;
+            ALTER TABLE events ADD COLUMN incident_id TEXT;
+            ALTER TABLE events ADD COLUMN schema_version TEXT;
+            ALTER TABLE events ADD COLUMN source_version TEXT;
+            ALTER TABLE events ADD COLUMN source_metadata_json TEXT;
+
+            CREATE INDEX idx_events_session ON events(session_id, observed_at);
+            CREATE INDEX idx_events_incident ON events(incident_id, observed_at);
+        "#,
+    },
+    Migration {
+        version: 3,
+        description: "api keys",
+        sql: r#"
+            CREATE TABLE api_keys (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                key_hash TEXT NOT NULL UNIQUE,
+                scope TEXT NOT NULL,
+                public INTEGER NOT NULL DEFAULT 0,
+                allowed_origins_json TEXT,
+                created_at INTEGER NOT NULL,
+                revoked_at INTEGER
+            );
+        "#,
+    },
+    Migration {
+        version: 4,
+        description: "sync attempts and dead letters",
+        sql: r#"
+            ALTER TABLE events ADD COLUMN sync_attempts INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE events ADD COLUMN sync_error TEXT;
+            ALTER TABLE events ADD COLUMN dead_lettered_at INTEGER;
+
+            CREATE INDEX idx_events_dead_letter ON events(dead_lettered_at, observed_at)
+                WHERE dead_lettered_at IS NOT NULL;
+        "#,
+    },
+    Migration {
+        version: 5,
+        description: "prioritized sync outbox",
+        // Pending events are re-ranked on startup by `Database::set_priority_lane`
+        sql: r#"
+            ALTER TABLE events ADD COLUMN sync_priority INTEGER NOT NULL DEFAULT 4;
+
+            CREATE INDEX idx_events_outbox ON events(sync_priority, source_id, observed_at)
+                WHERE synced = 0 AND dead_lettered_at IS NULL;
+        "#,
+    },
+    Migration {
+        version: 6,
+        description: "per-destination outbox",
+        // The single-hub backlog moves to the `default` destination, which is
+        // what a top-level `sync.hub_url` is called
+        sql: r#"
+            CREATE TABLE outbox (
+                destination TEXT NOT NULL,
+                event_id TEXT NOT NULL,
+                sync_priority INTEGER NOT NULL,
+                source_id TEXT NOT NULL,
+                observed_at INTEGER NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                error TEXT,
+                dead_lettered_at INTEGER,
+                PRIMARY KEY (destination, event_id)
+            );
+
+            INSERT INTO outbox (
+                destination, event_id, sync_priority, source_id, observed_at,
+                attempts, error, dead_lettered_at
+            )
+            SELECT 'default', event_id, sync_priority, source_id, observed_at,
+                sync_attempts, sync_error, dead_lettered_at
+            FROM events WHERE synced = 0;
+
+            CREATE INDEX idx_outbox_pending
+                ON outbox(destination, sync_priority, source_id, observed_at)
+                WHERE dead_lettered_at IS NULL;
+            CREATE INDEX idx_outbox_event ON outbox(event_id);
+
+            -- Deleted events leave the outbox with them
+            CREATE TRIGGER events_delete_outbox AFTER DELETE ON events BEGIN
+                DELETE FROM outbox WHERE event_id = old.event_id;
+            END;
+
+            DROP INDEX idx_events_synced;
+            DROP INDEX idx_events_dead_letter;
+            DROP INDEX idx_events_outbox;
+            ALTER TABLE events DROP COLUMN synced;
+            ALTER TABLE events DROP COLUMN sync_attempts;
+            ALTER TABLE events DROP COLUMN sync_error;
+            ALTER TABLE events DROP COLUMN dead_lettered_at;
+            ALTER TABLE events DROP COLUMN sync_priority;
+        "#,
+    },
+    Migration {
+        version: 7,
+        description: "sneakernet bundle exports",
+        sql: r#"
+            ALTER TABLE outbox ADD COLUMN exported_at INTEGER;
+            ALTER TABLE outbox ADD COLUMN bundle_id TEXT;
+        "#,
+    },
+    Migration {
+        version: 8,
+        description: "hourly and daily rollups",
+        // Triggers keep the rollups equal to the stored events: duplicates
+        // are never inserted, and retention deletes take their counts along
+        sql: r#"
+            DROP TABLE hourly_counts;
+            CREATE TABLE hourly_counts (
+                hour_bucket INTEGER NOT NULL,  -- observed_at in whole Unix hours
+                category TEXT NOT NULL,
+                type TEXT NOT NULL,
+                severity TEXT NOT NULL,
+                source_type TEXT NOT NULL,
+                source_id TEXT NOT NULL,
+                count INTEGER NOT NULL,
+                PRIMARY KEY (hour_bucket, category, type, severity, source_type, source_id)
+            ) WITHOUT ROWID;
+
+            CREATE TABLE daily_counts (
+                day_bucket INTEGER NOT NULL,   -- observed_at in whole Unix days (UTC)
+                category TEXT NOT NULL,
+                type TEXT NOT NULL,
+                severity TEXT NOT NULL,
+                source_type TEXT NOT NULL,
+                source_id TEXT NOT NULL,
+                count INTEGER NOT NULL,
+                PRIMARY KEY (day_bucket, category, type, severity, source_type, source_id)
+            ) WITHOUT ROWID;
+
+            INSERT INTO hourly_counts
+            SELECT observed_at / 3600000, category, type, severity, source_type, source_id, COUNT(*)
+            FROM events
+            GROUP BY 1, 2, 3, 4, 5, 6;
+
+            INSERT INTO daily_counts
+            SELECT hour_bucket / 24, category, type, severity, source_type, source_id, SUM(count)
+            FROM hourly_counts
+            GROUP BY 1, 2, 3, 4, 5, 6;
+
+            CREATE TRIGGER events_insert_rollup AFTER INSERT ON events BEGIN
+                INSERT INTO hourly_counts
+                VALUES (new.observed_at / 3600000, new.category, new.type, new.severity,
+                    new.source_type, new.source_id, 1)
+                ON CONFLICT (hour_bucket, category, type, severity, source_type, source_id)
+                DO UPDATE SET count = count + 1;
+                INSERT INTO daily_counts
+                VALUES (new.observed_at / 86400000, new.category, new.type, new.severity,
+                    new.source_type, new.source_id, 1)
+                ON CONFLICT (day_bucket, category, type, severity, source_type, source_id)
+                DO UPDATE SET count = count + 1;
+            END;
+
+            CREATE TRIGGER events_delete_rollup AFTER DELETE ON events BEGIN
+                UPDATE hourly_counts SET count = count - 1
+                WHERE hour_bucket = old.observed_at / 3600000 AND category = old.category
+                    AND type = old.type AND severity = old.severity
+                    AND source_type = old.source_type AND source_id = old.source_id;
+                DELETE FROM hourly_counts
+                WHERE hour_bucket = old.observed_at / 3600000 AND count <= 0;
+                UPDATE daily_counts SET count = count - 1
+                WHERE day_bucket = old.observed_at / 86400000 AND category = old.category
+                    AND type = old.type AND severity = old.severity
+                    AND source_type = old.source_type AND source_id = old.source_id;
+                DELETE FROM daily_counts
+                WHERE day_bucket = old.observed_at / 86400000 AND count <= 0;
+            END;
+        "#,
+    },
+    Migration {
+        version: 9,
+        description: "page view dimensions",
+        // Web fields pulled out of the JSON once, so top-N queries scan a
+        // narrow table instead of parsing every payload. The view is the one
+        // definition of the extraction, used for the backfill and the trigger.
+        sql: r#"
+            CREATE VIEW page_view_dimensions AS
+            SELECT observed_at, event_id, path,
+                COALESCE(referrer_domain, NULLIF(lower(substr(rest, 1, min(
+                    instr(rest || '/', '/'), instr(rest || '?', '?'),
+                    instr(rest || '#', '#'), instr(rest || ':', ':')
+                ) - 1)), '')) AS referrer_host,
+                utm_source, utm_medium, utm_campaign, device_type, browser
+            FROM (
+                SELECT observed_at, event_id, path, referrer_domain,
+                    CASE WHEN instr(referrer, '://') > 0
+                        THEN substr(referrer, instr(referrer, '://') + 3) END AS rest,
+                    utm_source, utm_medium, utm_campaign, device_type, browser
+                FROM (
+                    SELECT observed_at, event_id,
+                        json_extract(payload_json, '$.path') AS path,
+                        json_extract(payload_json, '$.referrer') AS referrer,
+                        json_extract(source_metadata_json, '$.referrer_domain') AS referrer_domain,
+                        COALESCE(json_extract(payload_json, '$.utm_source'),
+                            json_extract(source_metadata_json, '$.utm.source')) AS utm_source,
+                        COALESCE(json_extract(payload_json, '$.utm_medium'),
+                            json_extract(source_metadata_json, '$.utm.medium')) AS utm_medium,
+                        COALESCE(json_extract(payload_json, '$.utm_campaign'),
+                            json_extract(source_metadata_json, '$.utm.campaign')) AS utm_campaign,
+                        COALESCE(json_extract(payload_json, '$.device_type'),
+                            json_extract(source_metadata_json, '$.device_type')) AS device_type,
+                        COALESCE(json_extract(payload_json, '$.browser'),
+                            json_extract(source_metadata_json, '$.browser')) AS browser
+                    FROM events
+                    WHERE type = 'page_view'
+                )
+            );
+
+            CREATE TABLE page_views (
+                observed_at INTEGER NOT NULL,
+                event_id TEXT NOT NULL,
+                path TEXT,
+                referrer_host TEXT,
+                utm_source TEXT,
+                utm_medium TEXT,
+                utm_campaign TEXT,
+                device_type TEXT,
+                browser TEXT,
+                PRIMARY KEY (observed_at, event_id)
+            ) WITHOUT ROWID;
+
+            INSERT INTO page_views SELECT * FROM page_view_dimensions;
+
+            CREATE TRIGGER events_insert_page_view AFTER INSERT ON events
+            WHEN new.type = 'page_view' BEGIN
+                INSERT INTO page_views
+                SELECT * FROM page_view_dimensions WHERE event_id = new.event_id;
+            END;
+
+            CREATE TRIGGER events_delete_page_view AFTER DELETE ON events
+            WHEN old.type = 'page_view' BEGIN
+                DELETE FROM page_views
+                WHERE observed_at = old.observed_at AND event_id = old.event_id;
+            END;
+        "#,
+    },
+    Migration {
+        version: 10,
+        description: "relay arrival time",
+        // Relayed events keep the sender's received_at, so live stream
+        // replay orders by when an event reached this agent instead
+        sql: r#"
+            ALTER TABLE events ADD COLUMN relayed_at INTEGER;
+
+            DROP INDEX idx_events_received;
+            CREATE INDEX idx_events_arrived
+                ON events(COALESCE(relayed_at, received_at), event_id);
+        "#,
+    },
+    Migration {
+        version: 11,
+        description: "stream sequence",
+        // Arrival times are stamped before the writer queue, so events can
+        // commit out of that order; replay follows a number assigned at
+        // commit instead. Existing events are numbered in arrival order.
+        sql: r#"
+            ALTER TABLE events ADD COLUMN stream_seq INTEGER;
+
+            UPDATE events SET stream_seq = numbered.seq
+            FROM (
+                SELECT event_id, ROW_NUMBER() OVER (
+                    ORDER BY COALESCE(relayed_at, received_at), event_id
+                ) AS seq
+                FROM events
+            ) AS numbered
+            WHERE numbered.event_id = events.event_id;
+
+            INSERT INTO config (key, value, updated_at)
+            SELECT 'stream_seq', COALESCE(MAX(stream_seq), 0),
+                   CAST(unixepoch('subsec') * 1000 AS INTEGER)
+            FROM events;
+
+            DROP INDEX idx_events_arrived;
+            CREATE UNIQUE INDEX idx_events_stream_seq ON events(stream_seq);
+        "#,
+    },
+];
+
+/// Schema version this build expects
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
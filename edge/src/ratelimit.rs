@@ -0,0 +1,448 @@
+//! Ingest rate limiting for EdgeKite
+//!
+//! Token buckets keyed by client IP and API key (charged per request, before
+//! the body is read) and by `source.id` (charged per event, once the body is
+//! parsed). Throttled requests get `429 Too Many Requests` with `Retry-After`,
+//! and every drop is counted so `/api/stats` shows who is being throttled.
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::auth::ApiKey;
+use crate::config::{BucketConfig, RateLimitConfig};
+use crate::server::error_response;
+
+/// Most buckets kept; beyond this the least recently used one is dropped
+const MAX_BUCKETS: usize = 10_000;
+
+/// Distinct ids with their own drop counter, per dimension; the rest are
+/// counted under `OTHER`
+const MAX_TRACKED_DROPS: usize = 1000;
+const OTHER: &str = "(other)";
+
+/// What a bucket is keyed by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Dimension {
+    Source,
+    Key,
+    Ip,
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(limit: &BucketConfig, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &BucketConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    /// Time until `cost` tokens are available
+    fn wait_for(&self, limit: &BucketConfig, cost: f64) -> Duration {
+        Duration::from_secs_f64(((cost - self.tokens) / limit.per_second).max(0.0))
+    }
+}
+
+type BucketKey = (Dimension, String);
+
+/// Buckets in least recently used order, capped at `MAX_BUCKETS`
+///
+/// The least recently used bucket is the one most likely to have refilled,
+/// and a full bucket behaves exactly like a new one, so eviction rarely
+/// hands a throttled client fresh tokens.
+#[derive(Default)]
+struct Buckets {
+    /// Each bucket with the tick of its last use
+    map: HashMap<BucketKey, (Bucket, u64)>,
+    by_use: BTreeMap<u64, BucketKey>,
+    tick: u64,
+}
+
+impl Buckets {
+    /// The bucket for `key`, created with `new` if missing, marked as just used
+    fn touch(&mut self, key: BucketKey, new: impl FnOnce() -> Bucket) -> &mut Bucket {
+        self.tick += 1;
+        if let Some((_, used)) = self.map.get(&key) {
+            self.by_use.remove(used);
+        } else if self.map.len() >= MAX_BUCKETS {
+            if let Some((_, oldest)) = self.by_use.pop_first() {
+                self.map.remove(&oldest);
+            }
+        }
+        self.by_use.insert(self.tick, key.clone());
+        let (bucket, used) = self.map.entry(key).or_insert_with(|| (new(), 0));
+        *used = self.tick;
+        bucket
+    }
+
+    fn get_mut(&mut self, key: &BucketKey) -> Option<&mut Bucket> {
+        self.map.get_mut(key).map(|(bucket, _)| bucket)
+    }
+}
+
+#[derive(Default)]
+struct LimiterState {
+    buckets: Buckets,
+    drops: HashMap<Dimension, BTreeMap<String, u64>>,
+}
+
+/// Drop counters reported by `/api/stats`
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct RateLimitStats {
+    /// Requests and events dropped since startup
+    pub dropped_total: u64,
+    /// Dropped events per `source.id`
+    pub by_source: BTreeMap<String, u64>,
+    /// Dropped requests per API key id
+    pub by_key: BTreeMap<String, u64>,
+    /// Dropped requests per client IP
+    pub by_ip: BTreeMap<String, u64>,
+}
+
+/// Shared token-bucket limiter
+pub struct RateLimiter {
+    config: RwLock<RateLimitConfig>,
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            state: Mutex::new(LimiterState::default()),
+        }
+    }
+
+    /// Apply new limits; buckets keep their tokens, capped at the new burst
+    pub fn reconfigure(&self, config: RateLimitConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    fn limit(&self, dimension: Dimension) -> Option<BucketConfig> {
+        let config = self.config.read().unwrap();
+        let limit = match dimension {
+            Dimension::Source => config.per_source,
+            Dimension::Key => config.per_key,
+            Dimension::Ip => config.per_ip,
+        };
+        (config.enabled && limit.per_second > 0.0).then_some(limit)
+    }
+
+    /// Take `cost` tokens from every listed bucket, or from none of them
+    ///
+    /// On refusal the drop is counted against each bucket that was short,
+    /// and the time until all of them could serve the request is returned.
+    pub fn check(&self, keys: &[(Dimension, &str)], cost: u32) -> Result<(), Duration> {
+        self.check_at(keys, cost, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        keys: &[(Dimension, &str)],
+        cost: u32,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let keys: Vec<_> = keys
+            .iter()
+            .filter_map(|&(dimension, id)| Some((dimension, id, self.limit(dimension)?)))
+            .collect();
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let cost = cost as f64;
+        let mut state = self.state.lock().unwrap();
+        let mut wait = Duration::ZERO;
+        let mut short = Vec::new();
+        for (dimension, id, limit) in &keys {
+            let bucket = state
+                .buckets
+                .touch((*dimension, id.to_string()), || Bucket::full(limit, now));
+            bucket.refill(limit, now);
+            if bucket.tokens < cost {
+                wait = wait.max(bucket.wait_for(limit, cost));
+                short.push((*dimension, *id));
+            }
+        }
+
+        if short.is_empty() {
+            for &(dimension, id, _) in &keys {
+                if let Some(bucket) = state.buckets.get_mut(&(dimension, id.to_string())) {
+                    bucket.tokens -= cost;
+                }
+            }
+            return Ok(());
+        }
+
+        for (dimension, id) in short {
+            let counters = state.drops.entry(dimension).or_default();
+            let id = if counters.contains_key(id) || counters.len() < MAX_TRACKED_DROPS {
+                id
+            } else {
+                OTHER
+            };
+            *counters.entry(id.to_string()).or_default() += cost as u64;
+        }
+        Err(wait)
+    }
+
+    /// Snapshot of the drop counters
+    pub fn stats(&self) -> RateLimitStats {
+        let state = self.state.lock().unwrap();
+        let counters = |dimension| state.drops.get(&dimension).cloned().unwrap_or_default();
+        let (by_source, by_key, by_ip) = (
+            counters(Dimension::Source),
+            counters(Dimension::Key),
+            counters(Dimension::Ip),
+        );
+        RateLimitStats {
+            dropped_total: [&by_source, &by_key, &by_ip]
+                .iter()
+                .flat_map(|c| c.values())
+                .sum(),
+            by_source,
+            by_key,
+            by_ip,
+        }
+    }
+
+    /// Client IP for a request, honouring `X-Forwarded-For` only when trusted
+    fn client_ip(&self, headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<String> {
+        if self.config.read().unwrap().trust_forwarded_for {
+            let forwarded = headers
+                .get("x-forwarded-for")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.split(',').next())
+                .map(|ip| ip.trim().to_string())
+                .filter(|ip| !ip.is_empty());
+            if forwarded.is_some() {
+                return forwarded;
+            }
+        }
+        peer.map(|addr| addr.ip().to_string())
+    }
+}
+
+/// Charge one request to the client IP and API key buckets
+///
+/// Runs after authentication so the matched `ApiKey` is in the extensions.
+pub async fn limit(State(limiter): State<Arc<RateLimiter>>, req: Request, next: Next) -> Response {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| *addr);
+    let ip = limiter.client_ip(req.headers(), peer);
+    let key_id = req.extensions().get::<ApiKey>().map(|key| key.id.clone());
+
+    let mut keys = Vec::new();
+    if let Some(ip) = &ip {
+        keys.push((Dimension::Ip, ip.as_str()));
+    }
+    if let Some(id) = &key_id {
+        keys.push((Dimension::Key, id.as_str()));
+    }
+
+    match limiter.check(&keys, 1) {
+        Ok(()) => next.run(req).await,
+        Err(wait) => too_many_requests(
+            error_response(StatusCode::TOO_MANY_REQUESTS, "rate limit exceeded"),
+            wait,
+        ),
+    }
+}
+
+/// Turn a response into a 429 with a `Retry-After` header (whole seconds, at least 1)
+pub fn too_many_requests(mut response: Response, wait: Duration) -> Response {
+    *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    let seconds = wait.as_secs_f64().ceil().max(1.0) as u64;
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(per_second: f64, burst: u32) -> RateLimiter {
+        let limit = BucketConfig { per_second, burst };
+        RateLimiter::new(RateLimitConfig {
+            per_source: limit,
+            per_key: limit,
+            per_ip: limit,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_burst_then_refill() {
+        let limiter = limiter(2.0, 3);
+        let start = Instant::now();
+        let camera = [(Dimension::Source, "camera-01")];
+
+        for _ in 0..3 {
+            assert!(limiter.check_at(&camera, 1, start).is_ok());
+        }
+        let wait = limiter.check_at(&camera, 1, start).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(500));
+
+        // Other sources have their own bucket
+        assert!(limiter
+            .check_at(&[(Dimension::Source, "camera-02")], 1, start)
+            .is_ok());
+
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.check_at(&camera, 1, later).is_ok());
+        assert!(limiter.check_at(&camera, 1, later).is_err());
+    }
+
+    #[test]
+    fn test_bucket_count_is_capped() {
+        let limiter = limiter(0.001, 1);
+        let now = Instant::now();
+        let noisy = [(Dimension::Ip, "10.0.0.1")];
+        assert!(limiter.check_at(&noisy, 1, now).is_ok());
+
+        // A flood of one-off clients, while the throttled one keeps trying
+        for i in 0..MAX_BUCKETS * 2 {
+            let ip = format!("fd00::{:x}", i);
+            assert!(limiter.check_at(&[(Dimension::Ip, &ip)], 1, now).is_ok());
+            if i % 100 == 0 {
+                assert!(limiter.check_at(&noisy, 1, now).is_err());
+            }
+            assert!(limiter.state.lock().unwrap().buckets.map.len() <= MAX_BUCKETS);
+        }
+        let state = limiter.state.lock().unwrap();
+        assert_eq!(state.buckets.map.len(), MAX_BUCKETS);
+        assert_eq!(state.buckets.by_use.len(), MAX_BUCKETS);
+        drop(state);
+        assert!(limiter.check_at(&noisy, 1, now).is_err());
+    }
+
+    #[test]
+    fn test_refused_request_takes_no_tokens() {
+        let limiter = limiter(1.0, 2);
+        let now = Instant::now();
+        let ip = (Dimension::Ip, "10.0.0.1");
+        let key = (Dimension::Key, "abc123");
+
+        assert!(limiter.check_at(&[ip], 2, now).is_ok());
+        assert!(limiter.check_at(&[ip, key], 1, now).is_err());
+        // The key bucket is still full
+        assert!(limiter.check_at(&[key], 2, now).is_ok());
+    }
+
+    #[test]
+    fn test_drop_counters() {
+        let limiter = limiter(1.0, 1);
+        let now = Instant::now();
+        let ip = [(Dimension::Ip, "10.0.0.1")];
+        let source = [(Dimension::Source, "bot")];
+
+        assert!(limiter.check_at(&ip, 1, now).is_ok());
+        assert!(limiter.check_at(&ip, 1, now).is_err());
+        assert!(limiter.check_at(&source, 5, now).is_err());
+
+        let stats = limiter.stats();
+        assert_eq!(stats.dropped_total, 6);
+        assert_eq!(stats.by_ip.get("10.0.0.1"), Some(&1));
+        assert_eq!(stats.by_source.get("bot"), Some(&5));
+        assert!(stats.by_key.is_empty());
+    }
+
+    #[test]
+    fn test_reconfigure() {
+        let limiter = limiter(1.0, 5);
+        let now = Instant::now();
+        let ip = [(Dimension::Ip, "10.0.0.1")];
+        assert!(limiter.check_at(&ip, 1, now).is_ok());
+
+        // A smaller burst caps the tokens the bucket already had
+        let limit = BucketConfig {
+            per_second: 1.0,
+            burst: 2,
+        };
+        limiter.reconfigure(RateLimitConfig {
+            per_ip: limit,
+            ..Default::default()
+        });
+        assert!(limiter.check_at(&ip, 2, now).is_ok());
+        assert!(limiter.check_at(&ip, 1, now).is_err());
+
+        limiter.reconfigure(RateLimitConfig {
+            enabled: false,
+            ..Default::default()
+        });
+        assert!(limiter.check_at(&ip, 100, now).is_ok());
+    }
+
+    #[test]
+    fn test_disabled_limits() {
+        let now = Instant::now();
+        let unlimited = limiter(0.0, 0);
+        for _ in 0..100 {
+            assert!(unlimited.check_at(&[(Dimension::Ip, "a")], 1, now).is_ok());
+        }
+
+        let off = RateLimiter::new(RateLimitConfig {
+            enabled: false,
+            per_ip: BucketConfig {
+                per_second: 1.0,
+                burst: 0,
+            },
+            ..Default::default()
+        });
+        assert!(off.check_at(&[(Dimension::Ip, "a")], 1, now).is_ok());
+        assert_eq!(off.stats(), RateLimitStats::default());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let peer: SocketAddr = "192.168.1.5:4000".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("203.0.113.9, 10.0.0.1"),
+        );
+
+        let direct = limiter(1.0, 1);
+        assert_eq!(
+            direct.client_ip(&headers, Some(peer)).as_deref(),
+            Some("192.168.1.5")
+        );
+
+        let proxied = RateLimiter::new(RateLimitConfig {
+            trust_forwarded_for: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            proxied.client_ip(&headers, Some(peer)).as_deref(),
+            Some("203.0.113.9")
+        );
+        assert_eq!(
+            proxied.client_ip(&HeaderMap::new(), Some(peer)).as_deref(),
+            Some("192.168.1.5")
+        );
+    }
+}
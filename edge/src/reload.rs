@@ -0,0 +1,233 @@
+//! Configuration hot reload
+//!
+//! SIGHUP or `POST /api/config/reload` re-reads the configuration and
+//! publishes it to the parts of the agent that can change while running:
+//! sync workers restart with the new `[sync]` section, the retention worker
+//! reads `[retention]` on its next run, and the server swaps rate limits,
+//! CORS and the ingest validator. Settings bound at startup (listen address,
+//! data directory, auth, hub mode) keep their old values and are reported as
+//! needing a restart.
+
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::sync;
+use crate::validate::Validator;
+
+type Loader = dyn Fn() -> Result<Config> + Send + Sync;
+
+/// What a reload changed, as dotted setting paths (e.g. `sync.batch_size`)
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct ReloadReport {
+    /// Changes now in effect
+    pub applied: Vec<String>,
+    /// Changes ignored until the agent restarts
+    pub restart_required: Vec<String>,
+}
+
+impl ReloadReport {
+    pub fn log(&self) {
+        if self.applied.is_empty() {
+            info!("Configuration reloaded, nothing to apply");
+        } else {
+            info!(
+                "Configuration reloaded, applied: {}",
+                self.applied.join(", ")
+            );
+        }
+        if !self.restart_required.is_empty() {
+            warn!("Restart to apply: {}", self.restart_required.join(", "));
+        }
+    }
+}
+
+/// The configuration in effect, and how to read it again
+#[derive(Clone)]
+pub struct Reloader {
+    config: Arc<watch::Sender<Config>>,
+    load: Arc<Loader>,
+    /// Reloads from SIGHUP and the API run one at a time
+    lock: Arc<Mutex<()>>,
+}
+
+impl Reloader {
+    /// `load` reads the configuration the way startup did (file, environment
+    /// and command-line overrides)
+    pub fn new(config: Config, load: impl Fn() -> Result<Config> + Send + Sync + 'static) -> Self {
+        Self {
+            config: Arc::new(watch::channel(config).0),
+            load: Arc::new(load),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn current(&self) -> Config {
+        self.config.borrow().clone()
+    }
+
+    /// Receives the configuration each time a reload changes it
+    pub fn subscribe(&self) -> watch::Receiver<Config> {
+        self.config.subscribe()
+    }
+
+    /// Read the configuration again and publish what can be applied live
+    ///
+    /// An unreadable or invalid configuration changes nothing.
+    pub fn reload(&self) -> Result<ReloadReport> {
+        let _guard = self.lock.lock().unwrap();
+        let loaded = (self.load)()?;
+        sync::plan(&loaded.sync)?;
+
+        let current = self.current();
+        let effective = keep_startup_settings(&current, loaded.clone());
+        if effective.validation != current.validation {
+            // Refuse payload schemas that do not compile before anyone swaps
+            Validator::load(&effective.validation, &effective.data_dir)?;
+        }
+        let report = ReloadReport {
+            applied: changes(&current, &effective)?,
+            restart_required: changes(&effective, &loaded)?,
+        };
+        if !report.applied.is_empty() {
+            self.config.send_replace(effective);
+        }
+        Ok(report)
+    }
+}
+
+/// `loaded` with the settings that are only read at startup taken from `current`
+fn keep_startup_settings(current: &Config, mut loaded: Config) -> Config {
+    loaded.data_dir = current.data_dir.clone();
+    loaded.server.listen = current.server.listen.clone();
+    loaded.server.auth_required = current.server.auth_required;
+    loaded.server.ui_path = current.server.ui_path.clone();
+    loaded.hub = current.hub.clone();
+    loaded
+}
+
+/// Dotted paths of the settings that differ; lists compare as a whole
+fn changes(old: &Config, new: &Config) -> Result<Vec<String>> {
+    fn walk(path: &str, old: &serde_json::Value, new: &serde_json::Value, out: &mut Vec<String>) {
+        match (old, new) {
+            (serde_json::Value::Object(old), serde_json::Value::Object(new)) => {
+                let mut keys: Vec<_> = old.keys().chain(new.keys()).collect();
+                keys.sort();
+                keys.dedup();
+                for key in keys {
+                    let null = serde_json::Value::Null;
+                    let child = if path.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{}.{}", path, key)
+                    };
+                    walk(
+                        &child,
+                        old.get(key).unwrap_or(&null),
+                        new.get(key).unwrap_or(&null),
+                        out,
+                    );
+                }
+            }
+            (old, new) if old != new => out.push(path.to_string()),
+            _ => {}
+        }
+    }
+
+    let mut out = Vec::new();
+    walk(
+        "",
+        &serde_json::to_value(old)?,
+        &serde_json::to_value(new)?,
+        &mut out,
+    );
+    Ok(out)
+}
+
+/// Reload whenever the process receives SIGHUP
+pub fn reload_on_sighup(reloader: Reloader) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                warn!("Cannot listen for SIGHUP, reload via the API only: {}", e);
+                return;
+            }
+        };
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading configuration");
+                match reloader.reload() {
+                    Ok(report) => report.log(),
+                    Err(e) => error!("Reload failed, configuration unchanged: {}", e),
+                }
+            }
+        });
+    }
+    #[cfg(not(unix))]
+    let _ = reloader;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ValidationMode;
+
+    #[test]
+    fn test_reload() {
+        let next = Arc::new(Mutex::new(Config::default()));
+        let reloader = Reloader::new(Config::default(), {
+            let next = next.clone();
+            move || Ok(next.lock().unwrap().clone())
+        });
+        let mut rx = reloader.subscribe();
+        assert_eq!(reloader.reload().unwrap(), ReloadReport::default());
+        assert!(!rx.has_changed().unwrap());
+
+        {
+            let mut next = next.lock().unwrap();
+            next.sync.interval_seconds = 5;
+            next.server.rate_limit.per_ip.burst = 1;
+            next.server.listen = "127.0.0.1:9999".to_string();
+            next.hub.enabled = true;
+            next.validation.mode = ValidationMode::Warn;
+        }
+        let report = reloader.reload().unwrap();
+        assert_eq!(
+            report.applied,
+            [
+                "server.rate_limit.per_ip.burst",
+                "sync.interval_seconds",
+                "validation.mode"
+            ]
+        );
+        assert_eq!(report.restart_required, ["hub.enabled", "server.listen"]);
+        assert!(rx.has_changed().unwrap());
+        let current = rx.borrow_and_update().clone();
+        assert_eq!(current.sync.interval_seconds, 5);
+        assert_eq!(current.server.listen, Config::default().server.listen);
+
+        // An invalid configuration is refused and changes nothing
+        next.lock().unwrap().sync.enabled = true;
+        assert!(reloader.reload().is_err());
+        assert!(!reloader.current().sync.enabled);
+
+        // So are payload schemas that do not compile
+        let schemas = tempfile::tempdir().unwrap();
+        let dir = schemas.path().join("web").join("page_view");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("1.0.json"), r#"{"type": 12}"#).unwrap();
+        {
+            let mut next = next.lock().unwrap();
+            next.sync.enabled = false;
+            next.validation.schemas_dir = Some(schemas.path().to_path_buf());
+        }
+        assert!(reloader.reload().is_err());
+        assert_eq!(reloader.current().validation.schemas_dir, None);
+    }
+}
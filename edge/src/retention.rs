@@ -0,0 +1,410 @@
+//! Retention cleanup worker for EdgeKite
+//!
+//! Deletes events past their retention window once a day, from `cleanup_hour`
+//! on; a day whose cleanup hour passed while the agent was down is caught up
+//! at startup.
+//! Deletes run in bounded batches so ingest is never locked out for long,
+//! and unsynced events are kept until their destinations have them unless
+//! the hard database size limit is exceeded.
+
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveDate, Timelike, Utc};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+use crate::config::{Config, RetentionConfig, SyncConfig, Transport};
+use crate::db::Database;
+use crate::destination::Destination;
+use crate::error::Result;
+
+/// `config` table key holding the local date of the last full cleanup
+const LAST_CLEANUP_KEY: &str = "retention.last_cleanup";
+
+/// What a cleanup pass removed
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CleanupReport {
+    /// Expired `short` retention class events
+    pub short: usize,
+    /// Expired `standard` (or unrecognized) retention class events
+    pub standard: usize,
+    /// Expired `long` retention class events
+    pub long: usize,
+    /// Events evicted to get back under `max_db_mb`
+    pub evicted: usize,
+}
+
+impl CleanupReport {
+    pub fn total(&self) -> usize {
+        self.short + self.standard + self.long + self.evicted
+    }
+}
+
+/// Start the retention worker
+///
+/// Runs at startup and then at the top of every hour: the full cleanup when
+/// one is due (see `run_scheduled`), only the size limit otherwise. Unsynced
+/// events expire like any other only while no queue is drained (see
+/// `expire_unsynced`). Each run uses the latest reloaded configuration.
+pub fn start_worker(db: Database, live: watch::Receiver<Config>) -> JoinHandle<()> {
+    let cleanup_hour = live.borrow().retention.cleanup_hour;
+    if cleanup_hour > 23 {
+        warn!(
+            "retention.cleanup_hour {} is out of range, using {}",
+            cleanup_hour,
+            cleanup_hour % 24
+        );
+    }
+
+    tokio::spawn(async move {
+        loop {
+            let (config, include_unsynced) = {
+                let live = live.borrow();
+                (live.retention.clone(), expire_unsynced(&live.sync))
+            };
+            let db = db.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                run_scheduled(&db, &config, include_unsynced, Local::now())
+            })
+            .await;
+
+            match result {
+                Ok(Ok(report)) if report.total() > 0 => info!(
+                    "Retention cleanup removed {} events (short: {}, standard: {}, long: {}, evicted over size limit: {})",
+                    report.total(),
+                    report.short,
+                    report.standard,
+                    report.long,
+                    report.evicted
+                ),
+                Ok(Ok(_)) => debug!("Retention cleanup found nothing to remove"),
+                Ok(Err(e)) => error!("Retention cleanup failed: {}", e),
+                Err(e) => error!("Retention cleanup task panicked: {}", e),
+            }
+
+            tokio::time::sleep(until_next_hour(Local::now())).await;
+        }
+    })
+}
+
+/// One scheduled run: the full cleanup if it is due, the size limit otherwise
+///
+/// The full cleanup is due once `now` is at or past `cleanup_hour` and none
+/// has completed yet today. The date of the last one is kept in the `config`
+/// table, so an agent that was down or asleep at `cleanup_hour` catches up
+/// on its next run instead of waiting for the next day.
+fn run_scheduled(
+    db: &Database,
+    config: &RetentionConfig,
+    include_unsynced: bool,
+    now: DateTime<Local>,
+) -> Result<CleanupReport> {
+    let today = now.date_naive();
+    let last = db
+        .get_setting(LAST_CLEANUP_KEY)?
+        .and_then(|date| date.parse::<NaiveDate>().ok());
+    let due = now.hour() >= config.cleanup_hour % 24 && last.is_none_or(|last| last < today);
+    if !due {
+        return enforce_size_limit(db, config).map(|evicted| CleanupReport {
+            evicted,
+            ..Default::default()
+        });
+    }
+
+    let report = run_cleanup(db, config, include_unsynced, now.with_timezone(&Utc))?;
+    db.set_setting(LAST_CLEANUP_KEY, &today.to_string())?;
+    Ok(report)
+}
+
+/// Whether queued events may expire: only if no queue will ever be drained
+///
+/// With sync disabled there is no hub to wait for, but bundle destinations
+/// are exported by hand regardless, so their queues are always waited for.
+fn expire_unsynced(sync: &SyncConfig) -> bool {
+    match Destination::from_config(sync) {
+        Ok(destinations) => !destinations
+            .iter()
+            .any(|d| sync.enabled || d.transport == Transport::Bundle),
+        Err(_) => false,
+    }
+}
+
+/// Run a full cleanup pass: expire each retention class, then enforce the size limit
+pub fn run_cleanup(
+    db: &Database,
+    config: &RetentionConfig,
+    include_unsynced: bool,
+    now: DateTime<Utc>,
+) -> Result<CleanupReport> {
+    let cutoff = |days: u32| (now - ChronoDuration::days(days as i64)).timestamp_millis();
+    let batch = config.batch_size.max(1);
+
+    let short = delete_in_batches(|| {
+        db.delete_expired_events(
+            &["short"],
+            &[],
+            cutoff(config.short_days),
+            include_unsynced,
+            batch,
+        )
+    })?;
+    let long = delete_in_batches(|| {
+        db.delete_expired_events(
+            &["long"],
+            &[],
+            cutoff(config.long_days),
+            include_unsynced,
+            batch,
+        )
+    })?;
+    let standard = delete_in_batches(|| {
+        db.delete_expired_events(
+            &[],
+            &["short", "long"],
+            cutoff(config.events_days),
+            include_unsynced,
+            batch,
+        )
+    })?;
+    let evicted = enforce_size_limit(db, config)?;
+
+    Ok(CleanupReport {
+        short,
+        standard,
+        long,
+        evicted,
+    })
+}
+
+/// Evict the oldest events, synced or not, while the database exceeds `max_db_mb`
+fn enforce_size_limit(db: &Database, config: &RetentionConfig) -> Result<usize> {
+    if config.max_db_mb == 0 {
+        return Ok(0);
+    }
+
+    let limit_bytes = config.max_db_mb * 1024 * 1024;
+    let mut evicted = 0;
+    while db.used_bytes()? > limit_bytes {
+        let deleted = db.evict_oldest_events(config.batch_size.max(1))?;
+        if deleted == 0 {
+            break;
+        }
+        evicted += deleted;
+    }
+
+    if evicted > 0 {
+        warn!(
+            "Database exceeded {} MB, evicted {} oldest events (including unsynced)",
+            config.max_db_mb, evicted
+        );
+    }
+    Ok(evicted)
+}
+
+/// Repeat a bounded delete until it removes nothing, releasing the lock in between
+fn delete_in_batches(mut delete: impl FnMut() -> Result<usize>) -> Result<usize> {
+    let mut total = 0;
+    loop {
+        let deleted = delete()?;
+        if deleted == 0 {
+            return Ok(total);
+        }
+        total += deleted;
+        std::thread::yield_now();
+    }
+}
+
+/// Time until the start of the next local hour
+fn until_next_hour(now: DateTime<Local>) -> Duration {
+    let into_hour = now.minute() as u64 * 60 + now.second() as u64;
+    Duration::from_secs(3600 - into_hour.min(3599))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DestinationConfig;
+    use crate::event::test_support::test_event;
+    use crate::event::Event;
+    use tempfile::tempdir;
+
+    fn make_event(days_old: i64, retention_class: &str) -> Event {
+        test_event("person_detected")
+            .observed_at(Utc::now() - ChronoDuration::days(days_old))
+            .retention_class(retention_class)
+            .build()
+    }
+
+    fn test_config() -> RetentionConfig {
+        RetentionConfig {
+            batch_size: 2,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_cleanup_honours_retention_class() {
+        let dir = tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db")).unwrap();
+        db.migrate().unwrap();
+
+        let events = vec![
+            make_event(10, "short"),    // expired (7 days)
+            make_event(3, "short"),     // kept
+            make_event(40, "standard"), // expired (30 days)
+            make_event(40, "custom"),   // unknown class -> standard, expired
+            make_event(20, "standard"), // kept
+            make_event(40, "long"),     // kept (365 days)
+            make_event(400, "long"),    // expired
+        ];
+        // No destinations are configured, so nothing is queued
+        db.insert_events(&events).unwrap();
+
+        let report = run_cleanup(&db, &test_config(), false, Utc::now()).unwrap();
+        assert_eq!(
+            report,
+            CleanupReport {
+                short: 1,
+                standard: 2,
+                long: 1,
+                evicted: 0
+            }
+        );
+        assert_eq!(db.event_count().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_cleanup_keeps_unsynced_events() {
+        let dir = tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db")).unwrap();
+        db.migrate().unwrap();
+        let hub = SyncConfig {
+            hub_url: "https://hub.example.com".to_string(),
+            ..Default::default()
+        };
+        db.set_destinations(&Destination::from_config(&hub).unwrap())
+            .unwrap();
+
+        let events: Vec<Event> = (0..5).map(|_| make_event(40, "standard")).collect();
+        db.insert_events(&events).unwrap();
+
+        let report = run_cleanup(&db, &test_config(), false, Utc::now()).unwrap();
+        assert_eq!(report.total(), 0);
+        assert_eq!(db.event_count().unwrap(), 5);
+
+        // Without a hub, unsynced events expire normally
+        let report = run_cleanup(&db, &test_config(), true, Utc::now()).unwrap();
+        assert_eq!(report.standard, 5);
+        assert_eq!(db.event_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_cleanup_waits_for_bundle_export() {
+        let dir = tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db")).unwrap();
+        db.migrate().unwrap();
+        // Sync stays off on an air-gapped site; the queue drains by bundle
+        let sync = SyncConfig {
+            destinations: vec![DestinationConfig {
+                name: "usb".to_string(),
+                transport: Transport::Bundle,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        db.set_destinations(&Destination::from_config(&sync).unwrap())
+            .unwrap();
+
+        let events: Vec<Event> = (0..3).map(|_| make_event(40, "standard")).collect();
+        db.insert_events(&events).unwrap();
+
+        assert!(!expire_unsynced(&sync));
+        let report = run_cleanup(&db, &test_config(), expire_unsynced(&sync), Utc::now()).unwrap();
+        assert_eq!(report.total(), 0);
+        assert_eq!(db.event_count().unwrap(), 3);
+
+        // An HTTP queue nobody syncs does not hold events back
+        let idle = SyncConfig {
+            hub_url: "https://hub.example.com".to_string(),
+            ..Default::default()
+        };
+        assert!(expire_unsynced(&idle));
+        assert!(!expire_unsynced(&SyncConfig {
+            enabled: true,
+            ..idle
+        }));
+    }
+
+    #[test]
+    fn test_size_limit_evicts_unsynced() {
+        let dir = tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db")).unwrap();
+        db.migrate().unwrap();
+
+        let mut events: Vec<Event> = (0..2000).map(|_| make_event(1, "standard")).collect();
+        for e in &mut events {
+            e.event.data = serde_json::json!({ "padding": "x".repeat(1024) });
+        }
+        db.insert_events(&events).unwrap();
+        assert!(db.used_bytes().unwrap() > 1024 * 1024);
+
+        let config = RetentionConfig {
+            max_db_mb: 1,
+            batch_size: 100,
+            ..Default::default()
+        };
+        let report = run_cleanup(&db, &config, false, Utc::now()).unwrap();
+        assert!(report.evicted > 0);
+        assert!(db.used_bytes().unwrap() <= 1024 * 1024);
+        assert_eq!(db.event_count().unwrap(), 2000 - report.evicted as i64);
+    }
+
+    #[test]
+    fn test_scheduled_cleanup_runs_once_a_day_from_cleanup_hour() {
+        use chrono::TimeZone;
+        let dir = tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db")).unwrap();
+        db.migrate().unwrap();
+        let config = RetentionConfig {
+            cleanup_hour: 5,
+            ..test_config()
+        };
+        let expire = |db: &Database| db.insert_events(&[make_event(40, "standard")]).unwrap();
+        let today = Local::now().date_naive();
+        let at = |days: i64, hour: u32| {
+            let date = today + ChronoDuration::days(days);
+            Local
+                .from_local_datetime(&date.and_hms_opt(hour, 0, 0).unwrap())
+                .single()
+                .unwrap()
+        };
+
+        // Started after the cleanup hour with none recorded: catch up
+        expire(&db);
+        let report = run_scheduled(&db, &config, false, at(0, 9)).unwrap();
+        assert_eq!(report.standard, 1);
+
+        // Only once that day
+        expire(&db);
+        let report = run_scheduled(&db, &config, false, at(0, 10)).unwrap();
+        assert_eq!(report.total(), 0);
+
+        // Not before the cleanup hour of the next day
+        let report = run_scheduled(&db, &config, false, at(1, 4)).unwrap();
+        assert_eq!(report.total(), 0);
+
+        // Down through the cleanup hour: caught up on the next run
+        let report = run_scheduled(&db, &config, false, at(1, 7)).unwrap();
+        assert_eq!(report.standard, 1);
+        assert_eq!(db.event_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_until_next_hour() {
+        use chrono::TimeZone;
+        let at = Local.with_ymd_and_hms(2026, 1, 2, 10, 59, 30).unwrap();
+        assert_eq!(until_next_hour(at), Duration::from_secs(30));
+        let at = Local.with_ymd_and_hms(2026, 1, 2, 10, 0, 0).unwrap();
+        assert_eq!(until_next_hour(at), Duration::from_secs(3600));
+    }
+}
@@ -0,0 +1,365 @@
+//! Sync windows, bandwidth budgets and the priority lane
+//!
+//! Bulk sync only runs inside the configured local-time windows and while the
+//! daily and monthly upload budgets last. Priority events (high severity or a
+//! priority category) bypass both and are sent as soon as they are stored.
+//! Budget usage is persisted in the `config` table so restarts do not reset it.
+
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveTime};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tracing::warn;
+
+use crate::config::SyncConfig;
+use crate::db::Database;
+use crate::error::{Error, Result};
+use crate::event::{severities_at_least, Event, SEVERITIES};
+
+/// `config` table key holding the serialized `BudgetUsage`
+const USAGE_KEY: &str = "sync.budget_usage";
+
+/// A daily local-time range, e.g. `22:00-06:00` (may wrap past midnight)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl SyncWindow {
+    pub fn parse(raw: &str) -> Option<Self> {
+        let (start, end) = raw.split_once('-')?;
+        Some(Self {
+            start: NaiveTime::parse_from_str(start.trim(), "%H:%M").ok()?,
+            end: NaiveTime::parse_from_str(end.trim(), "%H:%M").ok()?,
+        })
+    }
+
+    /// Whether `time` falls inside the window (start inclusive, end exclusive)
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start == self.end {
+            true
+        } else if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Events that sync immediately, regardless of windows and budgets
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PriorityLane {
+    pub severities: &'static [&'static str],
+    pub categories: Vec<String>,
+}
+
+impl PriorityLane {
+    pub fn from_config(config: &SyncConfig) -> Self {
+        Self {
+            severities: severities_at_least(&config.priority_min_severity),
+            categories: config.priority_categories.clone(),
+        }
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        self.severities.contains(&event.event.severity.as_str())
+            || self.categories.contains(&event.event.category)
+    }
+
+    /// Outbox class of an event; lower classes drain first
+    ///
+    /// The priority lane is class 0, everything else follows by descending
+    /// severity (critical = 1 … debug = `LOWEST_CLASS`).
+    pub fn class(&self, event: &Event) -> i64 {
+        if self.matches(event) {
+            PRIORITY_CLASS
+        } else {
+            severity_class(&event.event.severity)
+        }
+    }
+}
+
+impl Default for PriorityLane {
+    fn default() -> Self {
+        Self::from_config(&SyncConfig::default())
+    }
+}
+
+/// Outbox class of priority-lane events
+pub const PRIORITY_CLASS: i64 = 0;
+
+/// Outbox class of the lowest severity (and of unknown severities)
+pub const LOWEST_CLASS: i64 = SEVERITIES.len() as i64;
+
+/// Outbox class of a severity outside the priority lane
+pub fn severity_class(severity: &str) -> i64 {
+    SEVERITIES
+        .iter()
+        .rev()
+        .position(|s| *s == severity)
+        .map_or(LOWEST_CLASS, |i| i as i64 + 1)
+}
+
+/// Upload bytes counted against the budgets
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BudgetUsage {
+    /// Day the `day_bytes` counter belongs to
+    day: Option<NaiveDate>,
+    day_bytes: u64,
+    /// First day of the month the `month_bytes` counter belongs to
+    month: Option<NaiveDate>,
+    month_bytes: u64,
+}
+
+impl BudgetUsage {
+    /// Load persisted usage (a missing or unreadable record starts from zero)
+    pub fn load(db: &Database) -> Self {
+        match db.get_setting(USAGE_KEY) {
+            Ok(Some(json)) => serde_json::from_str(&json).unwrap_or_default(),
+            Ok(None) => Self::default(),
+            Err(e) => {
+                warn!("Failed to load sync budget usage: {}", e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self, db: &Database) -> Result<()> {
+        db.set_setting(USAGE_KEY, &serde_json::to_string(self)?)
+    }
+
+    pub fn bytes_today(&self, today: NaiveDate) -> u64 {
+        if self.day == Some(today) {
+            self.day_bytes
+        } else {
+            0
+        }
+    }
+
+    pub fn bytes_this_month(&self, today: NaiveDate) -> u64 {
+        if self.month == Some(first_of_month(today)) {
+            self.month_bytes
+        } else {
+            0
+        }
+    }
+
+    /// Count `bytes` sent on `today`, starting new counters on a new day or month
+    pub fn record(&mut self, bytes: u64, today: NaiveDate) {
+        self.day_bytes = self.bytes_today(today) + bytes;
+        self.month_bytes = self.bytes_this_month(today) + bytes;
+        self.day = Some(today);
+        self.month = Some(first_of_month(today));
+    }
+}
+
+fn first_of_month(date: NaiveDate) -> NaiveDate {
+    date.with_day(1).unwrap_or(date)
+}
+
+/// Why bulk sync is held back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hold {
+    OutsideWindow,
+    DailyBudget,
+    MonthlyBudget,
+}
+
+impl fmt::Display for Hold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Hold::OutsideWindow => "outside sync window",
+            Hold::DailyBudget => "daily budget used",
+            Hold::MonthlyBudget => "monthly budget used",
+        })
+    }
+}
+
+/// When bulk sync may run
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    windows: Vec<SyncWindow>,
+    daily_budget: Option<u64>,
+    monthly_budget: Option<u64>,
+}
+
+impl Schedule {
+    pub fn from_config(config: &SyncConfig) -> Result<Self> {
+        let windows = config
+            .windows
+            .iter()
+            .map(|raw| {
+                SyncWindow::parse(raw).ok_or_else(|| {
+                    invalid(format!(
+                        "sync.windows entry '{}' must look like 22:00-06:00",
+                        raw
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if severities_at_least(&config.priority_min_severity).is_empty() {
+            return Err(invalid(format!(
+                "sync.priority_min_severity '{}' is not a severity level",
+                config.priority_min_severity
+            )));
+        }
+
+        let budget = |mb: f64| (mb > 0.0).then_some((mb * 1024.0 * 1024.0) as u64);
+        Ok(Self {
+            windows,
+            daily_budget: budget(config.daily_budget_mb),
+            monthly_budget: budget(config.monthly_budget_mb),
+        })
+    }
+
+    /// Why bulk sync may not run at `now`, if anything
+    ///
+    /// Budgets are checked before each batch, so the last batch of a period
+    /// may overshoot by up to one batch.
+    pub fn hold(&self, now: DateTime<Local>, usage: &BudgetUsage) -> Option<Hold> {
+        let today = now.date_naive();
+        if !self.windows.is_empty() && !self.windows.iter().any(|w| w.contains(now.time())) {
+            Some(Hold::OutsideWindow)
+        } else if self
+            .daily_budget
+            .is_some_and(|b| usage.bytes_today(today) >= b)
+        {
+            Some(Hold::DailyBudget)
+        } else if self
+            .monthly_budget
+            .is_some_and(|b| usage.bytes_this_month(today) >= b)
+        {
+            Some(Hold::MonthlyBudget)
+        } else {
+            None
+        }
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::Config(config::ConfigError::Message(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::test_support::test_event;
+    use chrono::TimeZone;
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2026, 3, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_window_wraps_midnight() {
+        let night = SyncWindow::parse("22:00-06:00").unwrap();
+        assert!(night.contains(time(23, 30)));
+        assert!(night.contains(time(2, 0)));
+        assert!(!night.contains(time(6, 0)));
+        assert!(!night.contains(time(12, 0)));
+
+        let lunch = SyncWindow::parse("12:00 - 13:30").unwrap();
+        assert!(lunch.contains(time(12, 0)));
+        assert!(!lunch.contains(time(13, 30)));
+
+        assert!(SyncWindow::parse("00:00-00:00")
+            .unwrap()
+            .contains(time(9, 0)));
+        assert!(SyncWindow::parse("night").is_none());
+        assert!(SyncWindow::parse("25:00-01:00").is_none());
+    }
+
+    #[test]
+    fn test_hold() {
+        let config = SyncConfig {
+            windows: vec!["22:00-06:00".to_string()],
+            daily_budget_mb: 1.0,
+            monthly_budget_mb: 2.0,
+            ..Default::default()
+        };
+        let schedule = Schedule::from_config(&config).unwrap();
+        let mut usage = BudgetUsage::default();
+
+        assert_eq!(
+            schedule.hold(at(10, 12, 0), &usage),
+            Some(Hold::OutsideWindow)
+        );
+        assert_eq!(schedule.hold(at(10, 23, 0), &usage), None);
+
+        usage.record(1024 * 1024, at(10, 23, 0).date_naive());
+        assert_eq!(
+            schedule.hold(at(10, 23, 30), &usage),
+            Some(Hold::DailyBudget)
+        );
+        // A new day starts a new daily budget
+        assert_eq!(schedule.hold(at(11, 1, 0), &usage), None);
+
+        usage.record(1024 * 1024, at(11, 1, 0).date_naive());
+        assert_eq!(
+            schedule.hold(at(12, 1, 0), &usage),
+            Some(Hold::MonthlyBudget)
+        );
+        assert_eq!(
+            usage.bytes_this_month(at(12, 1, 0).date_naive()),
+            2 * 1024 * 1024
+        );
+        assert_eq!(
+            usage.bytes_this_month(NaiveDate::from_ymd_opt(2026, 4, 1).unwrap()),
+            0
+        );
+    }
+
+    #[test]
+    fn test_priority_lane() {
+        let lane = PriorityLane::default();
+        assert_eq!(lane.severities, &["error", "critical"]);
+
+        let mut event = test_event("page_view").build();
+        assert!(!lane.matches(&event));
+        assert_eq!(lane.class(&event), 4);
+
+        event.event.severity = "critical".to_string();
+        assert!(lane.matches(&event));
+        assert_eq!(lane.class(&event), PRIORITY_CLASS);
+
+        event.event.severity = "info".to_string();
+        event.event.category = "security".to_string();
+        assert!(lane.matches(&event));
+
+        // Outside the lane, higher severities still drain first
+        let critical_only = PriorityLane {
+            severities: severities_at_least("critical"),
+            categories: Vec::new(),
+        };
+        event.event.severity = "error".to_string();
+        assert_eq!(critical_only.class(&event), 2);
+        assert_eq!(severity_class("debug"), LOWEST_CLASS);
+        assert_eq!(severity_class("bogus"), LOWEST_CLASS);
+
+        let bad = SyncConfig {
+            priority_min_severity: "urgent".to_string(),
+            ..Default::default()
+        };
+        assert!(Schedule::from_config(&bad).is_err());
+    }
+
+    #[test]
+    fn test_usage_persists() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::open(&dir.path().join("test.db")).unwrap();
+        db.migrate().unwrap();
+        assert_eq!(BudgetUsage::load(&db), BudgetUsage::default());
+
+        let mut usage = BudgetUsage::default();
+        usage.record(4096, NaiveDate::from_ymd_opt(2026, 3, 10).unwrap());
+        usage.save(&db).unwrap();
+        assert_eq!(BudgetUsage::load(&db), usage);
+    }
+}
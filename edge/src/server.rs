@@ -1,159 +1,1159 @@
 //! HTTP server for EdgeKite
 
 use axum::{
-    extract::{Json, State},
-    http::StatusCode,
-    response::IntoResponse,
+    body::Bytes,
+    extract::{DefaultBodyLimit, Extension, Json, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::{
+        sse::{Event as SseEvent, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     routing::{get, post},
     Router,
 };
-use serde::Serialize;
+use chrono::{DateTime, FixedOffset, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use sysinfo::System;
-use tower_http::cors::{Any, CorsLayer};
-use tracing::info;
+use tokio::sync::broadcast;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tracing::{debug, error, info, warn};
 
-use crate::config::ServerConfig;
-use crate::db::Database;
-use crate::error::Result;
+use crate::aggregate::{self, CountQuery, WebField};
+use crate::auth::{self, ApiKey, Guard, Scope};
+use crate::compression::{self, Encoding};
+use crate::db::{Cursor, Database, DeadLetter, EventFilter, InsertOutcome, Interval, TopValue};
+use crate::error::{Error, Result};
 use crate::event::{Event, IncomingEvent};
+use crate::ingest::{self, Full, IngestQueue, Outcome, QUEUE_FULL};
+use crate::metrics::{self, Metrics, Rejection, Sampled};
+use crate::ratelimit::{self, Dimension, RateLimitStats, RateLimiter};
+use crate::reload::Reloader;
+use crate::shutdown::Shutdown;
+use crate::sync::{SyncOverview, SyncPhase, SyncState};
+use crate::validate::Validator;
 
 /// Application state shared across handlers
 #[derive(Clone)]
 pub struct AppState {
     db: Database,
     db_path: PathBuf,
+    /// Newly stored events and their stream sequence, fanned out to live
+    /// stream subscribers
+    live: broadcast::Sender<(i64, Event)>,
+    /// Replaced when a reload changes `[validation]`
+    validator: Arc<RwLock<Arc<Validator>>>,
+    limiter: Arc<RateLimiter>,
+    /// Events waiting to be stored
+    ingest: Arc<IngestQueue>,
+    metrics: Arc<Metrics>,
+    sync: SyncState,
+    /// Largest decoded hub-mode upload, in bytes
+    relay_max_body: usize,
+    /// Ends live streams so the server can drain
+    shutdown: Shutdown,
+    reloader: Reloader,
 }
 
+impl AppState {
+    /// The validator in effect; a batch keeps one for all of its events
+    fn validator(&self) -> Arc<Validator> {
+        self.validator.read().unwrap().clone()
+    }
+}
+
+/// Capacity of the live event broadcast; slower subscribers catch up from SQLite
+const LIVE_CHANNEL_CAPACITY: usize = 1024;
+
 /// Run the HTTP server
-pub async fn run(config: ServerConfig, db: Database, db_path: PathBuf) -> Result<()> {
-    let state = Arc::new(AppState { db, db_path });
+pub async fn run(
+    reloader: Reloader,
+    db: Database,
+    validator: Validator,
+    metrics: Arc<Metrics>,
+    sync: SyncState,
+    shutdown: Shutdown,
+) -> Result<()> {
+    let listen = reloader.current().server.listen;
+    let app = router(reloader, db, validator, metrics, sync, shutdown.clone());
 
-    let mut app = Router::new()
-        // Event ingestion
+    let listener = tokio::net::TcpListener::bind(&listen).await?;
+    info!("Server listening on {}", listen);
+
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move { shutdown.triggered().await })
+    .await?;
+
+    Ok(())
+}
+
+/// Routes and shared state, with the background tasks they rely on
+fn router(
+    reloader: Reloader,
+    db: Database,
+    validator: Validator,
+    metrics: Arc<Metrics>,
+    sync: SyncState,
+    shutdown: Shutdown,
+) -> Router {
+    let config = reloader.current();
+    let db_path = config.db_path();
+    let hub = config.hub;
+    let config = config.server;
+    if !config.auth_required {
+        warn!("API authentication is disabled (server.auth_required = false)");
+    }
+    let guard = |required, query_key| {
+        middleware::from_fn_with_state(
+            Guard {
+                db: db.clone(),
+                required,
+                enforce: config.auth_required,
+                query_key,
+            },
+            auth::require,
+        )
+    };
+
+    let limiter = Arc::new(RateLimiter::new(config.rate_limit.clone()));
+    let cors = Arc::new(AtomicBool::new(config.cors_enabled));
+    let queue = Arc::new(IngestQueue::new(config.ingest_queue.clone()));
+    tokio::spawn({
+        let (queue, db) = (queue.clone(), db.clone());
+        async move { queue.drain(db).await }
+    });
+
+    // Rate limits, the ingest queue, CORS and validation follow
+    // configuration reloads
+    let validator = Arc::new(RwLock::new(Arc::new(validator)));
+    tokio::spawn({
+        let mut live = reloader.subscribe();
+        let (limiter, queue, cors) = (limiter.clone(), queue.clone(), cors.clone());
+        let validator = validator.clone();
+        let mut validation = reloader.current().validation;
+        async move {
+            while live.changed().await.is_ok() {
+                let config = live.borrow_and_update().clone();
+                let server = config.server;
+                limiter.reconfigure(server.rate_limit);
+                queue.reconfigure(server.ingest_queue);
+                cors.store(server.cors_enabled, Ordering::Relaxed);
+                if config.validation != validation {
+                    match Validator::load(&config.validation, &config.data_dir) {
+                        Ok(loaded) => *validator.write().unwrap() = Arc::new(loaded),
+                        Err(e) => error!("Keeping the previous validator: {}", e),
+                    }
+                    validation = config.validation;
+                }
+            }
+        }
+    });
+
+    // Event ingestion (browsers, devices); rate limited after authentication.
+    // `sendBeacon` cannot set headers, so the key may come as `?key=`.
+    let ingest = Router::new()
         .route("/api/events", post(ingest_event))
         .route("/api/events/batch", post(ingest_batch))
-        // API endpoints
-        .route("/api/health", get(health))
+        .route_layer(middleware::from_fn_with_state(
+            limiter.clone(),
+            ratelimit::limit,
+        ))
+        .route_layer(guard(Scope::Ingest, true))
+        .route_layer(middleware::from_fn_with_state(
+            metrics.clone(),
+            metrics::track_ingest,
+        ));
+
+    // Queries (dashboards)
+    let read = Router::new()
+        .route("/api/events/recent", get(recent_events))
         .route("/api/stats", get(stats))
+        .route("/api/stats/timeseries", get(timeseries))
+        .route("/api/stats/counts", get(counts))
+        .route("/api/stats/top/:field", get(top_page_views))
         .route("/api/resources", get(resources))
-        // TODO: Add timeline, query, SSE endpoints
-        .with_state(state);
+        .route("/api/sync/status", get(sync_status))
+        .route("/api/sync/dead-letters", get(list_dead_letters))
+        .route("/metrics", get(prometheus_metrics))
+        .route_layer(guard(Scope::Read, false));
 
-    // Add CORS if enabled
-    if config.cors_enabled {
-        app = app.layer(
-            CorsLayer::new()
-                .allow_origin(Any)
-                .allow_methods(Any)
-                .allow_headers(Any),
-        );
+    // Live stream; `EventSource` cannot set headers either
+    let stream = Router::new()
+        .route("/api/stream", get(stream_events))
+        .route_layer(guard(Scope::Read, true));
+
+    // Hub mode: sync uploads from downstream agents. Always authenticated,
+    // because relayed events keep the timestamps the sender assigned.
+    let relay_max_body = (hub.max_body_mb * 1024 * 1024) as usize;
+    let mut relay = Router::new();
+    if hub.enabled {
+        info!("Hub mode enabled: accepting sync uploads on /api/ingest/batch");
+        relay = relay
+            .route("/api/ingest/capabilities", get(relay_capabilities))
+            .route("/api/ingest/batch", post(relay_batch))
+            .layer(DefaultBodyLimit::max(relay_max_body))
+            .route_layer(middleware::from_fn_with_state(
+                Guard {
+                    db: db.clone(),
+                    required: Scope::Relay,
+                    enforce: true,
+                    query_key: false,
+                },
+                auth::require,
+            ))
+            .route_layer(middleware::from_fn_with_state(
+                metrics.clone(),
+                metrics::track_ingest,
+            ));
     }
 
-    // TODO: Add static file serving for SPA
+    // Management
+    let admin = Router::new()
+        .route("/api/keys", get(list_keys))
+        .route("/api/sync/dead-letters/requeue", post(requeue_dead_letters))
+        .route("/api/config/reload", post(reload_config))
+        .route_layer(guard(Scope::Admin, false));
 
-    let listener = tokio::net::TcpListener::bind(&config.listen).await?;
-    info!("Server listening on {}", config.listen);
+    let (live, _) = broadcast::channel(LIVE_CHANNEL_CAPACITY);
+    let state = Arc::new(AppState {
+        db,
+        db_path,
+        live,
+        validator,
+        limiter,
+        ingest: queue,
+        metrics,
+        sync,
+        relay_max_body,
+        shutdown,
+        reloader,
+    });
 
-    axum::serve(listener, app).await?;
+    let mut app = Router::new()
+        .route("/api/health", get(health))
+        .merge(ingest)
+        .merge(relay)
+        .merge(read)
+        .merge(stream)
+        .merge(admin)
+        .with_state(state);
 
-    Ok(())
+    // CORS can be switched by a reload; while off, no origin is allowed
+    app = app.layer(
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::predicate(move |_, _| {
+                cors.load(Ordering::Relaxed)
+            }))
+            .allow_methods(Any)
+            .allow_headers(Any),
+    );
+
+    // TODO: Add static file serving for SPA
+
+    app
 }
 
 /// Ingest a single event
 async fn ingest_event(
     State(state): State<Arc<AppState>>,
     Json(incoming): Json<IncomingEvent>,
-) -> impl IntoResponse {
+) -> Response {
     let event = incoming.into_event();
 
-    match state.db.insert_event(&event) {
-        Ok(_) => (
-            StatusCode::ACCEPTED,
-            Json(IngestResponse {
-                accepted: vec![event.event_id],
-                rejected: vec![],
-            }),
-        ),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
+    if let Err(reason) = state.validator().check(&event) {
+        state.metrics.event_rejected(Rejection::Invalid);
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
             Json(IngestResponse {
-                accepted: vec![],
                 rejected: vec![RejectedEvent {
                     event_id: Some(event.event_id),
-                    reason: e.to_string(),
+                    index: None,
+                    reason,
                 }],
+                ..Default::default()
             }),
-        ),
+        )
+            .into_response();
+    }
+
+    if let Err(wait) = state
+        .limiter
+        .check(&[(Dimension::Source, &event.source.id)], 1)
+    {
+        state.metrics.event_rejected(Rejection::RateLimited);
+        let response = Json(IngestResponse {
+            rejected: vec![RejectedEvent {
+                event_id: Some(event.event_id),
+                index: None,
+                reason: source_limited(&event.source.id),
+            }],
+            ..Default::default()
+        });
+        return ratelimit::too_many_requests(response.into_response(), wait);
+    }
+
+    let (event, outcome) = match state.ingest.store(vec![event]).await {
+        Ok(mut stored) => stored.pop().expect("one outcome per event"),
+        Err(Full { mut events, .. }) => (events.remove(0), Outcome::Dropped),
+    };
+    match outcome {
+        Outcome::Stored(InsertOutcome::Inserted(seq)) => {
+            let event_id = event.event_id.clone();
+            state.metrics.event_ingested(&event);
+            state.sync.event_stored(&event);
+            let _ = state.live.send((seq, event));
+            (
+                StatusCode::ACCEPTED,
+                Json(IngestResponse {
+                    accepted: vec![event_id],
+                    ..Default::default()
+                }),
+            )
+        }
+        Outcome::Stored(InsertOutcome::Duplicate) => {
+            state.metrics.event_duplicate();
+            (
+                StatusCode::ACCEPTED,
+                Json(IngestResponse {
+                    duplicates: vec![event.event_id],
+                    ..Default::default()
+                }),
+            )
+        }
+        Outcome::Stored(InsertOutcome::Failed(reason)) | Outcome::Failed(reason) => {
+            state.metrics.event_rejected(Rejection::Storage);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(IngestResponse {
+                    rejected: vec![RejectedEvent {
+                        event_id: Some(event.event_id),
+                        index: None,
+                        reason,
+                    }],
+                    ..Default::default()
+                }),
+            )
+        }
+        Outcome::Dropped => {
+            state.metrics.event_shed(&event);
+            let response = Json(IngestResponse {
+                rejected: vec![RejectedEvent {
+                    event_id: Some(event.event_id),
+                    index: None,
+                    reason: QUEUE_FULL.to_string(),
+                }],
+                ..Default::default()
+            });
+            return ingest::service_unavailable(
+                response.into_response(),
+                state.ingest.retry_after(),
+            );
+        }
     }
+    .into_response()
+}
+
+fn source_limited(source_id: &str) -> String {
+    format!("rate limit exceeded for source '{}'", source_id)
 }
 
 /// Ingest a batch of events
+///
+/// Each element is parsed, validated and stored on its own: the response
+/// lists which event_ids were stored, which were already present, and which
+/// were rejected (with their position in the array and the reason). Events
+/// over their source's rate limit are rejected individually; if that leaves
+/// nothing to store, the whole batch gets a 429. A full ingest queue turns
+/// the batch away with a 503, or drops some of its events (see `ingest`).
 async fn ingest_batch(
     State(state): State<Arc<AppState>>,
-    Json(incoming): Json<Vec<IncomingEvent>>,
+    Json(incoming): Json<Vec<serde_json::Value>>,
+) -> Response {
+    let mut response = IngestResponse::default();
+    let mut events = Vec::new();
+    let mut positions = Vec::new();
+    let mut retry_after = None;
+    let validator = state.validator();
+
+    for (index, raw) in incoming.into_iter().enumerate() {
+        let claimed_id = raw
+            .get("event_id")
+            .and_then(|id| id.as_str())
+            .map(str::to_string);
+        let event = match serde_json::from_value::<IncomingEvent>(raw) {
+            Ok(incoming) => incoming.into_event(),
+            Err(e) => {
+                state.metrics.event_rejected(Rejection::Malformed);
+                response.rejected.push(RejectedEvent {
+                    event_id: claimed_id,
+                    index: Some(index),
+                    reason: format!("malformed event: {}", e),
+                });
+                continue;
+            }
+        };
+        if let Err(reason) = validator.check(&event) {
+            state.metrics.event_rejected(Rejection::Invalid);
+            response.rejected.push(RejectedEvent {
+                event_id: Some(event.event_id),
+                index: Some(index),
+                reason,
+            });
+            continue;
+        }
+        if let Err(wait) = state
+            .limiter
+            .check(&[(Dimension::Source, &event.source.id)], 1)
+        {
+            retry_after = retry_after.max(Some(wait));
+            state.metrics.event_rejected(Rejection::RateLimited);
+            let reason = source_limited(&event.source.id);
+            response.rejected.push(RejectedEvent {
+                event_id: Some(event.event_id),
+                index: Some(index),
+                reason,
+            });
+            continue;
+        }
+        events.push(event);
+        positions.push(index);
+    }
+
+    if let (Some(wait), true) = (retry_after, events.is_empty()) {
+        return ratelimit::too_many_requests(Json(response).into_response(), wait);
+    }
+
+    let outcomes =
+        match state.ingest.store(events).await {
+            Ok(outcomes) => outcomes,
+            Err(Full {
+                events,
+                retry_after,
+            }) => {
+                for event in &events {
+                    state.metrics.event_shed(event);
+                }
+                response
+                    .rejected
+                    .extend(events.into_iter().zip(positions).map(|(event, index)| {
+                        RejectedEvent {
+                            event_id: Some(event.event_id),
+                            index: Some(index),
+                            reason: QUEUE_FULL.to_string(),
+                        }
+                    }));
+                response.rejected.sort_by_key(|r| r.index);
+                return ingest::service_unavailable(Json(response).into_response(), retry_after);
+            }
+        };
+
+    let mut status = StatusCode::ACCEPTED;
+    let mut dropped = false;
+    for ((event, outcome), index) in outcomes.into_iter().zip(positions) {
+        let reason = match outcome {
+            Outcome::Stored(InsertOutcome::Inserted(seq)) => {
+                response.accepted.push(event.event_id.clone());
+                state.metrics.event_ingested(&event);
+                state.sync.event_stored(&event);
+                let _ = state.live.send((seq, event));
+                continue;
+            }
+            Outcome::Stored(InsertOutcome::Duplicate) => {
+                state.metrics.event_duplicate();
+                response.duplicates.push(event.event_id);
+                continue;
+            }
+            Outcome::Stored(InsertOutcome::Failed(reason)) => {
+                state.metrics.event_rejected(Rejection::Storage);
+                reason
+            }
+            Outcome::Failed(reason) => {
+                // The write failed as a whole: nothing in it was stored
+                status = StatusCode::INTERNAL_SERVER_ERROR;
+                state.metrics.event_rejected(Rejection::Storage);
+                reason
+            }
+            Outcome::Dropped => {
+                dropped = true;
+                state.metrics.event_shed(&event);
+                QUEUE_FULL.to_string()
+            }
+        };
+        response.rejected.push(RejectedEvent {
+            event_id: Some(event.event_id),
+            index: Some(index),
+            reason,
+        });
+    }
+    response.rejected.sort_by_key(|r| r.index);
+
+    // Everything that got as far as the queue was dropped
+    if dropped
+        && status == StatusCode::ACCEPTED
+        && response.accepted.is_empty()
+        && response.duplicates.is_empty()
+    {
+        return ingest::service_unavailable(
+            Json(response).into_response(),
+            state.ingest.retry_after(),
+        );
+    }
+
+    (status, Json(response)).into_response()
+}
+
+/// Hub mode: content codings accepted by `/api/ingest/batch`
+async fn relay_capabilities() -> Json<CapabilitiesResponse> {
+    Json(CapabilitiesResponse {
+        encodings: compression::ACCEPTED.iter().map(|e| e.as_str()).collect(),
+    })
+}
+
+/// Hub mode: store a sync upload from a downstream agent
+///
+/// Events keep the sender's envelope and are queued for this agent's own
+/// destinations, so they continue upstream. Duplicates are acknowledged like
+/// new events so the sender stops resending them; rejected events count
+/// towards the sender's dead letters.
+async fn relay_batch(
+    State(state): State<Arc<AppState>>,
+    Extension(key): Extension<ApiKey>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let encoding = match headers.get(header::CONTENT_ENCODING) {
+        None => Encoding::Identity,
+        Some(value) => match value.to_str().ok().and_then(Encoding::parse) {
+            Some(encoding) => encoding,
+            None => {
+                return error_response(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "unsupported Content-Encoding",
+                )
+            }
+        },
+    };
+    let body = match encoding.decompress(&body, state.relay_max_body) {
+        Ok(body) => body,
+        Err(e) if e.kind() == std::io::ErrorKind::FileTooLarge => {
+            return error_response(StatusCode::PAYLOAD_TOO_LARGE, &e.to_string())
+        }
+        Err(e) => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                &format!("could not decode {} body: {}", encoding.as_str(), e),
+            )
+        }
+    };
+    let incoming: Vec<serde_json::Value> = match serde_json::from_slice(&body) {
+        Ok(incoming) => incoming,
+        Err(e) => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                &format!("expected a JSON array of events: {}", e),
+            )
+        }
+    };
+
+    let mut response = RelayResponse::default();
+    let mut events = Vec::new();
+    let mut positions = Vec::new();
+    let validator = state.validator();
+    for (index, raw) in incoming.into_iter().enumerate() {
+        let claimed_id = raw
+            .get("event_id")
+            .and_then(|id| id.as_str())
+            .map(str::to_string);
+        let event = match serde_json::from_value::<Event>(raw) {
+            Ok(event) => event.into_relayed(),
+            Err(e) => {
+                state.metrics.event_rejected(Rejection::Malformed);
+                response.rejected.push(RejectedEvent {
+                    event_id: claimed_id,
+                    index: Some(index),
+                    reason: format!("malformed event: {}", e),
+                });
+                continue;
+            }
+        };
+        if let Err(reason) = validator.check(&event) {
+            state.metrics.event_rejected(Rejection::Invalid);
+            response.rejected.push(RejectedEvent {
+                event_id: Some(event.event_id),
+                index: Some(index),
+                reason,
+            });
+            continue;
+        }
+        events.push(event);
+        positions.push(index);
+    }
+
+    // A full queue or a failed write is the sender's cue to retry the whole
+    // batch; whatever was stored meanwhile is acknowledged as a duplicate
+    let outcomes = match state.ingest.store(events).await {
+        Ok(outcomes) => outcomes,
+        Err(full) => {
+            for event in &full.events {
+                state.metrics.event_shed(event);
+            }
+            return ingest::service_unavailable(
+                error_response(StatusCode::SERVICE_UNAVAILABLE, QUEUE_FULL),
+                full.retry_after,
+            );
+        }
+    };
+
+    let mut stored = 0;
+    let mut failure = None;
+    let mut dropped = false;
+    for ((event, outcome), index) in outcomes.into_iter().zip(positions) {
+        let outcome = match outcome {
+            Outcome::Stored(outcome) => outcome,
+            Outcome::Failed(reason) => {
+                state.metrics.event_rejected(Rejection::Storage);
+                failure = Some(reason);
+                continue;
+            }
+            Outcome::Dropped => {
+                state.metrics.event_shed(&event);
+                dropped = true;
+                continue;
+            }
+        };
+        match outcome {
+            InsertOutcome::Inserted(seq) => {
+                stored += 1;
+                response.accepted.push(event.event_id.clone());
+                state.metrics.event_ingested(&event);
+                state.sync.event_stored(&event);
+                let _ = state.live.send((seq, event));
+            }
+            InsertOutcome::Duplicate => {
+                state.metrics.event_duplicate();
+                response.accepted.push(event.event_id);
+            }
+            InsertOutcome::Failed(reason) => {
+                state.metrics.event_rejected(Rejection::Storage);
+                response.rejected.push(RejectedEvent {
+                    event_id: Some(event.event_id),
+                    index: Some(index),
+                    reason,
+                });
+            }
+        }
+    }
+    if let Some(reason) = failure {
+        return error_response(StatusCode::INTERNAL_SERVER_ERROR, &reason);
+    }
+    if dropped {
+        return ingest::service_unavailable(
+            error_response(StatusCode::SERVICE_UNAVAILABLE, QUEUE_FULL),
+            state.ingest.retry_after(),
+        );
+    }
+    response.rejected.sort_by_key(|r| r.index);
+    debug!(
+        "Relayed batch from '{}': {} stored, {} already present, {} rejected",
+        key.name,
+        stored,
+        response.accepted.len() - stored,
+        response.rejected.len()
+    );
+
+    Json(response).into_response()
+}
+
+/// Default and maximum page size for timeline queries
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+/// Timeline query: newest events first, filtered, with cursor pagination
+async fn recent_events(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<TimelineParams>,
 ) -> impl IntoResponse {
-    let events: Vec<Event> = incoming.into_iter().map(|e| e.into_event()).collect();
-    let event_ids: Vec<String> = events.iter().map(|e| e.event_id.clone()).collect();
+    let cursor = match params.cursor.as_deref().map(Cursor::decode) {
+        Some(None) => return error_response(StatusCode::BAD_REQUEST, "invalid cursor"),
+        Some(Some(cursor)) => Some(cursor),
+        None => None,
+    };
+    if let Some(min) = params.min_severity.as_deref() {
+        if !crate::event::validate_severity(min) {
+            return error_response(StatusCode::BAD_REQUEST, "invalid min_severity");
+        }
+    }
 
-    match state.db.insert_events(&events) {
-        Ok(count) => (
-            StatusCode::ACCEPTED,
-            Json(IngestResponse {
-                accepted: event_ids[..count].to_vec(),
-                rejected: vec![],
-            }),
-        ),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(IngestResponse {
-                accepted: vec![],
-                rejected: vec![RejectedEvent {
-                    event_id: None,
-                    reason: e.to_string(),
-                }],
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let filter = EventFilter {
+        since: params.since,
+        until: params.until,
+        category: params.category,
+        event_type: params.event_type,
+        severity: params.severity,
+        min_severity: params.min_severity,
+        source_type: params.source_type,
+        source_id: params.source_id,
+        correlation_id: params.correlation_id,
+        session_id: params.session_id,
+        incident_id: params.incident_id,
+    };
+
+    let page = state
+        .db
+        .blocking(move |db| db.query_events(&filter, cursor.as_ref(), limit))
+        .await;
+    match page {
+        Ok(page) => (
+            StatusCode::OK,
+            Json(TimelineResponse {
+                events: page.events,
+                next_cursor: page.next_cursor.map(|c| c.encode()),
             }),
-        ),
+        )
+            .into_response(),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
     }
 }
 
+/// Page size used when replaying missed events to a stream subscriber
+const REPLAY_PAGE_SIZE: usize = 500;
+
+/// Live event stream (Server-Sent Events)
+///
+/// Each SSE `id` is the event's stream sequence, assigned when it was
+/// committed. A reconnecting client sends it back as `Last-Event-ID` (or
+/// `?last_event_id=`) and receives everything committed after it before
+/// switching to live events.
+async fn stream_events(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<StreamParams>,
+) -> axum::response::Response {
+    if let Some(min) = params.min_severity.as_deref() {
+        if !crate::event::validate_severity(min) {
+            return error_response(StatusCode::BAD_REQUEST, "invalid min_severity");
+        }
+    }
+
+    let resume = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .or(params.last_event_id);
+    let resume = match resume {
+        Some(raw) => match resume_position(&state.db, raw).await {
+            Ok(seq) => seq,
+            Err(message) => return error_response(StatusCode::BAD_REQUEST, &message),
+        },
+        None => None,
+    };
+
+    let filter = EventFilter {
+        category: params.category,
+        event_type: params.event_type,
+        severity: params.severity,
+        min_severity: params.min_severity,
+        source_id: params.source_id,
+        ..Default::default()
+    };
+
+    // Subscribe before reading the head so nothing committed meanwhile is lost
+    let mut rx = state.live.subscribe();
+    let head = match state.db.blocking(|db| db.stream_head()).await {
+        Ok(head) => head,
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    };
+
+    let stream = async_stream::stream! {
+        // Every event up to this sequence has been sent or filtered out
+        let mut cursor = resume.unwrap_or(head);
+        let mut behind = resume.is_some();
+
+        loop {
+            if behind {
+                let head = match state.db.blocking(|db| db.stream_head()).await {
+                    Ok(head) => head,
+                    Err(e) => {
+                        warn!("Stream replay failed: {}", e);
+                        return;
+                    }
+                };
+                loop {
+                    let (from, page_filter) = (cursor, filter.clone());
+                    let events = state.db.blocking(move |db| {
+                        db.events_in_stream(from, head, &page_filter, REPLAY_PAGE_SIZE)
+                    });
+                    let events = match events.await {
+                        Ok(events) => events,
+                        Err(e) => {
+                            warn!("Stream replay failed: {}", e);
+                            return;
+                        }
+                    };
+                    let done = events.len() < REPLAY_PAGE_SIZE;
+                    for (seq, event) in events {
+                        cursor = seq;
+                        yield sse_event(seq, &event);
+                    }
+                    if done {
+                        break;
+                    }
+                }
+                cursor = cursor.max(head);
+                behind = false;
+            }
+
+            let received = tokio::select! {
+                _ = state.shutdown.triggered() => return,
+                received = rx.recv() => received,
+            };
+            match received {
+                // Already replayed
+                Ok((seq, _)) if seq <= cursor => {}
+                // Handlers broadcast in whatever order they are scheduled, and
+                // imports are never broadcast, so a gap means something
+                // committed earlier is missing: catch up from SQLite
+                Ok((seq, _)) if seq > cursor + 1 => behind = true,
+                Ok((seq, event)) => {
+                    cursor = seq;
+                    if filter.matches(&event) {
+                        yield sse_event(seq, &event);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    debug!("Stream subscriber lagged by {} events, replaying", skipped);
+                    behind = true;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    };
+
+    Sse::new(stream)
+        .keep_alive(
+            KeepAlive::new()
+                .interval(Duration::from_secs(15))
+                .text("heartbeat"),
+        )
+        .into_response()
+}
+
+/// Stream sequence to resume after, from a client's `Last-Event-ID`
+///
+/// Ids sent before streams used sequences (`{arrived_at_ms}:{event_id}`)
+/// resume after that event, or from now if it is gone.
+async fn resume_position(db: &Database, raw: String) -> std::result::Result<Option<i64>, String> {
+    if let Ok(seq) = raw.parse::<i64>() {
+        if seq >= 0 {
+            return Ok(Some(seq));
+        }
+    }
+    let Some((_, event_id)) = raw.split_once(':') else {
+        return Err("invalid Last-Event-ID".to_string());
+    };
+    let event_id = event_id.to_string();
+    db.blocking(move |db| db.stream_seq_of(&event_id))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+fn sse_event(seq: i64, event: &Event) -> std::result::Result<SseEvent, axum::Error> {
+    SseEvent::default()
+        .event("event")
+        .id(seq.to_string())
+        .json_data(event)
+}
+
 /// Health check endpoint
 async fn health(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    let event_count = state.db.event_count().unwrap_or(-1);
-    let pending_sync = state.db.pending_sync_count().unwrap_or(-1);
+    let (event_count, pending_sync) = state
+        .db
+        .blocking(|db| {
+            (
+                db.event_count().unwrap_or(-1),
+                db.pending_sync_count().unwrap_or(-1),
+            )
+        })
+        .await;
+    let sync = state.sync.snapshot(&[]);
+    // The destination that has gone longest without a successful batch
+    let last_sync_success = sync
+        .destinations
+        .iter()
+        .map(|d| d.last_success)
+        .min()
+        .flatten();
 
     Json(HealthResponse {
         status: "ok".to_string(),
         version: env!("CARGO_PKG_VERSION").to_string(),
         event_count,
         pending_sync,
+        sync_state: sync.state,
+        last_sync_success,
     })
 }
 
+/// Sync status per destination: state, last attempt/success/error and backlog drain estimate
+async fn sync_status(State(state): State<Arc<AppState>>) -> Json<SyncOverview> {
+    let counts = state
+        .db
+        .blocking(|db| db.outbox_counts().unwrap_or_default())
+        .await;
+    Json(state.sync.snapshot(&counts))
+}
+
 /// Stats endpoint
 async fn stats(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    let event_count = state.db.event_count().unwrap_or(0);
-    let pending_sync = state.db.pending_sync_count().unwrap_or(0);
+    let (event_count, pending_sync, dead_letters) = state
+        .db
+        .blocking(|db| {
+            (
+                db.event_count().unwrap_or(0),
+                db.pending_sync_count().unwrap_or(0),
+                db.dead_letter_count().unwrap_or(0),
+            )
+        })
+        .await;
 
     Json(StatsResponse {
         total_events: event_count,
         pending_sync,
+        dead_letters,
+        rate_limited: state.limiter.stats(),
+    })
+}
+
+/// Most buckets one counts or timeseries request may cover
+const MAX_BUCKETS: i64 = 2000;
+/// Groups listed by `/api/stats/counts` unless `limit` says otherwise
+const DEFAULT_GROUPS: usize = 10;
+
+/// Event counts per minute, hour or day
+///
+/// The ungrouped form of `/api/stats/counts`, one `{start, count}` per bucket.
+async fn timeseries(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<CountParams>,
+) -> axum::response::Response {
+    let query = match count_query(params) {
+        Ok(query) => CountQuery {
+            group_by: Vec::new(),
+            ..query
+        },
+        Err(message) => return error_response(StatusCode::BAD_REQUEST, &message),
+    };
+
+    match state
+        .db
+        .blocking(move |db| aggregate::count(db, &query))
+        .await
+    {
+        Ok(counts) => {
+            let series = counts.groups.into_iter().next().map(|g| g.counts);
+            Json(TimeseriesResponse {
+                interval: counts.interval,
+                tz: counts.tz,
+                buckets: counts
+                    .buckets
+                    .iter()
+                    .enumerate()
+                    .map(|(i, start)| TimeseriesBucket {
+                        start: *start,
+                        count: series.as_ref().map_or(0, |s| s[i]),
+                    })
+                    .collect(),
+                total: counts.total,
+            })
+            .into_response()
+        }
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+/// Event counts per time bucket, grouped by `group_by` dimensions
+///
+/// Lists the `limit` largest groups; the rest are summed into `other`.
+async fn counts(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<CountParams>,
+) -> axum::response::Response {
+    let query = match count_query(params) {
+        Ok(query) => query,
+        Err(message) => return error_response(StatusCode::BAD_REQUEST, &message),
+    };
+    match state
+        .db
+        .blocking(move |db| aggregate::count(db, &query))
+        .await
+    {
+        Ok(counts) => Json(counts).into_response(),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+/// Most frequent values of a page view field (path, referrer host, UTM
+/// tags, device type or browser)
+///
+/// Covers the last 24 hours unless `since` is given.
+async fn top_page_views(
+    State(state): State<Arc<AppState>>,
+    Path(field): Path<WebField>,
+    Query(params): Query<TopParams>,
+) -> axum::response::Response {
+    let until = params.until.unwrap_or_else(Utc::now);
+    let since = params
+        .since
+        .unwrap_or_else(|| until - chrono::Duration::hours(24));
+    if since >= until {
+        return error_response(StatusCode::BAD_REQUEST, "since must be before until");
+    }
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_GROUPS)
+        .clamp(1, aggregate::MAX_GROUPS);
+
+    let (from, to) = (since.timestamp_millis(), until.timestamp_millis());
+    let top = state
+        .db
+        .blocking(move |db| db.top_page_views(field, from, to, limit))
+        .await;
+    match top {
+        Ok(top) => Json(TopResponse {
+            field,
+            since,
+            until,
+            total: top.total,
+            values: top.values,
+            other: top.other,
+        })
+        .into_response(),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+/// Validate count parameters and fill in defaults
+///
+/// Without `since`, covers the last hour, 24 hours or 30 days (for minute,
+/// hour and day buckets) up to `until`, which defaults to now.
+fn count_query(params: CountParams) -> std::result::Result<CountQuery, String> {
+    if let Some(min) = params.min_severity.as_deref() {
+        if !crate::event::validate_severity(min) {
+            return Err("invalid min_severity".to_string());
+        }
+    }
+    let tz = match params.tz.as_deref().map(str::parse::<Tz>) {
+        None => Tz::UTC,
+        Some(Ok(tz)) => tz,
+        Some(Err(_)) => return Err("unknown time zone".to_string()),
+    };
+    let mut group_by: Vec<aggregate::Dimension> = Vec::new();
+    for name in params.group_by.iter().flat_map(|list| list.split(',')) {
+        match name.trim().parse() {
+            Ok(dimension) if !group_by.contains(&dimension) => group_by.push(dimension),
+            Ok(_) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    let interval = params.interval;
+    let until = params.until.unwrap_or_else(Utc::now);
+    let since = params.since.unwrap_or_else(|| match interval {
+        Interval::Minute => until - chrono::Duration::hours(1),
+        Interval::Hour => until - chrono::Duration::hours(24),
+        Interval::Day => until - chrono::Duration::days(30),
+    });
+    if since >= until {
+        return Err("since must be before until".to_string());
+    }
+    if (until - since).num_milliseconds() / interval.millis() >= MAX_BUCKETS {
+        return Err(format!("range covers more than {} buckets", MAX_BUCKETS));
+    }
+
+    Ok(CountQuery {
+        interval,
+        tz,
+        since,
+        until,
+        group_by,
+        limit: params
+            .limit
+            .unwrap_or(DEFAULT_GROUPS)
+            .clamp(1, aggregate::MAX_GROUPS),
+        filter: EventFilter {
+            category: params.category,
+            event_type: params.event_type,
+            severity: params.severity,
+            min_severity: params.min_severity,
+            source_type: params.source_type,
+            source_id: params.source_id,
+            ..Default::default()
+        },
     })
 }
 
 /// Resource monitoring endpoint
 async fn resources(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    // Get system info
+    let (cpu_percent, ram_bytes) = system_usage();
+
+    // Get database file size
+    let db_size_bytes = std::fs::metadata(&state.db_path)
+        .map(|m| m.len())
+        .unwrap_or(0);
+
+    Json(ResourcesResponse {
+        cpu_percent: (cpu_percent * 10.0).round() / 10.0, // 1 decimal place
+        ram_mb: (ram_bytes as f64 / 1024.0 / 1024.0 * 10.0).round() / 10.0,
+        db_size_mb: (db_size_bytes as f64 / 1024.0 / 1024.0 * 100.0).round() / 100.0,
+        sync_status: state.sync.phase().as_str().to_string(),
+    })
+}
+
+/// Prometheus / OpenMetrics scrape endpoint
+async fn prometheus_metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let (cpu_percent, memory_bytes) = system_usage();
+    let file_size = |path: &std::path::Path| std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    let mut wal_path = state.db_path.clone().into_os_string();
+    wal_path.push("-wal");
+
+    let outbox = state
+        .db
+        .blocking(|db| db.outbox_counts().unwrap_or_default())
+        .await;
+    let body = state.metrics.encode(&Sampled {
+        outbox,
+        ingest_queue_depth: state.ingest.depth(),
+        db_bytes: file_size(&state.db_path),
+        wal_bytes: file_size(wal_path.as_ref()),
+        cpu_percent,
+        memory_bytes,
+    });
+
+    ([(header::CONTENT_TYPE, metrics::CONTENT_TYPE)], body)
+}
+
+/// CPU usage (average across all cores) and this process's resident memory in bytes
+fn system_usage() -> (f32, u64) {
     let mut sys = System::new();
     sys.refresh_cpu_usage();
     sys.refresh_memory();
 
-    // Calculate CPU usage (average across all cores)
     let cpu_percent = sys.cpus().iter().map(|c| c.cpu_usage()).sum::<f32>()
         / sys.cpus().len().max(1) as f32;
 
-    // Get memory usage for this process
     let ram_bytes = sysinfo::get_current_pid()
         .ok()
         .and_then(|pid| {
@@ -162,45 +1162,307 @@ async fn resources(State(state): State<Arc<AppState>>) -> impl IntoResponse {
         })
         .unwrap_or(0);
 
-    // Get database file size
-    let db_size_bytes = std::fs::metadata(&state.db_path)
-        .map(|m| m.len())
-        .unwrap_or(0);
+    (cpu_percent, ram_bytes)
+}
 
-    Json(ResourcesResponse {
-        cpu_percent: (cpu_percent * 10.0).round() / 10.0, // 1 decimal place
-        ram_mb: (ram_bytes as f64 / 1024.0 / 1024.0 * 10.0).round() / 10.0,
-        db_size_mb: (db_size_bytes as f64 / 1024.0 / 1024.0 * 100.0).round() / 100.0,
-        sync_status: "connected".to_string(), // TODO: get actual sync status
-    })
+pub(crate) fn error_response(status: StatusCode, message: &str) -> axum::response::Response {
+    (
+        status,
+        Json(ErrorResponse {
+            error: message.to_string(),
+        }),
+    )
+        .into_response()
+}
+
+/// List API keys (never their secrets)
+async fn list_keys(State(state): State<Arc<AppState>>) -> axum::response::Response {
+    match state.db.blocking(|db| db.list_api_keys()).await {
+        Ok(keys) => Json(
+            keys.into_iter()
+                .map(|(key, revoked_at)| KeyResponse {
+                    id: key.id,
+                    name: key.name,
+                    scope: key.scope,
+                    public: key.public,
+                    allowed_origins: key.allowed_origins,
+                    created_at: key.created_at,
+                    revoked_at: revoked_at.and_then(DateTime::from_timestamp_millis),
+                })
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+/// Events a destination rejected too many times, newest first
+async fn list_dead_letters(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DeadLetterParams>,
+) -> axum::response::Response {
+    let cursor = match params.cursor.as_deref().map(Cursor::decode) {
+        Some(None) => return error_response(StatusCode::BAD_REQUEST, "invalid cursor"),
+        Some(Some(cursor)) => Some(cursor),
+        None => None,
+    };
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    let page = state
+        .db
+        .blocking(move |db| {
+            db.list_dead_letters(params.destination.as_deref(), cursor.as_ref(), limit)
+        })
+        .await;
+    match page {
+        Ok(page) => Json(DeadLetterResponse {
+            dead_letters: page.dead_letters,
+            next_cursor: page.next_cursor.map(|c| c.encode()),
+        })
+        .into_response(),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+/// Send dead letters again (after fixing the hub or its schema)
+async fn requeue_dead_letters(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<RequeueRequest>,
+) -> axum::response::Response {
+    let event_ids = match (request.event_ids, request.all) {
+        (Some(ids), false) => Some(ids),
+        (None, true) => None,
+        _ => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "specify either event_ids or \"all\": true",
+            )
+        }
+    };
+
+    let requeued = state
+        .db
+        .blocking(move |db| {
+            db.requeue_dead_letters(request.destination.as_deref(), event_ids.as_deref())
+        })
+        .await;
+    match requeued {
+        Ok(requeued) => {
+            info!("Requeued {} dead-lettered events", requeued);
+            Json(RequeueResponse { requeued }).into_response()
+        }
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+/// Re-read the configuration, like SIGHUP
+async fn reload_config(State(state): State<Arc<AppState>>) -> axum::response::Response {
+    match state.reloader.reload() {
+        Ok(report) => {
+            report.log();
+            Json(report).into_response()
+        }
+        Err(e) => {
+            warn!("Reload failed, configuration unchanged: {}", e);
+            let status = match e {
+                Error::Config(_) => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            error_response(status, &e.to_string())
+        }
+    }
+}
+
+// Request types
+
+#[derive(Deserialize)]
+struct TimelineParams {
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    category: Option<String>,
+    #[serde(rename = "type")]
+    event_type: Option<String>,
+    severity: Option<String>,
+    min_severity: Option<String>,
+    source_type: Option<String>,
+    source_id: Option<String>,
+    correlation_id: Option<String>,
+    session_id: Option<String>,
+    incident_id: Option<String>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct CountParams {
+    #[serde(default)]
+    interval: Interval,
+    /// IANA time zone name (default UTC)
+    tz: Option<String>,
+    /// Comma-separated dimensions (`/api/stats/counts` only)
+    group_by: Option<String>,
+    /// Groups to list before `other` (`/api/stats/counts` only)
+    limit: Option<usize>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    category: Option<String>,
+    #[serde(rename = "type")]
+    event_type: Option<String>,
+    severity: Option<String>,
+    min_severity: Option<String>,
+    source_type: Option<String>,
+    source_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TopParams {
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct DeadLetterParams {
+    destination: Option<String>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct RequeueRequest {
+    /// None = every destination
+    destination: Option<String>,
+    event_ids: Option<Vec<String>>,
+    #[serde(default)]
+    all: bool,
+}
+
+#[derive(Deserialize)]
+struct StreamParams {
+    category: Option<String>,
+    #[serde(rename = "type")]
+    event_type: Option<String>,
+    severity: Option<String>,
+    min_severity: Option<String>,
+    source_id: Option<String>,
+    /// Fallback for clients that cannot set the `Last-Event-ID` header
+    last_event_id: Option<String>,
 }
 
 // Response types
 
 #[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+#[derive(Serialize)]
+struct TimelineResponse {
+    events: Vec<Event>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DeadLetterResponse {
+    dead_letters: Vec<DeadLetter>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RequeueResponse {
+    requeued: usize,
+}
+
+#[derive(Serialize, Default)]
 struct IngestResponse {
+    /// Newly stored events
+    accepted: Vec<String>,
+    /// Events whose event_id was already stored (safe to treat as delivered)
+    duplicates: Vec<String>,
+    rejected: Vec<RejectedEvent>,
+}
+
+/// Hub-mode upload result, in the shape the sync worker expects
+#[derive(Serialize, Default)]
+struct RelayResponse {
+    /// Stored or already present; the sender may drop these from its queue
     accepted: Vec<String>,
     rejected: Vec<RejectedEvent>,
 }
 
+#[derive(Serialize)]
+struct CapabilitiesResponse {
+    encodings: Vec<&'static str>,
+}
+
 #[derive(Serialize)]
 struct RejectedEvent {
     event_id: Option<String>,
+    /// Position in the submitted batch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<usize>,
     reason: String,
 }
 
+#[derive(Serialize)]
+struct KeyResponse {
+    id: String,
+    name: String,
+    scope: Scope,
+    public: bool,
+    allowed_origins: Option<Vec<String>>,
+    created_at: DateTime<Utc>,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
 #[derive(Serialize)]
 struct HealthResponse {
     status: String,
     version: String,
     event_count: i64,
     pending_sync: i64,
+    sync_state: SyncPhase,
+    last_sync_success: Option<DateTime<Utc>>,
 }
 
 #[derive(Serialize)]
 struct StatsResponse {
     total_events: i64,
     pending_sync: i64,
+    /// Deliveries the destinations permanently rejected (see `/api/sync/dead-letters`)
+    dead_letters: i64,
+    /// Ingest drops by the rate limiter since startup
+    rate_limited: RateLimitStats,
+}
+
+#[derive(Serialize)]
+struct TimeseriesResponse {
+    interval: Interval,
+    tz: String,
+    buckets: Vec<TimeseriesBucket>,
+    total: i64,
+}
+
+#[derive(Serialize)]
+struct TopResponse {
+    field: WebField,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+    /// Page views in the window
+    total: i64,
+    /// Most frequent first; `null` counts page views without the field
+    values: Vec<TopValue>,
+    /// Page views with any other value
+    other: i64,
+}
+
+#[derive(Serialize)]
+struct TimeseriesBucket {
+    start: DateTime<FixedOffset>,
+    count: i64,
 }
 
 #[derive(Serialize)]
@@ -210,3 +1472,420 @@ struct ResourcesResponse {
     db_size_mb: f64,
     sync_status: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, ValidationMode};
+    use crate::destination::Destination;
+    use axum::body::{Body, HttpBody};
+    use axum::http::Request;
+    use chrono::SubsecRound;
+    use serde_json::{json, Value};
+    use std::pin::Pin;
+    use tempfile::{tempdir, TempDir};
+    use tower::Service;
+
+    struct Harness {
+        _dir: TempDir,
+        db: Database,
+        app: Router,
+        relay_token: String,
+        ingest_token: String,
+        reloader: Reloader,
+        /// What the next reload reads
+        next: Arc<std::sync::Mutex<Config>>,
+    }
+
+    fn harness() -> Harness {
+        let dir = tempdir().unwrap();
+        let mut config = Config {
+            data_dir: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        config.hub.enabled = true;
+        config.sync.hub_url = "http://upstream.invalid".to_string();
+        let destinations = Destination::from_config(&config.sync).unwrap();
+        let db = Database::open(&config.db_path()).unwrap();
+        db.migrate().unwrap();
+        db.set_destinations(&destinations).unwrap();
+        let (_, relay_token) = auth::create_key(&db, "barn", Scope::Relay, false, vec![]).unwrap();
+        let (_, ingest_token) =
+            auth::create_key(&db, "camera", Scope::Ingest, false, vec![]).unwrap();
+
+        let sync = SyncState::new(&config.sync, &destinations);
+        let validator = Validator::new(config.validation.mode);
+        let next = Arc::new(std::sync::Mutex::new(config.clone()));
+        let reloader = Reloader::new(config, {
+            let next = next.clone();
+            move || Ok(next.lock().unwrap().clone())
+        });
+        let app = router(
+            reloader.clone(),
+            db.clone(),
+            validator,
+            Arc::new(Metrics::new()),
+            sync,
+            Shutdown::new(),
+        );
+        Harness {
+            _dir: dir,
+            db,
+            app,
+            relay_token,
+            ingest_token,
+            reloader,
+            next,
+        }
+    }
+
+    /// An event as a downstream agent's sync worker sends it
+    fn forwarded(event_id: &str, received_at: DateTime<Utc>) -> Value {
+        json!({
+            "event_id": event_id,
+            "observed_at": received_at - chrono::Duration::seconds(5),
+            "received_at": received_at,
+            "source": {"type": "edge_device", "id": "barn-cam", "version": "1.2.0"},
+            "event": {"category": "iot", "type": "motion", "severity": "warn", "data": {"zone": 2}},
+            "sync": {"synced": true, "source_seq": 41}
+        })
+    }
+
+    fn relay_request(token: Option<&str>, encoding: Encoding, events: &Value) -> Request<Body> {
+        let body = encoding
+            .compress(&serde_json::to_vec(events).unwrap())
+            .unwrap();
+        let mut request =
+            Request::post("/api/ingest/batch").header(header::CONTENT_TYPE, "application/json");
+        if encoding != Encoding::Identity {
+            request = request.header(header::CONTENT_ENCODING, encoding.as_str());
+        }
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        request.body(Body::from(body)).unwrap()
+    }
+
+    async fn send(app: &Router, request: Request<Body>) -> (StatusCode, Value) {
+        let response = app.clone().call(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    /// Next chunk of a streaming response body
+    async fn next_chunk(body: &mut Body) -> String {
+        let frame = std::future::poll_fn(|cx| Pin::new(&mut *body).poll_frame(cx));
+        let frame = tokio::time::timeout(Duration::from_secs(5), frame)
+            .await
+            .expect("no event within 5s")
+            .expect("stream ended")
+            .unwrap();
+        String::from_utf8(frame.into_data().unwrap().to_vec()).unwrap()
+    }
+
+    fn stored(db: &Database) -> Vec<Event> {
+        db.events_in_stream(0, i64::MAX, &EventFilter::default(), 100)
+            .unwrap()
+            .into_iter()
+            .map(|(_, event)| event)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_relay_requires_relay_scope() {
+        let h = harness();
+        let events = json!([forwarded("e-1", Utc::now())]);
+
+        let (status, _) = send(&h.app, relay_request(None, Encoding::Identity, &events)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let request = relay_request(Some(&h.ingest_token), Encoding::Identity, &events);
+        let (status, _) = send(&h.app, request).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(stored(&h.db).is_empty());
+
+        let request = relay_request(Some(&h.relay_token), Encoding::Identity, &events);
+        let (status, body) = send(&h.app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["accepted"], json!(["e-1"]));
+    }
+
+    #[tokio::test]
+    async fn test_relay_reports_duplicates_as_accepted() {
+        let h = harness();
+        let events = json!([forwarded("e-1", Utc::now()), forwarded("e-2", Utc::now())]);
+        let request = relay_request(Some(&h.relay_token), Encoding::Identity, &events);
+        send(&h.app, request).await;
+
+        // A retried batch, e.g. after the first response was lost
+        let events = json!([
+            forwarded("e-1", Utc::now()),
+            forwarded("e-2", Utc::now()),
+            forwarded("e-3", Utc::now()),
+            {"event_id": "e-4", "source": "garbage"}
+        ]);
+        let request = relay_request(Some(&h.relay_token), Encoding::Identity, &events);
+        let (status, body) = send(&h.app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["accepted"], json!(["e-1", "e-2", "e-3"]));
+        assert_eq!(body["rejected"][0]["event_id"], "e-4");
+        assert_eq!(body["rejected"][0]["index"], 3);
+        assert_eq!(stored(&h.db).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_relay_accepts_compressed_bodies() {
+        let h = harness();
+        for (i, encoding) in [Encoding::Gzip, Encoding::Zstd].into_iter().enumerate() {
+            let id = format!("e-{}", i);
+            let events = json!([forwarded(&id, Utc::now())]);
+            let request = relay_request(Some(&h.relay_token), encoding, &events);
+            let (status, body) = send(&h.app, request).await;
+            assert_eq!(status, StatusCode::OK, "{}", encoding.as_str());
+            assert_eq!(body["accepted"], json!([id]));
+        }
+        assert_eq!(stored(&h.db).len(), 2);
+
+        let mut request = relay_request(Some(&h.relay_token), Encoding::Identity, &json!([]));
+        request
+            .headers_mut()
+            .insert(header::CONTENT_ENCODING, "br".parse().unwrap());
+        let (status, _) = send(&h.app, request).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let mut request = relay_request(Some(&h.relay_token), Encoding::Identity, &json!([]));
+        request
+            .headers_mut()
+            .insert(header::CONTENT_ENCODING, "gzip".parse().unwrap());
+        let (status, _) = send(&h.app, request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_relay_preserves_envelope() {
+        let h = harness();
+        let received_at = Utc::now() - chrono::Duration::hours(3);
+        let sent = forwarded("e-1", received_at);
+        let request = relay_request(Some(&h.relay_token), Encoding::Identity, &json!([sent]));
+        let before = Utc::now().trunc_subsecs(3);
+        send(&h.app, request).await;
+
+        let event = stored(&h.db).pop().unwrap();
+        let sent: Event = serde_json::from_value(sent).unwrap();
+        assert_eq!(event.event_id, sent.event_id);
+        assert_eq!(event.observed_at, sent.observed_at.trunc_subsecs(3));
+        assert_eq!(event.received_at, sent.received_at.trunc_subsecs(3));
+        assert_eq!(event.source, sent.source);
+        assert_eq!(event.event, sent.event);
+        let sync = event.sync.unwrap();
+        assert_eq!(sync.source_seq, Some(41));
+        // Queued to be forwarded from here, and stamped with its arrival
+        assert!(!sync.synced);
+        assert_eq!(h.db.pending_sync_count().unwrap(), 1);
+        assert!(sync.relayed_at.unwrap() >= before);
+    }
+
+    fn ingest_request(path: &str, body: &Value) -> Request<Body> {
+        Request::post(path)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(body).unwrap()))
+            .unwrap()
+    }
+
+    fn incoming(event_id: &str) -> Value {
+        json!({
+            "event_id": event_id,
+            "source": {"type": "browser", "id": "session-1"},
+            "event": {"category": "web", "type": "page_view", "data": {"path": "/"}}
+        })
+    }
+
+    #[tokio::test]
+    async fn test_ingest_batch_reports_each_element() {
+        let h = harness();
+        let (status, _) = send(&h.app, ingest_request("/api/events", &incoming("dup-1"))).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let batch = json!([
+            incoming("ok-1"),
+            {"event_id": "bad-1", "event": {"category": "web"}},
+            incoming("dup-1"),
+            42,
+            incoming("ok-2")
+        ]);
+        let (status, body) = send(&h.app, ingest_request("/api/events/batch", &batch)).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body["accepted"], json!(["ok-1", "ok-2"]));
+        assert_eq!(body["duplicates"], json!(["dup-1"]));
+
+        let rejected = body["rejected"].as_array().unwrap();
+        assert_eq!(rejected.len(), 2);
+        assert_eq!(rejected[0]["event_id"], "bad-1");
+        assert_eq!(rejected[0]["index"], 1);
+        assert_eq!(rejected[1]["event_id"], Value::Null);
+        assert_eq!(rejected[1]["index"], 3);
+        for r in rejected {
+            assert!(r["reason"].as_str().unwrap().starts_with("malformed event"));
+        }
+        assert_eq!(stored(&h.db).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_default_config_stores_unconventional_types() {
+        let h = harness();
+        let mut event = incoming("pv-1");
+        event["event"]["type"] = json!("Page View");
+        let (status, body) = send(&h.app, ingest_request("/api/events", &event)).await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{}", body);
+        assert_eq!(stored(&h.db)[0].event.event_type, "Page View");
+    }
+
+    #[tokio::test]
+    async fn test_reload_replaces_validator() {
+        let h = harness();
+        let invalid = |id: &str| {
+            let mut event = incoming(id);
+            event["event"]["category"] = json!("bogus");
+            event
+        };
+        let (status, _) = send(&h.app, ingest_request("/api/events", &invalid("bad-0"))).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        h.next.lock().unwrap().validation.mode = ValidationMode::Warn;
+        assert_eq!(h.reloader.reload().unwrap().applied, ["validation.mode"]);
+
+        // The server swaps validators in the background
+        for attempt in 1.. {
+            let request = ingest_request("/api/events", &invalid(&format!("bad-{}", attempt)));
+            let (status, _) = send(&h.app, request).await;
+            if status == StatusCode::ACCEPTED {
+                break;
+            }
+            assert!(attempt < 100, "validator not replaced");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_replays_after_lag_before_first_event() {
+        let h = harness();
+        let request = Request::get("/api/stream").body(Body::empty()).unwrap();
+        let response = h.app.clone().call(request).await.unwrap();
+        let mut body = response.into_body();
+
+        // More than the live channel holds, before the stream is first polled
+        let mut ids = Vec::new();
+        for batch in 0..4 {
+            let events: Vec<Value> = (0..LIVE_CHANNEL_CAPACITY / 3)
+                .map(|i| {
+                    let id = format!("e-{}-{:04}", batch, i);
+                    ids.push(id.clone());
+                    json!({
+                        "event_id": id,
+                        "source": {"type": "server", "id": format!("s-{}", i)},
+                        "event": {"category": "ops", "type": "tick", "data": {}}
+                    })
+                })
+                .collect();
+            let request = ingest_request("/api/events/batch", &json!(events));
+            let (status, _) = send(&h.app, request).await;
+            assert_eq!(status, StatusCode::ACCEPTED);
+        }
+        assert!(ids.len() > LIVE_CHANNEL_CAPACITY);
+
+        // Lagged: the stream falls back to SQLite and misses nothing
+        let mut seen = String::new();
+        while !seen.contains(ids.last().unwrap()) {
+            seen.push_str(&next_chunk(&mut body).await);
+        }
+        for id in &ids {
+            assert!(seen.contains(&format!("\"event_id\":\"{}\"", id)), "{}", id);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_catches_up_on_events_committed_out_of_order() {
+        let h = harness();
+        let request = Request::get("/api/stream").body(Body::empty()).unwrap();
+        let response = h.app.clone().call(request).await.unwrap();
+        let mut body = response.into_body();
+
+        // Committed first, but its handler has not broadcast it yet
+        let early = serde_json::from_value::<IncomingEvent>(incoming("early"))
+            .unwrap()
+            .into_event();
+        h.db.insert_events(std::slice::from_ref(&early)).unwrap();
+        let (status, _) = send(&h.app, ingest_request("/api/events", &incoming("late"))).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let mut seen = String::new();
+        while !seen.contains("\"event_id\":\"late\"") {
+            seen.push_str(&next_chunk(&mut body).await);
+        }
+        let early_at = seen
+            .find("\"event_id\":\"early\"")
+            .expect("early event streamed");
+        assert!(early_at < seen.find("\"event_id\":\"late\"").unwrap());
+        assert!(
+            seen.contains("id: 1\n") && seen.contains("id: 2\n"),
+            "{}",
+            seen
+        );
+
+        // A client that saw the early event resumes after it
+        let request = Request::get("/api/stream")
+            .header("last-event-id", "1")
+            .body(Body::empty())
+            .unwrap();
+        let response = h.app.clone().call(request).await.unwrap();
+        let chunk = next_chunk(&mut response.into_body()).await;
+        assert!(chunk.contains("\"event_id\":\"late\""), "{}", chunk);
+
+        // Ids from before sequences resume after the event they name
+        let request = Request::get("/api/stream")
+            .header("last-event-id", "1700000000000:early")
+            .body(Body::empty())
+            .unwrap();
+        let response = h.app.clone().call(request).await.unwrap();
+        let chunk = next_chunk(&mut response.into_body()).await;
+        assert!(chunk.contains("\"event_id\":\"late\""), "{}", chunk);
+
+        let request = Request::get("/api/stream")
+            .header("last-event-id", "garbage")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = send(&h.app, request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_stream_replays_relayed_events_in_commit_order() {
+        let h = harness();
+        let local = serde_json::from_value::<IncomingEvent>(json!({
+            "event_id": "local",
+            "source": {"type": "server", "id": "site"},
+            "event": {"category": "ops", "type": "boot", "data": {}}
+        }))
+        .unwrap()
+        .into_event();
+        h.db.insert_events(std::slice::from_ref(&local)).unwrap();
+
+        // Received downstream a day ago, but arrives here after `local`
+        let events = json!([forwarded("relayed", Utc::now() - chrono::Duration::days(1))]);
+        let request = relay_request(Some(&h.relay_token), Encoding::Identity, &events);
+        send(&h.app, request).await;
+
+        let request = Request::get("/api/stream")
+            .header("last-event-id", "1")
+            .body(Body::empty())
+            .unwrap();
+        let response = h.app.clone().call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body();
+        let chunk = next_chunk(&mut body).await;
+        assert!(chunk.contains("\"event_id\":\"relayed\""), "{}", chunk);
+        assert!(chunk.contains("id: 2\n"), "{}", chunk);
+    }
+}
@@ -1,71 +1,625 @@
 //! Sync worker for EdgeKite
 //!
-//! Implements the outbox pattern: reads unsynced events from SQLite,
-//! batches them, sends to hub, and marks as synced on success.
+//! Implements the outbox pattern: each destination has a worker that reads
+//! its queued events from SQLite, batches them, sends them to its hub, and
+//! removes them from the queue on success. Destinations sync independently,
+//! so one failing hub does not hold back the others.
+//! Priority events are sent first; everything else waits for the sync
+//! window and budget (see `schedule`).
 
-use std::time::Duration;
-use tokio::task::JoinHandle;
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use serde::Serialize;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinSet;
 use tracing::{debug, error, info, warn};
 
-use crate::config::SyncConfig;
-use crate::db::Database;
+use crate::compression::{self, Encoding};
+use crate::config::{Config, SyncCompression, SyncConfig, Transport};
+use crate::db::{Database, OutboxCount};
+use crate::destination::{Destination, DestinationFilter};
+use crate::error::Error;
 use crate::event::Event;
+use crate::metrics::Metrics;
+use crate::schedule::{BudgetUsage, Hold, PriorityLane, Schedule};
+use crate::shutdown::Shutdown;
 
-/// Start the sync worker
-pub fn start_worker(db: Database, config: SyncConfig) -> JoinHandle<()> {
-    tokio::spawn(async move {
-        let client = reqwest::Client::new();
+/// What a sync worker is doing
+///
+/// Ordered by severity, so the overall state is the maximum over destinations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncPhase {
+    /// Sync is turned off (offline mode)
+    Disabled,
+    /// Waiting for the next interval
+    Idle,
+    /// A batch is in flight
+    Syncing,
+    /// Waiting to retry after a failed batch
+    BackingOff,
+    /// `retry_max_attempts` consecutive failures; retrying every interval
+    Failing,
+}
+
+impl SyncPhase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SyncPhase::Disabled => "disabled",
+            SyncPhase::Idle => "idle",
+            SyncPhase::Syncing => "syncing",
+            SyncPhase::BackingOff => "backing_off",
+            SyncPhase::Failing => "failing",
+        }
+    }
+}
+
+/// Snapshot of one destination's sync worker
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SyncStatus {
+    pub destination: String,
+    pub state: SyncPhase,
+    pub hub_url: String,
+    /// Upload encoding agreed with the hub (None until the first handshake)
+    pub encoding: Option<&'static str>,
+    pub last_attempt: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    /// Failed batches since the last success
+    pub consecutive_failures: u32,
+    /// Events acknowledged by the hub since startup
+    pub events_synced: u64,
+    /// Smoothed drain rate, including the pause between batches
+    pub events_per_second: Option<f64>,
+    /// Events waiting to be synced (filled in by `SyncState::snapshot`)
+    pub pending: i64,
+    /// Events this destination gave up on (filled in by `SyncState::snapshot`)
+    pub dead_letters: i64,
+    /// Estimated time to drain `pending` at `events_per_second`
+    pub eta_seconds: Option<u64>,
+}
+
+/// Sync status across all destinations (`/api/sync/status`)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SyncOverview {
+    /// Most severe state of any destination
+    pub state: SyncPhase,
+    /// Why bulk sync is paused (priority events still sync)
+    pub held: Option<String>,
+    /// Upload bytes counted against the daily budget
+    pub bytes_today: u64,
+    /// Upload bytes counted against the monthly budget
+    pub bytes_this_month: u64,
+    pub destinations: Vec<SyncStatus>,
+    /// Queues kept for destinations no longer configured; they resume if
+    /// the destination comes back, or go with `edge-kite outbox purge`
+    pub unconfigured: Vec<OutboxCount>,
+}
+
+/// Weight of the newest sample in smoothed averages (drain rate, compression ratio)
+const SMOOTHING: f64 = 0.3;
+
+/// Window and budget state, shared by all destinations
+#[derive(Debug, Default)]
+struct BudgetStatus {
+    held: Option<String>,
+    bytes_today: u64,
+    bytes_this_month: u64,
+}
+
+/// One destination's status, updated by its worker
+struct Tracker {
+    status: RwLock<SyncStatus>,
+    filter: DestinationFilter,
+    max_attempts: u32,
+    /// Wakes the worker early when a priority event is stored
+    wake: Notify,
+}
+
+impl Tracker {
+    /// Sleep for `duration`, or until a priority event arrives
+    async fn wait(&self, duration: Duration) {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => {}
+            _ = self.wake.notified() => {}
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut SyncStatus)) {
+        f(&mut self.status.write().unwrap());
+    }
+
+    fn negotiated(&self, encoding: Encoding) {
+        self.update(|s| s.encoding = Some(encoding.as_str()));
+    }
+
+    fn idle(&self) {
+        self.update(|s| s.state = SyncPhase::Idle);
+    }
+
+    fn attempt(&self) {
+        self.update(|s| {
+            s.state = SyncPhase::Syncing;
+            s.last_attempt = Some(Utc::now());
+        });
+    }
+
+    /// A batch of `synced` events took `cycle` (request time plus the pause
+    /// before the next batch)
+    fn success(&self, synced: usize, cycle: Duration) {
+        self.update(|s| {
+            s.state = SyncPhase::Idle;
+            s.last_success = Some(Utc::now());
+            s.consecutive_failures = 0;
+            s.events_synced += synced as u64;
+            if !cycle.is_zero() {
+                let sample = synced as f64 / cycle.as_secs_f64();
+                s.events_per_second = Some(match s.events_per_second {
+                    Some(rate) => rate + SMOOTHING * (sample - rate),
+                    None => sample,
+                });
+            }
+        });
+    }
+
+    fn failure(&self, error: &str) {
+        let max_attempts = self.max_attempts;
+        self.update(|s| {
+            s.consecutive_failures += 1;
+            s.last_error = Some(error.to_string());
+            s.state = if s.consecutive_failures >= max_attempts {
+                SyncPhase::Failing
+            } else {
+                SyncPhase::BackingOff
+            };
+        });
+    }
+}
+
+/// Sync state shared between the workers (the only writers) and the HTTP server
+#[derive(Clone)]
+pub struct SyncState {
+    budget: Arc<RwLock<BudgetStatus>>,
+    routes: Arc<RwLock<Routes>>,
+}
+
+/// Destinations being synced, replaced when `[sync]` is reloaded
+struct Routes {
+    enabled: bool,
+    lane: PriorityLane,
+    /// One per destination, in configuration order
+    trackers: Vec<Arc<Tracker>>,
+}
+
+impl SyncState {
+    pub fn new(config: &SyncConfig, destinations: &[Destination]) -> Self {
+        let state = Self {
+            budget: Arc::new(RwLock::new(BudgetStatus::default())),
+            routes: Arc::new(RwLock::new(Routes {
+                enabled: false,
+                lane: PriorityLane::default(),
+                trackers: Vec::new(),
+            })),
+        };
+        state.reconfigure(config, destinations);
+        state
+    }
+
+    /// Track a new set of destinations
+    ///
+    /// Destinations that keep their name keep their status and counters.
+    fn reconfigure(&self, config: &SyncConfig, destinations: &[Destination]) {
+        let mut routes = self.routes.write().unwrap();
+        let trackers = destinations
+            .iter()
+            .map(|destination| {
+                let previous = routes
+                    .trackers
+                    .iter()
+                    .find(|t| t.status.read().unwrap().destination == destination.name);
+                let status = match previous {
+                    Some(tracker) => SyncStatus {
+                        hub_url: destination.hub_url.clone(),
+                        ..tracker.status.read().unwrap().clone()
+                    },
+                    None => SyncStatus {
+                        destination: destination.name.clone(),
+                        state: SyncPhase::Idle,
+                        hub_url: destination.hub_url.clone(),
+                        encoding: None,
+                        last_attempt: None,
+                        last_success: None,
+                        last_error: None,
+                        consecutive_failures: 0,
+                        events_synced: 0,
+                        events_per_second: None,
+                        pending: 0,
+                        dead_letters: 0,
+                        eta_seconds: None,
+                    },
+                };
+                Arc::new(Tracker {
+                    status: RwLock::new(status),
+                    filter: destination.filter.clone(),
+                    max_attempts: config.retry_max_attempts,
+                    wake: Notify::new(),
+                })
+            })
+            .collect();
+
+        *routes = Routes {
+            enabled: config.enabled,
+            lane: PriorityLane::from_config(config),
+            trackers,
+        };
+    }
+
+    fn trackers(&self) -> Vec<Arc<Tracker>> {
+        self.routes.read().unwrap().trackers.clone()
+    }
+
+    /// Called after an event is stored; priority events are synced right away
+    pub fn event_stored(&self, event: &Event) {
+        let routes = self.routes.read().unwrap();
+        if routes.enabled && routes.lane.matches(event) {
+            for tracker in &routes.trackers {
+                if tracker.filter.matches(event) {
+                    tracker.wake.notify_one();
+                }
+            }
+        }
+    }
+
+    /// Most severe state of any destination
+    pub fn phase(&self) -> SyncPhase {
+        let routes = self.routes.read().unwrap();
+        if !routes.enabled {
+            return SyncPhase::Disabled;
+        }
+        routes
+            .trackers
+            .iter()
+            .map(|t| t.status.read().unwrap().state)
+            .max()
+            .unwrap_or(SyncPhase::Idle)
+    }
+
+    /// Current status, with each destination's backlog and drain estimate
+    pub fn snapshot(&self, counts: &[OutboxCount]) -> SyncOverview {
+        let destinations = self
+            .trackers()
+            .iter()
+            .map(|tracker| {
+                let mut status = tracker.status.read().unwrap().clone();
+                if let Some(count) = counts.iter().find(|c| c.destination == status.destination) {
+                    status.pending = count.pending;
+                    status.dead_letters = count.dead_letters;
+                }
+                status.eta_seconds = match status.events_per_second {
+                    _ if status.pending <= 0 => Some(0),
+                    Some(rate) if rate > 0.0 => Some((status.pending as f64 / rate).ceil() as u64),
+                    _ => None,
+                };
+                status
+            })
+            .collect();
+
+        let budget = self.budget.read().unwrap();
+        SyncOverview {
+            state: self.phase(),
+            held: budget.held.clone(),
+            bytes_today: budget.bytes_today,
+            bytes_this_month: budget.bytes_this_month,
+            destinations,
+            unconfigured: counts.iter().filter(|c| !c.configured).cloned().collect(),
+        }
+    }
+
+    fn scheduled(&self, hold: Option<Hold>, usage: &BudgetUsage, today: NaiveDate) {
+        let mut budget = self.budget.write().unwrap();
+        budget.held = hold.map(|h| h.to_string());
+        budget.bytes_today = usage.bytes_today(today);
+        budget.bytes_this_month = usage.bytes_this_month(today);
+    }
+}
+
+/// Destinations and schedule of a `[sync]` section, checked for consistency
+pub fn plan(config: &SyncConfig) -> crate::error::Result<(Vec<Destination>, Schedule)> {
+    let destinations = Destination::from_config(config)?;
+    let schedule = Schedule::from_config(config)?;
+    if config.enabled && !destinations.iter().any(|d| d.transport == Transport::Http) {
+        return Err(Error::Config(::config::ConfigError::Message(
+            "sync is enabled but neither sync.hub_url nor sync.destinations is set".to_string(),
+        )));
+    }
+    Ok((destinations, schedule))
+}
+
+/// Check a `[sync]` section and queue new events for its destinations
+///
+/// Returns the destinations that need a sync worker (bundle destinations
+/// are exported by hand) and the schedule.
+pub fn configure(
+    db: &Database,
+    config: &SyncConfig,
+) -> crate::error::Result<(Vec<Destination>, Schedule)> {
+    let (destinations, schedule) = plan(config)?;
+    db.set_priority_lane(PriorityLane::from_config(config))?;
+    // Events are queued for configured destinations even while sync is off
+    db.set_destinations(&destinations)?;
+    let synced = destinations
+        .into_iter()
+        .filter(|d| d.transport == Transport::Http)
+        .collect();
+    Ok((synced, schedule))
+}
+
+/// Run the sync workers until shutdown, restarting them when `[sync]` changes
+///
+/// `destinations` and `schedule` are the ones `configure` returned for the
+/// current configuration. After `shutdown` each worker keeps sending
+/// without waiting between batches, and exits once its queue is empty, a
+/// sync fails or the schedule holds everything back.
+pub async fn supervise(
+    db: Database,
+    mut config: watch::Receiver<Config>,
+    destinations: Vec<Destination>,
+    schedule: Schedule,
+    state: SyncState,
+    metrics: Arc<Metrics>,
+    shutdown: Shutdown,
+) {
+    let mut plan = Some((destinations, schedule));
+    loop {
+        let current = config.borrow_and_update().sync.clone();
+        let mut workers = match plan.take() {
+            Some((destinations, schedule)) if current.enabled => {
+                state.reconfigure(&current, &destinations);
+                for destination in &destinations {
+                    info!(
+                        "Sync enabled, destination '{}': {}",
+                        destination.name, destination.hub_url
+                    );
+                }
+                start_workers(
+                    &db,
+                    &current,
+                    destinations,
+                    schedule,
+                    &state,
+                    &metrics,
+                    &shutdown,
+                )
+            }
+            Some((destinations, _)) => {
+                state.reconfigure(&current, &destinations);
+                info!("Sync disabled (offline mode)");
+                Workers::default()
+            }
+            None => {
+                state.reconfigure(&current, &[]);
+                Workers::default()
+            }
+        };
+
+        // Wait for a change to `[sync]` (other sections are not ours)
+        loop {
+            tokio::select! {
+                changed = config.changed() => {
+                    if changed.is_err() || config.borrow().sync != current {
+                        break;
+                    }
+                }
+                _ = shutdown.triggered() => {
+                    workers.join().await;
+                    return;
+                }
+            }
+        }
+
+        info!("Sync configuration changed, restarting sync workers");
+        workers.stop.trigger();
+        workers.join().await;
+        let next = config.borrow().sync.clone();
+        plan = match configure(&db, &next) {
+            Ok(plan) => Some(plan),
+            Err(e) => {
+                // Reloads are validated first, so this is a database error
+                error!("Failed to apply sync configuration, sync stopped: {}", e);
+                None
+            }
+        };
+    }
+}
+
+/// One generation of sync workers
+#[derive(Default)]
+struct Workers {
+    tasks: JoinSet<()>,
+    /// Ends this generation after the batches in flight (no final flush)
+    stop: Shutdown,
+}
+
+impl Workers {
+    async fn join(&mut self) {
+        while self.tasks.join_next().await.is_some() {}
+    }
+}
+
+/// Start one sync worker per destination
+fn start_workers(
+    db: &Database,
+    config: &SyncConfig,
+    destinations: Vec<Destination>,
+    schedule: Schedule,
+    state: &SyncState,
+    metrics: &Arc<Metrics>,
+    shutdown: &Shutdown,
+) -> Workers {
+    // Budgets cover the link, so every destination draws from the same usage
+    let usage = Arc::new(Mutex::new(BudgetUsage::load(db)));
+    let schedule = Arc::new(schedule);
+    let mut workers = Workers::default();
+
+    for (destination, tracker) in destinations.into_iter().zip(state.trackers()) {
+        let worker = Worker {
+            db: db.clone(),
+            config: config.clone(),
+            destination,
+            schedule: schedule.clone(),
+            state: state.clone(),
+            tracker,
+            usage: usage.clone(),
+            metrics: metrics.clone(),
+            client: reqwest::Client::new(),
+            link: Link::default(),
+            shutdown: shutdown.clone(),
+            stop: workers.stop.clone(),
+        };
+        workers.tasks.spawn(worker.run());
+    }
+    workers
+}
+
+/// The sync loop of one destination
+struct Worker {
+    db: Database,
+    config: SyncConfig,
+    destination: Destination,
+    schedule: Arc<Schedule>,
+    state: SyncState,
+    tracker: Arc<Tracker>,
+    usage: Arc<Mutex<BudgetUsage>>,
+    metrics: Arc<Metrics>,
+    client: reqwest::Client,
+    link: Link,
+    shutdown: Shutdown,
+    stop: Shutdown,
+}
+
+impl Worker {
+    async fn run(mut self) {
+        let name = self.destination.name.clone();
         let mut consecutive_failures = 0u32;
+        let interval = Duration::from_secs(self.config.interval_seconds);
+        let mut last_hold = None;
 
         loop {
-            // Check for unsynced events
-            match db.get_unsynced_events(config.batch_size) {
+            if self.stop.is_triggered() {
+                break;
+            }
+            let stopping = self.shutdown.is_triggered();
+            let now = Local::now();
+            let hold = {
+                let usage = self.usage.lock().unwrap();
+                let hold = self.schedule.hold(now, &usage);
+                self.state.scheduled(hold, &usage, now.date_naive());
+                hold
+            };
+            if hold != last_hold {
+                match hold {
+                    Some(reason) => info!("Bulk sync to '{}' paused: {}", name, reason),
+                    None => info!("Bulk sync to '{}' resumed", name),
+                }
+                last_hold = hold;
+            }
+
+            // Priority events go first, and are all that is sent while held
+            let limit = adaptive_batch_size(&self.destination, self.link.compression_ratio);
+            let held = hold.is_some();
+            let batch = self
+                .db
+                .blocking({
+                    let name = name.clone();
+                    move |db| match db.get_unsynced_priority_events(&name, limit) {
+                        Ok(events) if events.is_empty() && !held => {
+                            db.get_unsynced_events(&name, limit)
+                        }
+                        other => other,
+                    }
+                })
+                .await;
+
+            match batch {
                 Ok(events) if events.is_empty() => {
+                    if stopping {
+                        break;
+                    }
                     // Nothing to sync, wait and check again
-                    debug!("No events to sync");
-                    tokio::time::sleep(Duration::from_secs(config.interval_seconds)).await;
+                    debug!("No events to sync to '{}'", name);
+                    self.tracker.idle();
+                    self.pause(interval).await;
                     continue;
                 }
                 Ok(events) => {
                     let count = events.len();
-                    debug!("Syncing {} events to hub", count);
+                    debug!("Syncing {} events to '{}'", count, name);
+                    self.tracker.attempt();
+                    let started = Instant::now();
+
+                    let result = self.upload(&events).await;
+                    if let Err(e) = self.usage.lock().unwrap().save(&self.db) {
+                        warn!("Failed to save sync budget usage: {}", e);
+                    }
+
+                    match result {
+                        Ok(response) => {
+                            self.record_rejections(response.rejected).await;
 
-                    match sync_batch(&client, &config, &events).await {
-                        Ok(accepted_ids) => {
-                            // Mark as synced
-                            match db.mark_synced(&accepted_ids) {
+                            // Remove from this destination's queue
+                            let marked = self
+                                .db
+                                .blocking({
+                                    let name = name.clone();
+                                    move |db| db.mark_synced(&name, &response.accepted)
+                                })
+                                .await;
+                            match marked {
                                 Ok(marked) => {
-                                    info!("Synced {} events to hub", marked);
+                                    info!("Synced {} events to '{}'", marked, name);
+                                    self.metrics.sync_succeeded(&name, marked);
+                                    self.tracker.success(marked, started.elapsed() + interval);
                                     consecutive_failures = 0;
                                 }
                                 Err(e) => {
                                     error!("Failed to mark events as synced: {}", e);
+                                    self.tracker.failure(&format!(
+                                        "Failed to mark events as synced: {}",
+                                        e
+                                    ));
                                 }
                             }
                         }
                         Err(e) => {
+                            // The hub may have changed while unreachable; ask again
+                            self.link.encoding = None;
+                            self.metrics.sync_failed(&name);
+                            self.tracker.failure(&e);
                             consecutive_failures += 1;
                             warn!(
-                                "Sync failed (attempt {}): {}",
-                                consecutive_failures, e
+                                "Sync to '{}' failed (attempt {}): {}",
+                                name, consecutive_failures, e
                             );
+                            if stopping {
+                                break;
+                            }
 
                             // Exponential backoff
-                            if consecutive_failures < config.retry_max_attempts {
+                            if consecutive_failures < self.config.retry_max_attempts {
                                 let delay = calculate_backoff(
                                     consecutive_failures,
-                                    config.retry_base_delay_ms,
+                                    self.config.retry_base_delay_ms,
                                 );
                                 debug!("Retrying in {} ms", delay);
-                                tokio::time::sleep(Duration::from_millis(delay)).await;
+                                self.sleep(Duration::from_millis(delay)).await;
                             } else {
                                 error!(
-                                    "Max retry attempts ({}) reached, waiting for next interval",
-                                    config.retry_max_attempts
+                                    "Max retry attempts ({}) reached for '{}', waiting for next interval",
+                                    self.config.retry_max_attempts, name
                                 );
                                 consecutive_failures = 0;
-                                tokio::time::sleep(Duration::from_secs(config.interval_seconds))
-                                    .await;
+                                self.sleep(interval).await;
                             }
                             continue;
                         }
@@ -73,40 +627,222 @@ pub fn start_worker(db: Database, config: SyncConfig) -> JoinHandle<()> {
                 }
                 Err(e) => {
                     error!("Failed to get unsynced events: {}", e);
+                    self.tracker
+                        .failure(&format!("Failed to get unsynced events: {}", e));
+                    if stopping {
+                        break;
+                    }
                 }
             }
 
             // Wait before next sync cycle
-            tokio::time::sleep(Duration::from_secs(config.interval_seconds)).await;
+            if !stopping {
+                self.pause(interval).await;
+            }
+        }
+        info!("Sync to '{}' stopped", name);
+    }
+
+    /// Sleep for `duration`, or until a priority event arrives or shutdown
+    async fn pause(&self, duration: Duration) {
+        tokio::select! {
+            _ = self.tracker.wait(duration) => {}
+            _ = self.shutdown.triggered() => {}
+            _ = self.stop.triggered() => {}
         }
-    })
+    }
+
+    /// Sleep for `duration`, or until shutdown
+    async fn sleep(&self, duration: Duration) {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => {}
+            _ = self.shutdown.triggered() => {}
+            _ = self.stop.triggered() => {}
+        }
+    }
+
+    /// Count hub rejections against each event, dead-lettering repeat offenders
+    async fn record_rejections(&self, rejected: Option<Vec<RejectedEvent>>) {
+        let rejections: Vec<(String, String)> = rejected
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|r| Some((r.event_id?, r.reason)))
+            .collect();
+        if rejections.is_empty() {
+            return;
+        }
+
+        let name = &self.destination.name;
+        let count = rejections.len();
+        let max_attempts = self.config.max_reject_attempts;
+        self.metrics.sync_rejected(name, count);
+        let recorded = self
+            .db
+            .blocking({
+                let name = name.clone();
+                move |db| db.record_sync_rejections(&name, &rejections, max_attempts)
+            })
+            .await;
+        match recorded {
+            Ok(0) => warn!("'{}' rejected {} events", name, count),
+            Ok(dead) => warn!(
+                "'{}' rejected {} events, {} moved to dead letters after {} attempts",
+                name, count, dead, max_attempts
+            ),
+            Err(e) => error!("Failed to record sync rejections: {}", e),
+        }
+    }
+
+    /// Negotiate an encoding if needed, then compress and send a batch
+    async fn upload(&mut self, events: &[Event]) -> Result<SyncResponse, String> {
+        let destination = &self.destination;
+        let link = &mut self.link;
+        let encoding = match link.encoding {
+            Some(encoding) => encoding,
+            None => {
+                let advertised = if destination.compression == SyncCompression::None {
+                    Vec::new()
+                } else {
+                    fetch_capabilities(&self.client, destination).await?
+                };
+                let encoding = compression::negotiate(destination.compression, &advertised);
+                if encoding == Encoding::Identity {
+                    link.compression_ratio = None;
+                }
+                debug!(
+                    "Upload encoding for '{}': {}",
+                    destination.name,
+                    encoding.as_str()
+                );
+                link.encoding = Some(encoding);
+                self.tracker.negotiated(encoding);
+                encoding
+            }
+        };
+
+        let raw =
+            serde_json::to_vec(events).map_err(|e| format!("Failed to encode batch: {}", e))?;
+        let body = encoding
+            .compress(&raw)
+            .map_err(|e| format!("Failed to compress batch: {}", e))?;
+        self.metrics
+            .sync_bytes(&destination.name, encoding, raw.len(), body.len());
+        self.usage
+            .lock()
+            .unwrap()
+            .record(body.len() as u64, Local::now().date_naive());
+        if encoding != Encoding::Identity && !body.is_empty() {
+            let sample = raw.len() as f64 / body.len() as f64;
+            link.compression_ratio = Some(match link.compression_ratio {
+                Some(ratio) => ratio + SMOOTHING * (sample - ratio),
+                None => sample,
+            });
+        }
+
+        sync_batch(&self.client, destination, encoding, body).await
+    }
+}
+
+/// What the worker has learned about the hub connection
+#[derive(Debug, Default)]
+struct Link {
+    /// Upload encoding; None until the capability handshake succeeds
+    encoding: Option<Encoding>,
+    /// Smoothed uncompressed/compressed size of recent uploads
+    compression_ratio: Option<f64>,
+}
+
+/// Events per batch: with compression, grow the batch until its compressed
+/// size matches an uncompressed `batch_size` batch, up to `max_batch_size`
+fn adaptive_batch_size(destination: &Destination, compression_ratio: Option<f64>) -> usize {
+    let ratio = compression_ratio.unwrap_or(1.0).max(1.0);
+    ((destination.batch_size as f64 * ratio) as usize).clamp(
+        destination.batch_size,
+        destination.max_batch_size.max(destination.batch_size),
+    )
+}
+
+/// Hub capability document (`GET {hub_url}/api/ingest/capabilities`)
+#[derive(serde::Deserialize)]
+struct HubCapabilities {
+    /// Accepted `Content-Encoding` values for uploads
+    #[serde(default)]
+    encodings: Vec<String>,
+}
+
+/// Ask the hub which upload encodings it accepts
+///
+/// Hubs without the capabilities endpoint get uncompressed uploads.
+async fn fetch_capabilities(
+    client: &reqwest::Client,
+    destination: &Destination,
+) -> Result<Vec<Encoding>, String> {
+    let url = format!(
+        "{}/api/ingest/capabilities",
+        destination.hub_url.trim_end_matches('/')
+    );
+
+    let response = client
+        .get(&url)
+        .header("Authorization", format!("Bearer {}", destination.api_key))
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await
+        .map_err(|e| format!("Capability handshake failed: {}", e))?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(Vec::new());
+    }
+    if !response.status().is_success() {
+        return Err(format!(
+            "Capability handshake failed: hub returned {}",
+            response.status()
+        ));
+    }
+
+    let capabilities: HubCapabilities = response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse hub capabilities: {}", e))?;
+    Ok(capabilities
+        .encodings
+        .iter()
+        .filter_map(|e| Encoding::parse(e))
+        .collect())
 }
 
 /// Sync a batch of events to the hub
 async fn sync_batch(
     client: &reqwest::Client,
-    config: &SyncConfig,
-    events: &[Event],
-) -> Result<Vec<String>, String> {
-    let url = format!("{}/api/ingest/batch", config.hub_url.trim_end_matches('/'));
+    destination: &Destination,
+    encoding: Encoding,
+    body: Vec<u8>,
+) -> Result<SyncResponse, String> {
+    let url = format!(
+        "{}/api/ingest/batch",
+        destination.hub_url.trim_end_matches('/')
+    );
 
-    let response = client
+    let mut request = client
         .post(&url)
-        .header("Authorization", format!("Bearer {}", config.api_key))
-        .header("Content-Type", "application/json")
-        .json(events)
+        .header("Authorization", format!("Bearer {}", destination.api_key))
+        .header("Content-Type", "application/json");
+    if encoding != Encoding::Identity {
+        request = request.header("Content-Encoding", encoding.as_str());
+    }
+
+    let response = request
+        .body(body)
         .timeout(Duration::from_secs(30))
         .send()
         .await
         .map_err(|e| format!("HTTP request failed: {}", e))?;
 
     if response.status().is_success() {
-        let result: SyncResponse = response
+        response
             .json()
             .await
-            .map_err(|e| format!("Failed to parse response: {}", e))?;
-
-        Ok(result.accepted)
+            .map_err(|e| format!("Failed to parse response: {}", e))
     } else {
         let status = response.status();
         let body = response.text().await.unwrap_or_default();
@@ -125,12 +861,10 @@ fn calculate_backoff(attempt: u32, base_delay_ms: u64) -> u64 {
 #[derive(serde::Deserialize)]
 struct SyncResponse {
     accepted: Vec<String>,
-    #[allow(dead_code)]
     rejected: Option<Vec<RejectedEvent>>,
 }
 
 #[derive(serde::Deserialize)]
-#[allow(dead_code)]
 struct RejectedEvent {
     event_id: Option<String>,
     reason: String,
@@ -140,6 +874,19 @@ struct RejectedEvent {
 mod tests {
     use super::*;
 
+    fn destination(name: &str) -> Destination {
+        Destination {
+            name: name.to_string(),
+            transport: crate::config::Transport::Http,
+            hub_url: format!("https://{}.example.com", name),
+            api_key: String::new(),
+            batch_size: 100,
+            max_batch_size: 1000,
+            compression: SyncCompression::Auto,
+            filter: DestinationFilter::default(),
+        }
+    }
+
     #[test]
     fn test_backoff_calculation() {
         assert_eq!(calculate_backoff(1, 1000), 1000);
@@ -148,4 +895,87 @@ mod tests {
         assert_eq!(calculate_backoff(4, 1000), 8000);
         assert_eq!(calculate_backoff(10, 1000), 300000); // Capped at 5 min
     }
+
+    #[test]
+    fn test_adaptive_batch_size() {
+        let hub = destination("hub");
+        assert_eq!(adaptive_batch_size(&hub, None), 100);
+        assert_eq!(adaptive_batch_size(&hub, Some(0.8)), 100);
+        assert_eq!(adaptive_batch_size(&hub, Some(4.5)), 450);
+        assert_eq!(adaptive_batch_size(&hub, Some(20.0)), 1000);
+
+        // max_batch_size below batch_size never shrinks batches
+        let hub = Destination {
+            max_batch_size: 10,
+            ..hub
+        };
+        assert_eq!(adaptive_batch_size(&hub, Some(5.0)), 100);
+    }
+
+    #[test]
+    fn test_sync_state_transitions() {
+        let config = SyncConfig {
+            enabled: true,
+            retry_max_attempts: 2,
+            ..Default::default()
+        };
+        let state = SyncState::new(&config, &[destination("hub"), destination("backup")]);
+        let trackers = state.trackers();
+        let (hub, backup) = (&trackers[0], &trackers[1]);
+        let counts = |pending| {
+            vec![OutboxCount {
+                destination: "hub".to_string(),
+                pending,
+                dead_letters: 1,
+                configured: true,
+            }]
+        };
+        assert_eq!(state.phase(), SyncPhase::Idle);
+
+        hub.attempt();
+        assert_eq!(state.phase(), SyncPhase::Syncing);
+        hub.failure("Hub returned 503");
+        assert_eq!(state.phase(), SyncPhase::BackingOff);
+        backup.attempt();
+        hub.failure("Hub returned 503");
+        assert_eq!(state.phase(), SyncPhase::Failing);
+
+        let status = &state.snapshot(&counts(10)).destinations[0];
+        assert_eq!(status.consecutive_failures, 2);
+        assert_eq!(status.last_error.as_deref(), Some("Hub returned 503"));
+        assert!(status.last_attempt.is_some());
+        assert_eq!(status.dead_letters, 1);
+        assert_eq!(status.eta_seconds, None);
+
+        hub.success(100, Duration::from_secs(10));
+        let overview = state.snapshot(&counts(250));
+        // The backup destination is still mid-batch
+        assert_eq!(overview.state, SyncPhase::Syncing);
+        let status = &overview.destinations[0];
+        assert_eq!(status.state, SyncPhase::Idle);
+        assert_eq!(status.consecutive_failures, 0);
+        assert_eq!(status.events_synced, 100);
+        assert_eq!(status.events_per_second, Some(10.0));
+        assert_eq!(status.eta_seconds, Some(25));
+        assert_eq!(overview.destinations[1].pending, 0);
+        assert!(overview.unconfigured.is_empty());
+
+        // Queues left behind by removed destinations are reported apart
+        let mut leftover = counts(5);
+        leftover[0].destination = "old-hub".to_string();
+        leftover[0].configured = false;
+        let overview = state.snapshot(&leftover);
+        assert_eq!(overview.unconfigured, leftover);
+        assert_eq!(overview.destinations[0].pending, 0);
+
+        // The rate is smoothed across batches
+        hub.success(100, Duration::from_secs(5));
+        let status = &state.snapshot(&counts(0)).destinations[0];
+        assert_eq!(status.events_per_second, Some(13.0));
+        assert_eq!(status.eta_seconds, Some(0));
+
+        let disabled = SyncState::new(&SyncConfig::default(), &[]);
+        assert_eq!(disabled.phase(), SyncPhase::Disabled);
+        assert!(disabled.snapshot(&[]).destinations.is_empty());
+    }
 }
@@ -0,0 +1,309 @@
+//! Ingest validation for EdgeKite
+//!
+//! Enforces the envelope rules from `docs/event-schema.md` and, optionally,
+//! per-type JSON Schemas for the `data` payload. Schemas are loaded from
+//! `{schemas_dir}/{category}/{type}/{schema_version}.json`.
+
+use jsonschema::JSONSchema;
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::{info, warn};
+
+use crate::config::{ValidationConfig, ValidationMode};
+use crate::error::{Error, Result};
+use crate::event::{validate_category, validate_severity, validate_source_type, Event};
+
+/// Schema version assumed when an event does not declare one
+const DEFAULT_SCHEMA_VERSION: &str = "1.0";
+
+/// Maximum number of payload schema errors reported per event
+const MAX_SCHEMA_ERRORS: usize = 5;
+
+/// Registry key: (category, type, schema_version)
+type SchemaKey = (String, String, String);
+
+/// Validates events before they are stored
+pub struct Validator {
+    mode: ValidationMode,
+    schemas: HashMap<SchemaKey, JSONSchema>,
+}
+
+impl Validator {
+    /// Validator with envelope rules only (no payload schemas)
+    pub fn new(mode: ValidationMode) -> Self {
+        Self {
+            mode,
+            schemas: HashMap::new(),
+        }
+    }
+
+    /// Build a validator, loading payload schemas from the configured directory
+    pub fn load(config: &ValidationConfig, data_dir: &Path) -> Result<Self> {
+        let mut validator = Self::new(config.mode);
+        if config.mode == ValidationMode::Off {
+            return Ok(validator);
+        }
+
+        let dir = config
+            .schemas_dir
+            .clone()
+            .unwrap_or_else(|| data_dir.join("schemas"));
+        if !dir.is_dir() {
+            return Ok(validator);
+        }
+
+        for category in read_dirs(&dir)? {
+            for event_type in read_dirs(&category)? {
+                for entry in std::fs::read_dir(&event_type)? {
+                    let path = entry?.path();
+                    if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                        continue;
+                    }
+                    let key = (
+                        file_name(&category),
+                        file_name(&event_type),
+                        path.file_stem()
+                            .and_then(|s| s.to_str())
+                            .unwrap_or_default()
+                            .to_string(),
+                    );
+                    let raw: serde_json::Value =
+                        serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+                    let schema = JSONSchema::compile(&raw)
+                        .map_err(|e| Error::Schema(format!("{}: {}", path.display(), e)))?;
+                    validator.schemas.insert(key, schema);
+                }
+            }
+        }
+
+        info!(
+            "Loaded {} payload schema(s) from {:?}",
+            validator.schemas.len(),
+            dir
+        );
+        Ok(validator)
+    }
+
+    /// Check an event according to the configured mode
+    ///
+    /// Returns the rejection reason in strict mode; in warn mode violations
+    /// are only logged. Advisory ("should") rules are only ever logged.
+    pub fn check(&self, event: &Event) -> std::result::Result<(), String> {
+        if self.mode == ValidationMode::Off {
+            return Ok(());
+        }
+
+        let advice = advisories(event);
+        if !advice.is_empty() {
+            warn!("Event {}: {}", event.event_id, advice.join("; "));
+        }
+
+        let violations = self.violations(event);
+        if violations.is_empty() {
+            return Ok(());
+        }
+
+        let reason = violations.join("; ");
+        match self.mode {
+            ValidationMode::Strict => Err(reason),
+            _ => {
+                warn!("Event {} failed validation: {}", event.event_id, reason);
+                Ok(())
+            }
+        }
+    }
+
+    /// All violations of the rules an event must follow
+    fn violations(&self, event: &Event) -> Vec<String> {
+        let mut violations = Vec::new();
+
+        if event.event_id.trim().is_empty() {
+            violations.push("event_id must not be empty".to_string());
+        }
+        if !validate_source_type(&event.source.source_type) {
+            violations.push(format!(
+                "source.type '{}' must be one of: browser, edge_device, server, mobile",
+                event.source.source_type
+            ));
+        }
+        if event.source.id.trim().is_empty() {
+            violations.push("source.id must not be empty".to_string());
+        }
+        if !validate_category(&event.event.category) {
+            violations.push(format!(
+                "event.category '{}' must be one of: web, iot, app, ops, security",
+                event.event.category
+            ));
+        }
+        if !validate_severity(&event.event.severity) {
+            violations.push(format!(
+                "event.severity '{}' must be one of: debug, info, warn, error, critical",
+                event.event.severity
+            ));
+        }
+        if !event.event.data.is_object() {
+            violations.push("event.data must be an object".to_string());
+        }
+        for attachment in event.attachments.iter().flatten() {
+            if !["file://", "https://", "s3://"]
+                .iter()
+                .any(|scheme| attachment.uri.starts_with(scheme))
+            {
+                violations.push(format!(
+                    "attachment uri '{}' must use file://, https:// or s3://",
+                    attachment.uri
+                ));
+            }
+        }
+        if let Some(privacy) = &event.privacy {
+            if !matches!(
+                privacy.retention_class.as_str(),
+                "short" | "standard" | "long"
+            ) {
+                violations.push(format!(
+                    "privacy.retention_class '{}' must be one of: short, standard, long",
+                    privacy.retention_class
+                ));
+            }
+        }
+
+        let key = (
+            event.event.category.clone(),
+            event.event.event_type.clone(),
+            event
+                .event
+                .schema_version
+                .clone()
+                .unwrap_or_else(|| DEFAULT_SCHEMA_VERSION.to_string()),
+        );
+        if let Some(schema) = self.schemas.get(&key) {
+            if let Err(errors) = schema.validate(&event.event.data) {
+                violations.extend(
+                    errors
+                        .take(MAX_SCHEMA_ERRORS)
+                        .map(|e| format!("event.data{}: {}", e.instance_path, e)),
+                );
+            }
+        }
+
+        violations
+    }
+}
+
+/// Departures from the rules an event should follow; these never reject
+fn advisories(event: &Event) -> Vec<String> {
+    let mut advice = Vec::new();
+    if !is_snake_case(&event.event.event_type) {
+        advice.push(format!(
+            "event.type '{}' should be lowercase_snake_case",
+            event.event.event_type
+        ));
+    }
+    advice
+}
+
+/// `lowercase_snake_case`: starts with a letter, then a-z, 0-9 or `_`
+fn is_snake_case(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_lowercase())
+        && s.chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+fn read_dirs(dir: &Path) -> Result<Vec<std::path::PathBuf>> {
+    let mut dirs = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            dirs.push(path);
+        }
+    }
+    Ok(dirs)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::test_support::test_event;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_envelope_rules() {
+        let validator = Validator::new(ValidationMode::Strict);
+        let ok = test_event("person_detected").category("iot").build();
+        assert!(validator.check(&ok).is_ok());
+
+        let mut bad = test_event("Person Detected")
+            .category("weather")
+            .data(serde_json::json!([1]))
+            .build();
+        bad.event.severity = "fatal".to_string();
+        let reason = validator.check(&bad).unwrap_err();
+        assert!(reason.contains("event.category 'weather'"));
+        assert!(!reason.contains("event.type"));
+        assert!(reason.contains("event.severity 'fatal'"));
+        assert!(reason.contains("event.data must be an object"));
+    }
+
+    #[test]
+    fn test_type_naming_is_advisory() {
+        // Types like `EdgeKite.track('Signup Clicked')` sends are only logged
+        let tracked = test_event("Signup Clicked").build();
+        assert!(Validator::new(ValidationMode::Strict)
+            .check(&tracked)
+            .is_ok());
+        assert_eq!(advisories(&tracked).len(), 1);
+        assert!(advisories(&test_event("signup_clicked").build()).is_empty());
+    }
+
+    #[test]
+    fn test_warn_and_off_modes_accept() {
+        let bad = test_event("x").category("weather").build();
+        assert!(Validator::new(ValidationMode::Warn).check(&bad).is_ok());
+        assert!(Validator::new(ValidationMode::Off).check(&bad).is_ok());
+    }
+
+    #[test]
+    fn test_payload_schema_registry() {
+        let dir = tempdir().unwrap();
+        let type_dir = dir.path().join("schemas/iot/reading");
+        std::fs::create_dir_all(&type_dir).unwrap();
+        std::fs::write(
+            type_dir.join("1.0.json"),
+            r#"{
+                "type": "object",
+                "required": ["metric", "value"],
+                "properties": { "value": { "type": "number" } }
+            }"#,
+        )
+        .unwrap();
+
+        let config = ValidationConfig::default();
+        let validator = Validator::load(&config, dir.path()).unwrap();
+
+        let good = test_event("reading")
+            .category("iot")
+            .data(serde_json::json!({"metric": "t", "value": 1.5}))
+            .build();
+        assert!(validator.check(&good).is_ok());
+
+        let bad = test_event("reading")
+            .category("iot")
+            .data(serde_json::json!({"value": "hot"}))
+            .build();
+        let reason = validator.check(&bad).unwrap_err();
+        assert!(reason.contains("metric"));
+        assert!(reason.contains("event.data/value"));
+
+        // Other schema versions are not checked against the 1.0 schema
+        let mut v2 = bad.clone();
+        v2.event.schema_version = Some("2.0".to_string());
+        assert!(validator.check(&v2).is_ok());
+    }
+}
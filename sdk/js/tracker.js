@@ -5,8 +5,11 @@
  * Usage:
  *   <script src="/tracker.js" data-endpoint="/api/events"></script>
  *
+ * With a public ingest key (when the agent requires authentication):
+ *   <script src="/tracker.js" data-endpoint="/api/events" data-key="ek_..."></script>
+ *
  * Or initialize manually:
- *   EdgeKite.init({ endpoint: '/api/events' });
+ *   EdgeKite.init({ endpoint: '/api/events', key: 'ek_...' });
  *   EdgeKite.track('custom_event', { key: 'value' });
  */
 
@@ -22,6 +25,7 @@
   var config = {
     endpoint: '/api/events',
     batchEndpoint: '/api/events/batch',
+    key: null,
     debug: false
   };
   var sessionId = null;
@@ -189,6 +193,13 @@
 
   // ============ Sending ============
 
+  // The key goes in the query string so sendBeacon (which cannot set
+  // headers) is authenticated too
+  function withKey(url) {
+    if (!config.key) return url;
+    return url + (url.indexOf('?') > -1 ? '&' : '?') + 'key=' + encodeURIComponent(config.key);
+  }
+
   function sendBatch() {
     if (queue.length === 0) return;
 
@@ -196,7 +207,7 @@
     log('Sending batch of', batch.length, 'events');
 
     var xhr = new XMLHttpRequest();
-    xhr.open('POST', config.batchEndpoint, true);
+    xhr.open('POST', withKey(config.batchEndpoint), true);
     xhr.setRequestHeader('Content-Type', 'application/json');
     xhr.onreadystatechange = function() {
       if (xhr.readyState === 4) {
@@ -231,12 +242,12 @@
     // Use sendBeacon if available for reliable delivery
     if (navigator.sendBeacon) {
       var blob = new Blob([JSON.stringify(queue)], { type: 'application/json' });
-      navigator.sendBeacon(config.batchEndpoint, blob);
+      navigator.sendBeacon(withKey(config.batchEndpoint), blob);
       queue = [];
     } else {
       // Fallback: synchronous XHR (not ideal but better than nothing)
       var xhr = new XMLHttpRequest();
-      xhr.open('POST', config.batchEndpoint, false);
+      xhr.open('POST', withKey(config.batchEndpoint), false);
       xhr.setRequestHeader('Content-Type', 'application/json');
       xhr.send(JSON.stringify(queue));
       queue = [];
@@ -416,6 +427,7 @@
      * Initialize the tracker
      * @param {Object} options - Configuration options
      * @param {string} options.endpoint - Event ingestion endpoint
+     * @param {string} options.key - Public ingest API key (optional)
      * @param {boolean} options.debug - Enable debug logging
      */
     init: function(options) {
@@ -427,6 +439,9 @@
         if (options.batchEndpoint) {
           config.batchEndpoint = options.batchEndpoint;
         }
+        if (options.key) {
+          config.key = options.key;
+        }
         if (options.debug) {
           config.debug = true;
         }
@@ -476,11 +491,13 @@
       var script = scripts[i];
       if (script.src && script.src.indexOf('tracker.js') > -1) {
         var endpoint = script.getAttribute('data-endpoint');
+        var key = script.getAttribute('data-key');
         var debug = script.getAttribute('data-debug') === 'true';
 
-        if (endpoint || debug) {
+        if (endpoint || key || debug) {
           EdgeKite.init({
             endpoint: endpoint || config.endpoint,
+            key: key,
             debug: debug
           });
           return;